@@ -1,27 +1,10 @@
-#[toml_cfg::toml_config]
-pub struct Config {
-    #[default("")]
-    wifi_ssid: &'static str,
-    #[default("")]
-    wifi_psk: &'static str,
-}
-
 fn main() {
-    // Check if the `cfg.toml` file exists and has been filled out.
+    // cfg.toml is optional: its values only seed the NVS settings on first boot,
+    // and a device without Wi-Fi credentials starts in provisioning mode.
+    println!("cargo:rerun-if-changed=cfg.toml");
     if !std::path::Path::new("cfg.toml").exists() {
-        panic!("You need to create a `cfg.toml` file with your Wi-Fi credentials! Use `cfg.toml.example` as a template.");
-    }
-
-    // The constant `CONFIG` is auto-generated by `toml_config`.
-    let app_config = CONFIG;
-    if app_config.wifi_ssid == "FBI Surveillance Van" || app_config.wifi_psk == "hunter2" {
-        panic!("You need to set the Wi-Fi credentials in `cfg.toml`!");
+        println!("cargo:warning=No `cfg.toml` found, building without first-boot defaults. Use `cfg.toml.example` as a template.");
     }
 
-    if app_config.wifi_ssid.is_empty() || app_config.wifi_psk.is_empty() {
-        panic!("You need to set the Wi-Fi credentials in `cfg.toml`!");
-    }
-
-
     embuild::espidf::sysenv::output();
 }
@@ -0,0 +1,35 @@
+//! The firmware modules that run anywhere, with their tests.
+
+#![allow(dead_code)]
+// the esp toolchain predates `Option::is_none_or`
+#![allow(clippy::unnecessary_map_or)]
+
+#[path = "../../src/connection.rs"]
+pub mod connection;
+
+#[path = "../../src/sntp.rs"]
+pub mod sntp;
+
+#[path = "../../src/posix_tz.rs"]
+pub mod posix_tz;
+
+#[path = "../../src/multipart.rs"]
+pub mod multipart;
+
+#[path = "../../src/outbox.rs"]
+pub mod outbox;
+
+#[path = "../../src/homeassistant.rs"]
+pub mod homeassistant;
+
+#[path = "../../src/firmware.rs"]
+pub mod firmware;
+
+#[path = "../../src/captures.rs"]
+pub mod captures;
+
+#[path = "../../src/transfer.rs"]
+pub mod transfer;
+
+#[path = "../../src/template.rs"]
+pub mod template;
@@ -0,0 +1,442 @@
+use anyhow::anyhow;
+use embedded_svc::http::server::{HandlerResult, Request};
+use esp_idf_svc::{
+    http::{server::{EspHttpConnection, EspHttpServer}, Method},
+    io::{Read, Write},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::clock;
+use crate::commands::{self, Command};
+use crate::delivery;
+use crate::ipconfig::{self, IpSettings};
+use crate::link;
+use crate::mdns;
+use crate::mqtt::{self, MqttSettings};
+use crate::networks::{load_networks, remove_network, upsert_network, KnownNetwork};
+use crate::ntp::{self, NtpSettings};
+use crate::posix_tz;
+use crate::power::{self, PowerSettings};
+use crate::sensor;
+use crate::preludes::*;
+use crate::recorder::{self, RecorderSettings};
+use crate::timezone;
+use crate::upload::{self, Trigger, UploadSettings};
+use crate::webhook::{self, Event, EventKind, WebhookSettings};
+use crate::wifi::{self, request_reconnect};
+
+pub type ApiRequest<'r, 'c> = Request<&'r mut EspHttpConnection<'c>>;
+
+const MAX_BODY_LEN: usize = 4096;
+/// How long `/api/wifi/scan` waits for the Wi-Fi task, which scans every channel.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn read_body(request: &mut ApiRequest<'_, '_>, limit: usize) -> anyhow::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut buf = [0u8; 256];
+    loop {
+        let n = request.read(&mut buf).map_err(|e| anyhow!("read body: {:?}", e))?;
+        if n == 0 {
+            return Ok(body);
+        }
+        if body.len() + n > limit {
+            return Err(anyhow!("body longer than {} bytes", limit));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+}
+
+pub fn read_json<T: DeserializeOwned>(request: &mut ApiRequest<'_, '_>) -> anyhow::Result<T> {
+    let body = read_body(request, MAX_BODY_LEN)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+pub fn reply_json<T: Serialize>(request: ApiRequest<'_, '_>, status: u16, value: &T) -> HandlerResult {
+    let body = serde_json::to_vec(value)?;
+    let mut response = request.into_response(
+        status,
+        None,
+        &[
+            ("Content-Type", "application/json"),
+            ("Content-Length", &body.len().to_string()),
+        ],
+    )?;
+    response.write_all(&body)?;
+    Ok(())
+}
+
+pub fn reply_error(request: ApiRequest<'_, '_>, status: u16, message: &str) -> HandlerResult {
+    reply_json(request, status, &serde_json::json!({ "error": message }))
+}
+
+/// Returns the decoded value of `key` from the query string of `uri`.
+pub fn query_param(uri: &str, key: &str) -> Option<String> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| percent_decode(v))
+}
+
+fn percent_decode(value: &str) -> String {
+    fn hex(b: u8) -> Option<u8> {
+        (b as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(hi), Some(lo)) => {
+                    out.push(hi << 4 | lo);
+                    i += 2;
+                },
+                _ => out.push(b'%'),
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub fn register_wifi_networks(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/wifi/networks", Method::Get, |request| {
+        let networks = load_networks()?;
+        let summaries: Vec<_> = networks.iter().map(KnownNetwork::summary).collect();
+        reply_json(request, 200, &summaries)
+    })?;
+
+    server.fn_handler("/api/wifi/networks", Method::Post, |mut request| {
+        let network: KnownNetwork = match read_json(&mut request) {
+            Ok(n) => n,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        info!("storing network {} with priority {}", network.ssid, network.priority);
+        match upsert_network(network) {
+            Ok(()) => reply_json(request, 200, &serde_json::json!({ "ok": true })),
+            Err(e) => reply_error(request, 400, &e.to_string()),
+        }
+    })?;
+
+    server.fn_handler("/api/wifi/networks", Method::Delete, |request| {
+        let Some(ssid) = query_param(request.uri(), "ssid") else {
+            return reply_error(request, 400, "missing ssid parameter");
+        };
+        match remove_network(&ssid)? {
+            true => reply_json(request, 200, &serde_json::json!({ "ok": true })),
+            false => reply_error(request, 404, "unknown ssid"),
+        }
+    })?;
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct HostnameRequest {
+    hostname: String,
+}
+
+pub fn register_device(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/hostname", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({
+            "hostname": mdns::hostname(),
+            "default": mdns::default_hostname(),
+        }))
+    })?;
+
+    server.fn_handler("/api/hostname", Method::Post, |mut request| {
+        let body: HostnameRequest = match read_json(&mut request) {
+            Ok(b) => b,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        match mdns::set_hostname(&body.hostname) {
+            Ok(name) => {
+                // the DHCP server only learns a new name when the lease is renewed
+                if matches!(ipconfig::load(), Ok(IpSettings::Dhcp { hostname: None })) {
+                    request_reconnect();
+                }
+                reply_json(request, 200, &serde_json::json!({ "hostname": name }))
+            },
+            Err(e) => reply_error(request, 400, &e.to_string()),
+        }
+    })?;
+
+    Ok(())
+}
+
+pub fn register_wifi_diagnostics(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/wifi/scan", Method::Get, |request| {
+        match wifi::request_scan(SCAN_TIMEOUT) {
+            Ok(scan) => {
+                let mut entries: Vec<link::ScanEntry> = scan.iter().map(link::ScanEntry::from).collect();
+                entries.sort_by(|a, b| b.rssi.cmp(&a.rssi));
+                reply_json(request, 200, &entries)
+            },
+            Err(e) => reply_error(request, 503, &e.to_string()),
+        }
+    })?;
+
+    server.fn_handler("/api/wifi/link", Method::Get, |request| {
+        reply_json(request, 200, &link::report())
+    })?;
+
+    Ok(())
+}
+
+pub fn register_network(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/network/ip", Method::Get, |request| {
+        let settings = ipconfig::load()?;
+        reply_json(request, 200, &serde_json::json!({
+            "settings": settings,
+            "current": link::ip_config(),
+        }))
+    })?;
+
+    server.fn_handler("/api/network/ip", Method::Post, |mut request| {
+        let settings: IpSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = ipconfig::store(&settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        info!("new ip settings: {:?}", settings);
+        request_reconnect();
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_power(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/wifi/power", Method::Get, |request| {
+        let settings = power::load()?;
+        reply_json(request, 200, &serde_json::json!({
+            "settings": settings,
+            "listen_interval": settings.listen_interval(),
+        }))
+    })?;
+
+    server.fn_handler("/api/wifi/power", Method::Post, |mut request| {
+        let settings: PowerSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = power::store(&settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        info!("new power settings: {:?}", settings);
+        // power save and TX power take effect now, the listen interval on the next association
+        if let Err(e) = power::apply() {
+            warn!("power settings: {}", e);
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_status(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/status", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({
+            "model": mdns::MODEL,
+            "firmware": mdns::FIRMWARE_VERSION,
+            "hostname": mdns::hostname(),
+            "uptime_secs": link::uptime_secs(),
+            "time": clock::report(),
+            "outbox": delivery::stats(),
+            "captures": recorder::stats(),
+            "local_time": timezone::now_local().to_rfc3339(),
+        }))
+    })?;
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize)]
+struct TimezoneRequest {
+    zone: String,
+}
+
+pub fn register_timezone(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/time/zone", Method::Get, |request| {
+        let zone = timezone::zone();
+        reply_json(request, 200, &serde_json::json!({
+            "zone": zone,
+            "posix": posix_tz::resolve(&zone).ok(),
+            "abbreviation": timezone::abbreviation(),
+            "local_time": timezone::now_local().to_rfc3339(),
+            "known_zones": posix_tz::ZONES.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+        }))
+    })?;
+
+    server.fn_handler("/api/time/zone", Method::Post, |mut request| {
+        let body: TimezoneRequest = match read_json(&mut request) {
+            Ok(b) => b,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = timezone::set_zone(&body.zone) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        reply_json(request, 200, &serde_json::json!({
+            "zone": body.zone,
+            "local_time": timezone::now_local().to_rfc3339(),
+        }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_time_sources(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/time/ntp", Method::Get, |request| {
+        let settings = ntp::load_settings()?;
+        reply_json(request, 200, &serde_json::json!({
+            "settings": settings,
+            "dhcp_servers": ntp::dhcp_servers(),
+            "time": clock::report(),
+        }))
+    })?;
+
+    server.fn_handler("/api/time/ntp", Method::Post, |mut request| {
+        let settings: NtpSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = ntp::store_settings(&settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        info!("new ntp settings: {:?}", settings);
+        clock::request_sync();
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_upload(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/upload", Method::Get, |request| {
+        reply_json(request, 200, &upload::load()?.redacted())
+    })?;
+
+    server.fn_handler("/api/upload", Method::Post, |mut request| {
+        let settings: UploadSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = upload::store(settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    // captures and uploads a frame now, even with automatic uploads turned off
+    server.fn_handler("/api/upload/test", Method::Post, |request| {
+        upload::request_capture(Trigger::Manual);
+        reply_json(request, 202, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_webhook(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/webhook", Method::Get, |request| {
+        reply_json(request, 200, &webhook::load()?.redacted())
+    })?;
+
+    server.fn_handler("/api/webhook", Method::Post, |mut request| {
+        let settings: WebhookSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = webhook::store(settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    // sends a "test" event whatever the event filter says
+    server.fn_handler("/api/webhook/test", Method::Post, |request| {
+        if !webhook::load()?.enabled {
+            return reply_error(request, 409, "webhook is disabled");
+        }
+        webhook::notify(Event::new(EventKind::Test, "sent from /api/webhook/test"));
+        reply_json(request, 202, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_recorder(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/recorder", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({
+            "settings": recorder::load()?,
+            "stats": recorder::stats(),
+        }))
+    })?;
+
+    // a smaller quota deletes the oldest frames right away
+    server.fn_handler("/api/recorder", Method::Post, |mut request| {
+        let settings: RecorderSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = recorder::store(settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+pub fn register_mqtt(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/mqtt", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({
+            "settings": mqtt::load()?.redacted(),
+            "status": mqtt::status(),
+        }))
+    })?;
+
+    // reconnects with the new settings in the background
+    server.fn_handler("/api/mqtt", Method::Post, |mut request| {
+        let settings: MqttSettings = match read_json(&mut request) {
+            Ok(s) => s,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = mqtt::store(settings) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    Ok(())
+}
+
+/// The HTTP side of `commands`, MQTT runs the same ones from its command topics.
+pub fn register_commands(server: &mut EspHttpServer) -> anyhow::Result<()> {
+    server.fn_handler("/api/command", Method::Post, |mut request| {
+        let command: Command = match read_json(&mut request) {
+            Ok(c) => c,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        match commands::execute(command) {
+            Ok(result) => reply_json(request, 200, &serde_json::json!({ "ok": true, "result": result })),
+            Err(e) => reply_error(request, 400, &e.to_string()),
+        }
+    })?;
+
+    server.fn_handler("/api/armed", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({ "armed": commands::is_armed() }))
+    })?;
+
+    server.fn_handler("/api/camera/sensor", Method::Get, |request| {
+        reply_json(request, 200, &sensor::load()?)
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,501 @@
+// Captured frames kept on the device, with an index and quotas.
+//
+// Every frame is one JPEG named after when it was taken and why, e.g.
+// `20261019-120301_motion_000123.jpg`. The index is an append-only journal
+// of JSON lines next to them: a record is journaled before its file is
+// written and a removal before its file is deleted, so after a power cut the
+// directory and the journal are reconciled by dropping whatever only one of
+// them knows. A torn last line is ignored. The tests below replay each of
+// those cuts in a temporary directory.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{TimeZone, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+const EXTENSION: &str = "jpg";
+const TEMP_EXTENSION: &str = "tmp";
+const JOURNAL: &str = "index.log";
+/// Compacted journal on its way to replacing `JOURNAL`.
+const JOURNAL_NEW: &str = "index.new";
+/// Journal lines allowed beyond one per record before it gets compacted.
+const JOURNAL_SLACK: usize = 64;
+
+/// One stored frame.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub id: u64,
+    /// Id of the first capture of the event this one belongs to.
+    pub event_id: u64,
+    pub taken_unix_ms: u64,
+    /// Whether the clock was set, `taken_unix_ms` is time since boot otherwise.
+    pub time_synced: bool,
+    pub trigger: String,
+    pub size: u64,
+}
+
+impl Record {
+    pub fn file_name(&self) -> String {
+        let taken = if self.time_synced {
+            Utc.timestamp_millis_opt(self.taken_unix_ms as i64)
+                .single()
+                .map_or_else(|| "unknown".to_owned(), |t| t.format("%Y%m%d-%H%M%S").to_string())
+        } else {
+            "unsynced".to_owned()
+        };
+        format!("{}_{}_{:06}.{}", taken, self.trigger, self.id, EXTENSION)
+    }
+}
+
+/// What the caller knows about a frame before it is stored.
+#[derive(Clone, Debug)]
+pub struct NewCapture {
+    pub taken_unix_ms: u64,
+    pub time_synced: bool,
+    pub trigger: String,
+    /// Continues this event, a new one starts otherwise.
+    pub event_id: Option<u64>,
+}
+
+/// Which records a listing or a deletion covers; unset fields match everything.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Filter {
+    /// Inclusive, in ms since the epoch. Frames taken before the clock was set never match a time.
+    pub from_unix_ms: Option<u64>,
+    /// Exclusive.
+    pub to_unix_ms: Option<u64>,
+    pub event_id: Option<u64>,
+}
+
+impl Filter {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, record: &Record) -> bool {
+        let timed = self.from_unix_ms.is_some() || self.to_unix_ms.is_some();
+        (!timed || record.time_synced)
+            && self.from_unix_ms.map_or(true, |from| record.taken_unix_ms >= from)
+            && self.to_unix_ms.map_or(true, |to| record.taken_unix_ms < to)
+            && self.event_id.map_or(true, |event| record.event_id == event)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    pub max_bytes: u64,
+    pub max_files: usize,
+    /// Files take whole clusters of this size, and `max_bytes` counts clusters.
+    pub cluster_bytes: u64,
+}
+
+impl Quota {
+    /// Flash taken by a file of `size` bytes.
+    pub fn on_flash(&self, size: u64) -> u64 {
+        let cluster = self.cluster_bytes.max(1);
+        size.div_ceil(cluster) * cluster
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    pub files: usize,
+    /// Flash taken, in whole clusters.
+    pub bytes: u64,
+    pub events: usize,
+    /// Frames deleted to make room since boot.
+    pub rotated: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JournalLine {
+    Add(Record),
+    Remove(u64),
+}
+
+pub struct CaptureStore {
+    dir: PathBuf,
+    quota: Quota,
+    /// Oldest first, ids only ever grow.
+    records: Vec<Record>,
+    next_id: u64,
+    journal_lines: usize,
+    rotated: u32,
+}
+
+impl CaptureStore {
+    /// Opens the store in `dir`, repairing whatever a power cut left behind.
+    pub fn open(dir: impl Into<PathBuf>, quota: Quota) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        recover_journal(&dir)?;
+        let (records, journal_lines) = read_journal(&dir.join(JOURNAL))?;
+        let mut store = Self { dir, quota, records, next_id: 1, journal_lines, rotated: 0 };
+        store.reconcile()?;
+        store.next_id = store.records.last().map_or(1, |r| r.id + 1);
+        store.compact()?;
+        store.enforce(0, 0)?;
+        Ok(store)
+    }
+
+    /// Drops records without a file, then files without a record.
+    fn reconcile(&mut self) -> Result<()> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(TEMP_EXTENSION) => fs::remove_file(&path)?,
+                Some(EXTENSION) => files.push(path),
+                _ => {},
+            }
+        }
+        self.records.retain(|record| {
+            let present = files.contains(&self.dir.join(record.file_name()));
+            if !present {
+                warn!("captures: {} is missing, dropping it from the index", record.file_name());
+            }
+            present
+        });
+        for path in files {
+            let known = path.file_name().and_then(|n| n.to_str()).is_some_and(|name| {
+                self.records.iter().any(|r| r.file_name() == name)
+            });
+            if !known {
+                warn!("captures: removing {:?}, it is not in the index", path);
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, line: &JournalLine) -> Result<()> {
+        let mut json = serde_json::to_vec(line)?;
+        json.push(b'\n');
+        let mut journal = OpenOptions::new().create(true).append(true).open(self.dir.join(JOURNAL))?;
+        journal.write_all(&json)?;
+        journal.sync_all()?;
+        self.journal_lines += 1;
+        Ok(())
+    }
+
+    /// Rewrites the journal with only the current records.
+    fn compact(&mut self) -> Result<()> {
+        let new = self.dir.join(JOURNAL_NEW);
+        {
+            let mut file = File::create(&new)?;
+            for record in &self.records {
+                let mut json = serde_json::to_vec(&JournalLine::Add(record.clone()))?;
+                json.push(b'\n');
+                file.write_all(&json)?;
+            }
+            file.sync_all()?;
+        }
+        // FAT cannot rename over a file; `recover_journal` handles a cut in between
+        remove_if_exists(&self.dir.join(JOURNAL))?;
+        fs::rename(&new, self.dir.join(JOURNAL))?;
+        self.journal_lines = self.records.len();
+        Ok(())
+    }
+
+    fn compact_if_needed(&mut self) -> Result<()> {
+        if self.journal_lines > self.records.len() + JOURNAL_SLACK {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest frames until `files` more frames of `bytes` fit.
+    fn enforce(&mut self, files: usize, bytes: u64) -> Result<()> {
+        while let Some(oldest) = self.records.first() {
+            let over_files = self.records.len() + files > self.quota.max_files;
+            let over_bytes = self.bytes() + bytes > self.quota.max_bytes;
+            if !over_files && !over_bytes {
+                break;
+            }
+            let id = oldest.id;
+            self.remove(id)?;
+            self.rotated += 1;
+        }
+        Ok(())
+    }
+
+    /// Applies a new quota, deleting the oldest frames if the store is now over it.
+    pub fn set_quota(&mut self, quota: Quota) -> Result<()> {
+        self.quota = quota;
+        self.enforce(0, 0)
+    }
+
+    fn bytes(&self) -> u64 {
+        self.records.iter().map(|r| self.quota.on_flash(r.size)).sum()
+    }
+
+    /// Stores a frame, rotating out the oldest ones until it fits.
+    pub fn add(&mut self, capture: NewCapture, jpeg: &[u8]) -> Result<Record> {
+        let size = jpeg.len() as u64;
+        if self.quota.on_flash(size) > self.quota.max_bytes || self.quota.max_files == 0 {
+            return Err(anyhow!("a {} byte frame does not fit the quota of {} bytes", size, self.quota.max_bytes));
+        }
+        self.enforce(1, self.quota.on_flash(size))?;
+
+        let id = self.next_id;
+        let record = Record {
+            id,
+            event_id: capture.event_id.filter(|&event| self.records.iter().any(|r| r.event_id == event)).unwrap_or(id),
+            taken_unix_ms: capture.taken_unix_ms,
+            time_synced: capture.time_synced,
+            trigger: capture.trigger,
+            size,
+        };
+        self.next_id += 1;
+        self.append(&JournalLine::Add(record.clone()))?;
+
+        let path = self.path(&record);
+        let temp = path.with_extension(TEMP_EXTENSION);
+        let written = (|| -> io::Result<()> {
+            let mut file = File::create(&temp)?;
+            file.write_all(jpeg)?;
+            file.sync_all()?;
+            drop(file);
+            fs::rename(&temp, &path)
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            // leaves the record to be dropped at the next open if this fails as well
+            let _ = self.append(&JournalLine::Remove(id));
+            return Err(e.into());
+        }
+        self.records.push(record.clone());
+        self.compact_if_needed()?;
+        Ok(record)
+    }
+
+    /// Deletes a frame; unknown ids are not an error.
+    pub fn remove(&mut self, id: u64) -> Result<()> {
+        let Some(index) = self.records.iter().position(|r| r.id == id) else {
+            return Ok(());
+        };
+        self.append(&JournalLine::Remove(id))?;
+        let record = self.records.remove(index);
+        remove_if_exists(&self.path(&record))?;
+        self.compact_if_needed()
+    }
+
+    /// Deletes every frame `filter` matches, returns how many there were.
+    pub fn remove_matching(&mut self, filter: &Filter) -> Result<usize> {
+        let ids: Vec<u64> = self.select(filter).map(|r| r.id).collect();
+        for &id in &ids {
+            self.remove(id)?;
+        }
+        Ok(ids.len())
+    }
+
+    /// Matching records, oldest first.
+    pub fn select<'s>(&'s self, filter: &'s Filter) -> impl Iterator<Item = &'s Record> + 's {
+        self.records.iter().filter(move |r| filter.matches(r))
+    }
+
+    /// Looks a frame up by id or by file name.
+    pub fn find(&self, id_or_name: &str) -> Option<&Record> {
+        match id_or_name.parse::<u64>() {
+            Ok(id) => self.records.iter().find(|r| r.id == id),
+            Err(_) => self.records.iter().find(|r| r.file_name() == id_or_name),
+        }
+    }
+
+    pub fn latest(&self) -> Option<&Record> {
+        self.records.last()
+    }
+
+    pub fn path(&self, record: &Record) -> PathBuf {
+        self.dir.join(record.file_name())
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        let mut events: Vec<u64> = self.records.iter().map(|r| r.event_id).collect();
+        events.sort_unstable();
+        events.dedup();
+        StoreStats { files: self.records.len(), bytes: self.bytes(), events: events.len(), rotated: self.rotated }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Finishes a compaction a power cut interrupted.
+fn recover_journal(dir: &Path) -> Result<()> {
+    let journal = dir.join(JOURNAL);
+    let new = dir.join(JOURNAL_NEW);
+    if !new.exists() {
+        return Ok(());
+    }
+    if journal.exists() {
+        // the old journal was still there, so the new one may be incomplete
+        fs::remove_file(&new)?;
+    } else {
+        fs::rename(&new, &journal)?;
+    }
+    Ok(())
+}
+
+/// Replays the journal, stopping at a line a power cut tore.
+fn read_journal(path: &Path) -> Result<(Vec<Record>, usize)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records: Vec<Record> = Vec::new();
+    let mut lines = 0;
+    for line in BufReader::new(file).split(b'\n') {
+        let line = line?;
+        match serde_json::from_slice(&line) {
+            Ok(JournalLine::Add(record)) => {
+                records.retain(|r| r.id != record.id);
+                records.push(record);
+            },
+            Ok(JournalLine::Remove(id)) => records.retain(|r| r.id != id),
+            Err(e) => {
+                warn!("captures: index ends in a damaged line after {} lines: {}", lines, e);
+                break;
+            },
+        }
+        lines += 1;
+    }
+    records.sort_by_key(|r| r.id);
+    Ok((records, lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: Quota = Quota { max_bytes: 1 << 20, max_files: 100, cluster_bytes: 4096 };
+
+    fn capture(n: u64) -> NewCapture {
+        NewCapture {
+            taken_unix_ms: 1_760_000_000_000 + n * 1000,
+            time_synced: true,
+            trigger: "motion".to_owned(),
+            event_id: None,
+        }
+    }
+
+    fn ids(store: &CaptureStore) -> Vec<u64> {
+        store.select(&Filter::default()).map(|r| r.id).collect()
+    }
+
+    /// A store in a fresh directory holding frames 1 to `count`.
+    fn filled(count: u64) -> (tempfile::TempDir, CaptureStore) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        for n in 1..=count {
+            store.add(capture(n), &[n as u8; 100]).unwrap();
+        }
+        (dir, store)
+    }
+
+    #[test]
+    fn stores_and_finds_frames() {
+        let (dir, mut store) = filled(3);
+        let record = store.find("2").unwrap().clone();
+        assert_eq!(record.file_name(), "20251009-085322_motion_000002.jpg");
+        assert_eq!(store.find(&record.file_name()), Some(&record));
+        assert_eq!(fs::read(store.path(&record)).unwrap(), [2; 100]);
+
+        store.remove(2).unwrap();
+        assert_eq!(ids(&store), [1, 3]);
+        assert!(!dir.path().join(record.file_name()).exists());
+
+        let store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 3]);
+        assert_eq!(store.latest().map(|r| r.id), Some(3));
+    }
+
+    #[test]
+    fn quota_counts_whole_clusters() {
+        let dir = tempfile::tempdir().unwrap();
+        let quota = Quota { max_bytes: 3 * 4096, ..QUOTA };
+        let mut store = CaptureStore::open(dir.path(), quota).unwrap();
+        for n in 1..=3 {
+            store.add(capture(n), &[0; 100]).unwrap();
+        }
+        assert_eq!(store.stats().bytes, 3 * 4096);
+
+        // one byte over a cluster takes two, so two frames make way
+        store.add(capture(4), &[0; 4097]).unwrap();
+        let stats = store.stats();
+        assert_eq!((stats.files, stats.bytes, stats.rotated), (2, 3 * 4096, 2));
+        assert_eq!(ids(&store), [3, 4]);
+
+        assert!(store.add(capture(5), &[0; 3 * 4096 + 1]).is_err());
+    }
+
+    #[test]
+    fn ignores_a_torn_journal_line() {
+        let (dir, store) = filled(2);
+        drop(store);
+        let mut journal = OpenOptions::new().append(true).open(dir.path().join(JOURNAL)).unwrap();
+        journal.write_all(br#"{"add":{"id":3,"event_id":3,"tak"#).unwrap();
+
+        let mut store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 2]);
+        assert_eq!(store.add(capture(3), b"next").unwrap().id, 3);
+        // the torn line is gone, so it does not cut off what came after it
+        let store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 2, 3]);
+    }
+
+    #[test]
+    fn removes_orphan_files() {
+        let (dir, store) = filled(2);
+        drop(store);
+        fs::write(dir.path().join("20251009-085323_motion_000003.tmp"), b"half").unwrap();
+        fs::write(dir.path().join("20251009-085323_motion_000003.jpg"), b"unindexed").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"keep").unwrap();
+
+        let store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 2]);
+        assert!(!dir.path().join("20251009-085323_motion_000003.tmp").exists());
+        assert!(!dir.path().join("20251009-085323_motion_000003.jpg").exists());
+        assert!(dir.path().join("notes.txt").exists());
+    }
+
+    #[test]
+    fn drops_records_whose_file_is_missing() {
+        let (dir, store) = filled(3);
+        let missing = store.find("2").unwrap().file_name();
+        drop(store);
+        fs::remove_file(dir.path().join(missing)).unwrap();
+
+        let mut store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 3]);
+        assert_eq!(store.add(capture(4), b"next").unwrap().id, 4);
+    }
+
+    #[test]
+    fn finishes_or_discards_an_interrupted_compaction() {
+        // cut before the old journal was removed: the new one may be incomplete
+        let (dir, store) = filled(2);
+        drop(store);
+        fs::write(dir.path().join(JOURNAL_NEW), br#"{"add":{"id":1"#).unwrap();
+        let store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 2]);
+        assert!(!dir.path().join(JOURNAL_NEW).exists());
+
+        // cut between removing the old journal and the rename: the new one is whole
+        drop(store);
+        fs::rename(dir.path().join(JOURNAL), dir.path().join(JOURNAL_NEW)).unwrap();
+        let store = CaptureStore::open(dir.path(), QUOTA).unwrap();
+        assert_eq!(ids(&store), [1, 2]);
+        assert!(dir.path().join(JOURNAL).exists());
+    }
+}
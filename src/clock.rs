@@ -0,0 +1,220 @@
+// Time service: keeps the system clock synced over NTP and tracks how far it
+// can be trusted.
+//
+// Queries block for seconds per server, so this runs on its own thread rather
+// than on the executor. The Wi-Fi task pokes it with `request_sync` whenever
+// the link comes up.
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::link;
+use crate::ntp::{self, ntp_sync, Synced};
+use crate::preludes::*;
+use crate::sntp::Sample;
+use crate::window::ClockState;
+
+const RESYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETRY_BASE: Duration = Duration::from_secs(30);
+/// Failed syncs in a row before it is reported as an error event.
+const REPORT_FAILURES: u32 = 3;
+/// How often the status is re-evaluated between syncs, so it can turn stale.
+const STATUS_CHECK: Duration = Duration::from_secs(60);
+/// Trust is lost after this long without a sync, whatever the drift estimate says.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+/// ... or once the estimated drift since the last sync exceeds this.
+const MAX_ESTIMATED_ERROR_MICROS: f64 = 1_000_000.0;
+/// Syncs closer together than this are too noisy to estimate drift from.
+const MIN_DRIFT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Weight of the newest drift measurement in the running average.
+const DRIFT_SMOOTHING: f64 = 0.25;
+
+lazy_static! {
+    static ref SYNC: Mutex<SyncState> = Mutex::new(SyncState::default());
+    static ref SYNC_REQUESTS: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeStatus {
+    /// Never synced since boot, the clock is meaningless.
+    Unsynced,
+    Synced,
+    /// Synced once but not recently enough to trust to the second.
+    Stale,
+}
+
+impl From<TimeStatus> for ClockState {
+    fn from(status: TimeStatus) -> Self {
+        match status {
+            TimeStatus::Unsynced => ClockState::Unsynced,
+            TimeStatus::Synced => ClockState::Synced,
+            TimeStatus::Stale => ClockState::Stale,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SyncState {
+    last_sync: Option<Instant>,
+    last_sample: Option<Sample>,
+    source: Option<String>,
+    last_sync_unix: Option<u64>,
+    /// Positive when the local clock runs slow.
+    drift_ppm: Option<f64>,
+    failures: u32,
+}
+
+impl SyncState {
+    fn record(&mut self, Synced { sample, source }: Synced) {
+        let now = Instant::now();
+        if let Some(last) = self.last_sync {
+            let elapsed = now - last;
+            if elapsed >= MIN_DRIFT_INTERVAL {
+                // the clock was stepped at the last sync, so the whole offset built up since then
+                let ppm = sample.offset_micros as f64 / elapsed.as_secs_f64();
+                self.drift_ppm = Some(match self.drift_ppm {
+                    Some(d) => d + DRIFT_SMOOTHING * (ppm - d),
+                    None => ppm,
+                });
+            }
+        }
+        self.last_sync = Some(now);
+        self.last_sample = Some(sample);
+        self.source = Some(source);
+        self.last_sync_unix = unix_now();
+        self.failures = 0;
+    }
+
+    fn estimated_error_micros(&self) -> Option<f64> {
+        let elapsed = self.last_sync?.elapsed().as_secs_f64();
+        let drift = self.drift_ppm.unwrap_or(0.0).abs();
+        let delay = self.last_sample.map_or(0, |s| s.delay_micros) as f64;
+        // half the round trip is the best the offset could be trusted to
+        Some(drift * elapsed + delay / 2.0)
+    }
+
+    fn status(&self) -> TimeStatus {
+        match self.last_sync {
+            None => TimeStatus::Unsynced,
+            Some(last) if last.elapsed() > STALE_AFTER => TimeStatus::Stale,
+            Some(_) if self.estimated_error_micros().unwrap_or(0.0) > MAX_ESTIMATED_ERROR_MICROS => TimeStatus::Stale,
+            Some(_) => TimeStatus::Synced,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimeReport {
+    pub status: TimeStatus,
+    pub source: Option<String>,
+    pub now_unix: Option<u64>,
+    pub last_sync_unix: Option<u64>,
+    pub since_last_sync_secs: Option<u64>,
+    pub offset_micros: Option<i64>,
+    pub delay_micros: Option<i64>,
+    pub stratum: Option<u8>,
+    pub drift_ppm: Option<f64>,
+    pub estimated_error_ms: Option<f64>,
+    pub failures: u32,
+}
+
+fn unix_now() -> Option<u64> {
+    SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+/// Whether the system clock can be trusted, for anything that timestamps data.
+pub fn status() -> TimeStatus {
+    SYNC.lock().status()
+}
+
+pub fn is_synced() -> bool {
+    status() == TimeStatus::Synced
+}
+
+pub fn report() -> TimeReport {
+    let sync = SYNC.lock();
+    TimeReport {
+        status: sync.status(),
+        source: sync.source.clone(),
+        now_unix: unix_now(),
+        last_sync_unix: sync.last_sync_unix,
+        since_last_sync_secs: sync.last_sync.map(|t| t.elapsed().as_secs()),
+        offset_micros: sync.last_sample.map(|s| s.offset_micros),
+        delay_micros: sync.last_sample.map(|s| s.delay_micros),
+        stratum: sync.last_sample.map(|s| s.stratum),
+        drift_ppm: sync.drift_ppm,
+        estimated_error_ms: sync.estimated_error_micros().map(|e| e / 1000.0),
+        failures: sync.failures,
+    }
+}
+
+/// Asks for a sync as soon as possible, e.g. when the network comes up.
+pub fn request_sync() {
+    let _ = SYNC_REQUESTS.0.try_send(());
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_BASE.saturating_mul(1 << failures.saturating_sub(1).min(8)).min(RESYNC_INTERVAL)
+}
+
+fn run(tx: InfoSender) {
+    let mut next_sync = Instant::now();
+    let mut shown: Option<TimeStatus> = None;
+
+    loop {
+        let wait = next_sync.saturating_duration_since(Instant::now()).min(STATUS_CHECK);
+        match SYNC_REQUESTS.1.recv_timeout(wait) {
+            Ok(()) => next_sync = Instant::now(),
+            Err(flume::RecvTimeoutError::Timeout) => {},
+            Err(flume::RecvTimeoutError::Disconnected) => return,
+        }
+
+        if Instant::now() >= next_sync {
+            if !link::is_connected() {
+                // the Wi-Fi task asks again once it is online
+                next_sync = Instant::now() + RESYNC_INTERVAL;
+            } else {
+                match ntp_sync() {
+                    Ok(synced) => {
+                        SYNC.lock().record(synced);
+                        next_sync = Instant::now() + RESYNC_INTERVAL;
+                    },
+                    Err(e) => {
+                        let mut sync = SYNC.lock();
+                        sync.failures += 1;
+                        let delay = retry_delay(sync.failures);
+                        error!("time sync failed ({} in a row), retrying in {:?}: {}", sync.failures, delay, e);
+                        if sync.failures == REPORT_FAILURES {
+                            let _ = tx.send(InfoUpdate::Error(format!("time sync keeps failing: {}", e)));
+                        }
+                        next_sync = Instant::now() + delay;
+                    },
+                }
+            }
+        }
+
+        let status = status();
+        if shown != Some(status) {
+            info!("time status: {:?}", status);
+            if tx.send(InfoUpdate::TimeSync(status.into())).is_err() {
+                return;
+            }
+            shown = Some(status);
+        }
+    }
+}
+
+pub fn start(tx: InfoSender) -> Result<()> {
+    ntp::enable_dhcp_servers();
+    thread::Builder::new()
+        .name("time_sync".to_owned())
+        .stack_size(8 * 1024)
+        .spawn(move || run(tx))?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// Remote control of the camera, shared by the HTTP API and MQTT.
+//
+// A command is a JSON object tagged with its name, e.g.
+// `{"command": "arm", "armed": false}`. Both transports parse into `Command`
+// and run it through `execute`, so they accept the same commands and report
+// the same results.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use esp_camera_rs::Camera;
+use esp_idf_sys::esp_restart;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::ota;
+use crate::preludes::*;
+use crate::sensor::{self, SensorPatch};
+use crate::settings::{Key, SETTINGS};
+use crate::upload::{self, Trigger};
+
+/// Whether motion triggers captures and motion events; the sensor state is reported either way.
+pub const ARMED: Key<bool> = Key::new("armed", || true);
+
+const MAX_MESSAGE_LEN: usize = 20;
+/// Time for the reply to get out before the restart.
+const REBOOT_DELAY: Duration = Duration::from_secs(2);
+
+static CONTEXT: OnceCell<Context> = OnceCell::new();
+
+struct Context {
+    camera: Arc<Mutex<Camera>>,
+    tx: InfoSender,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Command {
+    /// Captures a frame for every configured destination.
+    Snapshot,
+    Arm { armed: bool },
+    Sensor(SensorPatch),
+    Reboot,
+    /// Shows a short text on the display.
+    Message { text: String },
+    /// Looks for new firmware on the manifest server. Installing outside the
+    /// maintenance window needs the update token, so that stays with `/api/ota/check`.
+    Ota,
+}
+
+impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Snapshot => "snapshot",
+            Command::Arm { .. } => "arm",
+            Command::Sensor(_) => "sensor",
+            Command::Reboot => "reboot",
+            Command::Message { .. } => "message",
+            Command::Ota => "ota",
+        }
+    }
+
+    /// Builds the command `name` from its JSON arguments, an empty payload means none.
+    pub fn parse(name: &str, args: &[u8]) -> Result<Self> {
+        let mut value = if args.iter().all(u8::is_ascii_whitespace) {
+            json!({})
+        } else {
+            serde_json::from_slice(args)?
+        };
+        let object = value.as_object_mut().ok_or_else(|| anyhow!("arguments must be a JSON object"))?;
+        object.insert("command".to_owned(), Value::String(name.to_owned()));
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+pub fn is_armed() -> bool {
+    SETTINGS.lock().get(&ARMED).unwrap_or(true)
+}
+
+fn context() -> Result<&'static Context> {
+    CONTEXT.get().ok_or_else(|| anyhow!("commands are not ready yet"))
+}
+
+/// Runs a command, the result is what the caller gets back on success.
+pub fn execute(command: Command) -> Result<Value> {
+    info!("command: {:?}", command);
+    let context = context()?;
+    match command {
+        Command::Snapshot => {
+            upload::request_capture(Trigger::Manual);
+            Ok(json!({ "requested": true }))
+        },
+        Command::Arm { armed } => {
+            SETTINGS.lock().set(&ARMED, &armed)?;
+            let _ = context.tx.send(InfoUpdate::Armed(armed));
+            Ok(json!({ "armed": armed }))
+        },
+        Command::Sensor(patch) => Ok(serde_json::to_value(sensor::update(&context.camera, &patch)?)?),
+        Command::Reboot => {
+            thread::Builder::new().name("reboot".to_owned()).spawn(|| {
+                thread::sleep(REBOOT_DELAY);
+                unsafe { esp_restart() };
+            })?;
+            Ok(json!({ "rebooting_in_secs": REBOOT_DELAY.as_secs() }))
+        },
+        Command::Message { text } => {
+            let text: String = text.chars().filter(|c| !c.is_control()).take(MAX_MESSAGE_LEN).collect();
+            let _ = context.tx.send(InfoUpdate::Notice(text.clone()));
+            Ok(json!({ "text": text }))
+        },
+        Command::Ota => {
+            if ota::load()?.pull.manifest_url.is_none() {
+                return Err(anyhow!("no manifest server configured, see /api/ota/pull"));
+            }
+            ota::request_check(false);
+            Ok(json!({ "checking": true }))
+        },
+    }
+}
+
+/// Makes the camera and display reachable for commands and shows the armed state.
+pub fn init(camera: Arc<Mutex<Camera>>, tx: InfoSender) -> Result<()> {
+    let _ = tx.send(InfoUpdate::Armed(is_armed()));
+    CONTEXT
+        .set(Context { camera, tx })
+        .map_err(|_| anyhow!("commands already initialised"))
+}
@@ -0,0 +1,323 @@
+// Wi-Fi connection state machine.
+//
+// This module only decides what to do next; the Wi-Fi task in wifi.rs feeds it
+// events from the system event loop and from its own scan/connect attempts,
+// and carries out the returned actions. Nothing in here touches ESP-IDF so it
+// can be exercised on the host.
+
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnState {
+    Idle,
+    Scanning,
+    Connecting,
+    Connected,
+    Backoff { attempt: u32 },
+    Provisioning,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnEvent {
+    Start,
+    /// `candidates` is the number of known networks found by the scan.
+    ScanDone { candidates: usize },
+    ScanFailed,
+    GotIp,
+    ConnectFailed,
+    Disconnected,
+    LostIp,
+    BackoffElapsed,
+    ProvisioningRequested,
+    ProvisioningDone,
+    /// Settings changed and the link has to be re-established to pick them up.
+    ReconnectRequested,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnAction {
+    Scan,
+    Connect,
+    Wait(Duration),
+    Provision,
+    Online,
+    Disconnect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackoffConfig {
+    pub base: Duration,
+    pub max: Duration,
+    /// Consecutive scans without a known network before falling back to provisioning.
+    pub provision_after: u32,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+            provision_after: 5,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// Exponential delay for `attempt` (starting at 1) with "equal jitter":
+    /// half of the delay is fixed, the other half is drawn from `random`.
+    pub fn delay(&self, attempt: u32, random: u32) -> Duration {
+        let exp = attempt.saturating_sub(1).min(31);
+        let full = self.base.saturating_mul(1u32 << exp).min(self.max);
+        let half = full.as_millis() as u64 / 2;
+        let jitter = u64::from(random) % (half + 1);
+        Duration::from_millis(half + jitter)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionManager {
+    state: ConnState,
+    config: BackoffConfig,
+    failures: u32,
+    unreachable_scans: u32,
+    connects: u32,
+    rng: u32,
+}
+
+impl ConnectionManager {
+    pub fn new(config: BackoffConfig, seed: u32) -> Self {
+        Self {
+            state: ConnState::Idle,
+            config,
+            failures: 0,
+            unreachable_scans: 0,
+            connects: 0,
+            // xorshift must not start at zero
+            rng: seed | 1,
+        }
+    }
+
+    pub fn state(&self) -> ConnState {
+        self.state
+    }
+
+    /// Times the link came back after the first successful connection.
+    pub fn reconnects(&self) -> u32 {
+        self.connects.saturating_sub(1)
+    }
+
+    pub fn handle(&mut self, event: ConnEvent) -> Option<ConnAction> {
+        use ConnEvent::*;
+
+        match (self.state, event) {
+            (_, ProvisioningRequested) => {
+                self.state = ConnState::Provisioning;
+                Some(ConnAction::Provision)
+            },
+            (ConnState::Provisioning, ProvisioningDone) => {
+                self.failures = 0;
+                self.unreachable_scans = 0;
+                self.state = ConnState::Scanning;
+                Some(ConnAction::Scan)
+            },
+            (ConnState::Idle, Start) | (ConnState::Backoff { .. }, BackoffElapsed) => {
+                self.state = ConnState::Scanning;
+                Some(ConnAction::Scan)
+            },
+            (ConnState::Scanning, ScanDone { candidates: 0 }) => {
+                self.unreachable_scans += 1;
+                if self.unreachable_scans >= self.config.provision_after {
+                    self.state = ConnState::Provisioning;
+                    return Some(ConnAction::Provision);
+                }
+                Some(self.back_off())
+            },
+            (ConnState::Scanning, ScanDone { .. }) => {
+                self.unreachable_scans = 0;
+                self.state = ConnState::Connecting;
+                Some(ConnAction::Connect)
+            },
+            (ConnState::Scanning, ScanFailed) | (ConnState::Connecting, ConnectFailed) => Some(self.back_off()),
+            (ConnState::Connecting, GotIp) => {
+                self.failures = 0;
+                self.connects += 1;
+                self.state = ConnState::Connected;
+                Some(ConnAction::Online)
+            },
+            (ConnState::Connected, ReconnectRequested) => Some(ConnAction::Disconnect),
+            (ConnState::Connected, Disconnected | LostIp) => {
+                self.failures = 0;
+                Some(self.back_off())
+            },
+            _ => None,
+        }
+    }
+
+    fn back_off(&mut self) -> ConnAction {
+        self.failures += 1;
+        self.state = ConnState::Backoff { attempt: self.failures };
+        let random = self.next_random();
+        ConnAction::Wait(self.config.delay(self.failures, random))
+    }
+
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.rng;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.rng = x;
+        x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> BackoffConfig {
+        BackoffConfig { base: Duration::from_secs(1), max: Duration::from_secs(60), provision_after: 3 }
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let config = config();
+        let fixed: Vec<u64> = (1..=8).map(|attempt| config.delay(attempt, 0).as_millis() as u64).collect();
+        assert_eq!(fixed, [500, 1000, 2000, 4000, 8000, 16000, 30000, 30000]);
+        // far past the shift range it stays capped instead of overflowing
+        assert_eq!(config.delay(u32::MAX, 0), Duration::from_secs(30));
+        assert_eq!(config.delay(0, 0), config.delay(1, 0));
+    }
+
+    #[test]
+    fn jitter_stays_within_the_upper_half() {
+        let config = config();
+        for attempt in 1..=10 {
+            let full = config.base.saturating_mul(1 << (attempt - 1)).min(config.max);
+            for random in [0, 1, 7, 499, 500, 12_345, u32::MAX] {
+                let delay = config.delay(attempt, random);
+                assert!(delay >= full / 2 && delay <= full, "attempt {} random {}: {:?}", attempt, random, delay);
+            }
+        }
+        assert_eq!(config.delay(1, 500), Duration::from_millis(1000));
+        assert_eq!(config.delay(1, 501), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn jitter_differs_between_attempts() {
+        let mut manager = ConnectionManager::new(config(), 0);
+        let draws: Vec<u32> = (0..16).map(|_| manager.next_random()).collect();
+        assert!(draws.iter().all(|&x| x != 0));
+        let mut unique = draws.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), draws.len());
+    }
+
+    fn wait(action: Option<ConnAction>) -> Duration {
+        match action {
+            Some(ConnAction::Wait(delay)) => delay,
+            other => panic!("expected a wait, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn connects_and_reconnects() {
+        let mut manager = ConnectionManager::new(config(), 42);
+        assert_eq!(manager.handle(ConnEvent::Start), Some(ConnAction::Scan));
+        assert_eq!(manager.state(), ConnState::Scanning);
+        assert_eq!(manager.handle(ConnEvent::ScanDone { candidates: 2 }), Some(ConnAction::Connect));
+        assert_eq!(manager.handle(ConnEvent::GotIp), Some(ConnAction::Online));
+        assert_eq!(manager.state(), ConnState::Connected);
+        assert_eq!(manager.reconnects(), 0);
+
+        let delay = wait(manager.handle(ConnEvent::Disconnected));
+        assert!(delay <= config().base);
+        assert_eq!(manager.state(), ConnState::Backoff { attempt: 1 });
+        assert_eq!(manager.handle(ConnEvent::BackoffElapsed), Some(ConnAction::Scan));
+        manager.handle(ConnEvent::ScanDone { candidates: 1 });
+        manager.handle(ConnEvent::GotIp);
+        assert_eq!(manager.reconnects(), 1);
+    }
+
+    #[test]
+    fn failures_back_off_further_until_connected() {
+        let config = config();
+        let mut manager = ConnectionManager::new(config, 7);
+        manager.handle(ConnEvent::Start);
+        for attempt in 1..=4 {
+            manager.handle(ConnEvent::ScanDone { candidates: 1 });
+            let delay = wait(manager.handle(ConnEvent::ConnectFailed));
+            assert_eq!(manager.state(), ConnState::Backoff { attempt });
+            assert!(delay >= config.delay(attempt, 0));
+            manager.handle(ConnEvent::BackoffElapsed);
+        }
+        manager.handle(ConnEvent::ScanDone { candidates: 1 });
+        manager.handle(ConnEvent::GotIp);
+        wait(manager.handle(ConnEvent::LostIp));
+        assert_eq!(manager.state(), ConnState::Backoff { attempt: 1 });
+    }
+
+    #[test]
+    fn empty_scans_fall_back_to_provisioning() {
+        let mut manager = ConnectionManager::new(config(), 1);
+        manager.handle(ConnEvent::Start);
+        for _ in 1..config().provision_after {
+            wait(manager.handle(ConnEvent::ScanDone { candidates: 0 }));
+            manager.handle(ConnEvent::BackoffElapsed);
+        }
+        assert_eq!(manager.handle(ConnEvent::ScanDone { candidates: 0 }), Some(ConnAction::Provision));
+        assert_eq!(manager.state(), ConnState::Provisioning);
+
+        // events from the old attempt are ignored while the portal is up
+        assert_eq!(manager.handle(ConnEvent::BackoffElapsed), None);
+        assert_eq!(manager.handle(ConnEvent::ProvisioningDone), Some(ConnAction::Scan));
+        manager.handle(ConnEvent::ScanDone { candidates: 0 });
+        assert_eq!(manager.state(), ConnState::Backoff { attempt: 1 });
+    }
+
+    #[test]
+    fn a_found_network_resets_the_empty_scan_count() {
+        let mut manager = ConnectionManager::new(config(), 1);
+        manager.handle(ConnEvent::Start);
+        for _ in 0..10 {
+            wait(manager.handle(ConnEvent::ScanDone { candidates: 0 }));
+            manager.handle(ConnEvent::BackoffElapsed);
+            manager.handle(ConnEvent::ScanDone { candidates: 1 });
+            wait(manager.handle(ConnEvent::ConnectFailed));
+            manager.handle(ConnEvent::BackoffElapsed);
+        }
+        assert_ne!(manager.state(), ConnState::Provisioning);
+    }
+
+    #[test]
+    fn provisioning_can_be_requested_from_any_state() {
+        let mut manager = ConnectionManager::new(config(), 1);
+        manager.handle(ConnEvent::Start);
+        manager.handle(ConnEvent::ScanDone { candidates: 1 });
+        manager.handle(ConnEvent::GotIp);
+        assert_eq!(manager.handle(ConnEvent::ProvisioningRequested), Some(ConnAction::Provision));
+        assert_eq!(manager.state(), ConnState::Provisioning);
+    }
+
+    #[test]
+    fn reconnect_request_only_applies_while_connected() {
+        let mut manager = ConnectionManager::new(config(), 1);
+        assert_eq!(manager.handle(ConnEvent::ReconnectRequested), None);
+        manager.handle(ConnEvent::Start);
+        manager.handle(ConnEvent::ScanDone { candidates: 1 });
+        assert_eq!(manager.handle(ConnEvent::ReconnectRequested), None);
+        manager.handle(ConnEvent::GotIp);
+        assert_eq!(manager.handle(ConnEvent::ReconnectRequested), Some(ConnAction::Disconnect));
+        assert_eq!(manager.state(), ConnState::Connected);
+        wait(manager.handle(ConnEvent::Disconnected));
+    }
+
+    #[test]
+    fn stray_events_are_ignored() {
+        let mut manager = ConnectionManager::new(config(), 1);
+        for event in [ConnEvent::GotIp, ConnEvent::Disconnected, ConnEvent::BackoffElapsed, ConnEvent::ProvisioningDone] {
+            assert_eq!(manager.handle(event), None);
+            assert_eq!(manager.state(), ConnState::Idle);
+        }
+    }
+}
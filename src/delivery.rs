@@ -0,0 +1,140 @@
+// Delivers what is waiting in the outbox once the network is there.
+//
+// Senders try to deliver directly and only hand over what failed with a
+// transient error; this thread then retries the oldest message with
+// exponential backoff, starting over whenever the Wi-Fi link comes back.
+
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::http::HttpError;
+use crate::link;
+use crate::outbox::{Backoff, Header, Kind, Outbox, OutboxStats};
+use crate::preludes::*;
+use crate::storage;
+use crate::upload;
+use crate::webhook;
+
+const OUTBOX_DIR: &str = "outbox";
+/// The outbox share of the storage partition, see `storage::CLUSTER_BYTES`.
+const OUTBOX_MAX_BYTES: u64 = 160 * 1024;
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(10 * 60);
+/// How often the link is checked while there is nothing else to wake up for.
+const IDLE_CHECK: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref OUTBOX: Mutex<Option<Outbox>> = Mutex::new(None);
+    static ref WAKE: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+}
+
+/// Whether a failed delivery is worth keeping for later.
+pub fn is_transient(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpError>().is_some_and(HttpError::is_transient)
+}
+
+/// Stores a message for later delivery.
+pub fn enqueue(kind: Kind, meta: &impl Serialize, payload: &[u8]) -> Result<()> {
+    let header = Header {
+        kind,
+        meta: serde_json::to_value(meta)?,
+        created_unix: SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()),
+    };
+    let mut outbox = OUTBOX.lock();
+    let outbox = outbox.as_mut().ok_or_else(|| anyhow!("outbox is not available"))?;
+    let id = outbox.push(&header, payload)?;
+    info!("outbox: queued {:?} message {} ({} bytes)", kind, id, payload.len());
+    let _ = WAKE.0.try_send(());
+    Ok(())
+}
+
+/// True when nothing is waiting, so new messages can skip the queue without reordering.
+pub fn is_empty() -> bool {
+    match OUTBOX.lock().as_ref() {
+        Some(outbox) => outbox.is_empty().unwrap_or(false),
+        None => true,
+    }
+}
+
+pub fn stats() -> Option<OutboxStats> {
+    OUTBOX.lock().as_ref().and_then(|outbox| outbox.stats().ok())
+}
+
+fn send(kind: Kind, meta: &serde_json::Value, payload: Vec<u8>) -> Result<()> {
+    match kind {
+        Kind::Upload => upload::send_queued(meta, payload),
+        Kind::Webhook => webhook::send_queued(meta, payload),
+    }
+}
+
+/// Sends queued messages oldest first until the queue is empty or one fails.
+fn drain(backoff: &mut Backoff) -> Result<()> {
+    loop {
+        let Some(entry) = OUTBOX.lock().as_mut().map(Outbox::oldest).transpose()?.flatten() else {
+            return Ok(());
+        };
+        match send(entry.header.kind, &entry.header.meta, entry.payload) {
+            Ok(()) => {
+                info!("outbox: delivered message {}", entry.id);
+                backoff.reset();
+            },
+            Err(e) if is_transient(&e) => {
+                let delay = backoff.failed(Instant::now());
+                warn!("outbox: message {} failed, retrying in {:?}: {}", entry.id, delay, e);
+                return Ok(());
+            },
+            Err(e) => error!("outbox: dropping message {}: {}", entry.id, e),
+        }
+        if let Some(outbox) = OUTBOX.lock().as_mut() {
+            outbox.remove(entry.id)?;
+        }
+    }
+}
+
+fn run() {
+    let mut backoff = Backoff::new(RETRY_BASE, RETRY_MAX);
+    let mut was_connected = false;
+    loop {
+        let wait = match backoff.remaining(Instant::now()) {
+            Duration::ZERO => IDLE_CHECK,
+            remaining => remaining.min(IDLE_CHECK),
+        };
+        if let Err(flume::RecvTimeoutError::Disconnected) = WAKE.1.recv_timeout(wait) {
+            return;
+        }
+
+        let connected = link::is_connected();
+        if connected && !was_connected {
+            backoff.reset();
+        }
+        was_connected = connected;
+        if !connected || !backoff.ready(Instant::now()) {
+            continue;
+        }
+        if let Err(e) = drain(&mut backoff) {
+            error!("outbox: {}", e);
+        }
+    }
+}
+
+/// Opens the outbox on the storage partition and starts delivering from it.
+pub fn start() -> Result<()> {
+    if !storage::is_mounted() {
+        return Err(anyhow!("storage is not mounted"));
+    }
+    let outbox = Outbox::open(storage::path(OUTBOX_DIR), OUTBOX_MAX_BYTES, storage::CLUSTER_BYTES)?;
+    if let Ok(stats) = outbox.stats() {
+        info!("outbox: {} messages waiting", stats.depth);
+    }
+    *OUTBOX.lock() = Some(outbox);
+    thread::Builder::new()
+        .name("delivery".to_owned())
+        .stack_size(16 * 1024)
+        .spawn(run)?;
+    Ok(())
+}
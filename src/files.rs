@@ -0,0 +1,248 @@
+// `/api/files`: browsing the captures the recorder kept.
+//
+// Listing and deleting take the same filters: `from` and `to` as RFC 3339 or
+// Unix seconds, and `event`. Single files support `Range` and `ETag`; a whole
+// event comes as `/api/files/events/<id>.zip` or `.tar`.
+
+use std::fs::File;
+use std::io::{Read as _, Seek, SeekFrom};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use embedded_svc::http::server::HandlerResult;
+use embedded_svc::http::Headers;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use serde::Serialize;
+
+use crate::api::{query_param, reply_error, reply_json, ApiRequest};
+use crate::captures::{Filter, Record};
+use crate::power;
+use crate::preludes::*;
+use crate::recorder;
+use crate::transfer::{self, ArchiveEntry, ArchiveFormat, ArchiveWriter, RangeRequest};
+
+const PREFIX: &str = "/api/files/";
+const EVENTS: &str = "events/";
+const CHUNK_LEN: usize = 4096;
+
+#[derive(Serialize)]
+struct Listing {
+    id: u64,
+    name: String,
+    size: u64,
+    event_id: u64,
+    trigger: String,
+    /// RFC 3339, unknown for frames taken before the clock was set.
+    taken: Option<String>,
+    url: String,
+}
+
+impl From<&Record> for Listing {
+    fn from(record: &Record) -> Self {
+        Self {
+            id: record.id,
+            name: record.file_name(),
+            size: record.size,
+            event_id: record.event_id,
+            trigger: record.trigger.clone(),
+            taken: record
+                .time_synced
+                .then(|| Utc.timestamp_millis_opt(record.taken_unix_ms as i64).single())
+                .flatten()
+                .map(|t| t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+            url: format!("{}{}", PREFIX, record.id),
+        }
+    }
+}
+
+fn parse_time(value: &str) -> Result<u64> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs * 1000);
+    }
+    let time = DateTime::parse_from_rfc3339(value).map_err(|_| anyhow!("{:?} is neither RFC 3339 nor Unix seconds", value))?;
+    u64::try_from(time.timestamp_millis()).map_err(|_| anyhow!("{:?} is before 1970", value))
+}
+
+fn filter(uri: &str) -> Result<Filter> {
+    Ok(Filter {
+        from_unix_ms: query_param(uri, "from").map(|v| parse_time(&v)).transpose()?,
+        to_unix_ms: query_param(uri, "to").map(|v| parse_time(&v)).transpose()?,
+        event_id: query_param(uri, "event")
+            .map(|v| v.parse::<u64>().map_err(|_| anyhow!("event must be a number")))
+            .transpose()?,
+    })
+}
+
+/// The part of the path after `/api/files/`, without the query.
+fn tail(uri: &str) -> &str {
+    let path = uri.split_once('?').map_or(uri, |(path, _)| path);
+    path.strip_prefix(PREFIX).unwrap_or("")
+}
+
+/// Copies `len` bytes of `file` into the response, failing if it ends early.
+fn copy(file: &mut File, mut len: u64, out: &mut impl Write, mut observe: impl FnMut(&[u8])) -> Result<()> {
+    let mut buf = vec![0u8; CHUNK_LEN];
+    while len > 0 {
+        let want = CHUNK_LEN.min(len as usize);
+        let n = file.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(anyhow!("file ended {} bytes early", len));
+        }
+        observe(&buf[..n]);
+        out.write_all(&buf[..n]).map_err(|e| anyhow!("sending: {:?}", e))?;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+fn send_file(request: ApiRequest<'_, '_>, name: &str) -> HandlerResult {
+    let found = recorder::with_store(|store| Ok(store.find(name).map(|r| (r.clone(), store.path(r)))))?;
+    let Some((record, path)) = found else {
+        return reply_error(request, 404, "no such file");
+    };
+    let etag = transfer::etag(record.id, record.size, record.taken_unix_ms);
+    if request.header("If-None-Match").is_some_and(|h| transfer::etag_matches(h, &etag)) {
+        request.into_response(304, None, &[("ETag", &etag)])?;
+        return Ok(());
+    }
+    let mut range = RangeRequest::parse(request.header("Range"), record.size);
+    if request.header("If-Range").is_some_and(|h| !transfer::if_range_matches(h, &etag)) {
+        range = RangeRequest::Full;
+    }
+
+    let (status, start, len) = match range {
+        RangeRequest::Full => (200, 0, record.size),
+        RangeRequest::Partial { start, end } => (206, start, end - start + 1),
+        RangeRequest::Unsatisfiable => {
+            let content_range = format!("bytes */{}", record.size);
+            request.into_response(416, None, &[("Content-Range", &content_range)])?;
+            return Ok(());
+        },
+    };
+    let mut file = File::open(&path)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let _busy = power::busy();
+    let content_length = len.to_string();
+    let content_range = format!("bytes {}-{}/{}", start, start + len.saturating_sub(1), record.size);
+    let disposition = format!("inline; filename=\"{}\"", record.file_name());
+    let mut headers = vec![
+        ("Content-Type", "image/jpeg"),
+        ("Content-Length", content_length.as_str()),
+        ("ETag", etag.as_str()),
+        ("Accept-Ranges", "bytes"),
+        ("Content-Disposition", disposition.as_str()),
+    ];
+    if status == 206 {
+        headers.push(("Content-Range", content_range.as_str()));
+    }
+    let mut response = request.into_response(status, None, &headers)?;
+    copy(&mut file, len, &mut response, |_| {})?;
+    Ok(())
+}
+
+fn send_archive(request: ApiRequest<'_, '_>, spec: &str) -> HandlerResult {
+    let parsed = spec.rsplit_once('.').and_then(|(event, extension)| {
+        Some((event.parse::<u64>().ok()?, ArchiveFormat::from_extension(extension)?))
+    });
+    let Some((event_id, format)) = parsed else {
+        return reply_error(request, 404, "expected events/<id>.zip or events/<id>.tar");
+    };
+    let filter = Filter { event_id: Some(event_id), ..Default::default() };
+    let files: Vec<(Record, std::path::PathBuf)> =
+        recorder::with_store(|store| Ok(store.select(&filter).map(|r| (r.clone(), store.path(r))).collect()))?;
+    if files.is_empty() {
+        return reply_error(request, 404, "no such event");
+    }
+
+    let entries: Vec<ArchiveEntry> = files
+        .iter()
+        .map(|(record, _)| ArchiveEntry {
+            name: record.file_name(),
+            size: record.size,
+            modified_unix: record.taken_unix_ms / 1000,
+        })
+        .collect();
+    let _busy = power::busy();
+    let content_length = ArchiveWriter::content_length(format, &entries).to_string();
+    let disposition = format!("attachment; filename=\"event-{}.{}\"", event_id, spec.rsplit('.').next().unwrap_or("zip"));
+    let mut response = request.into_response(
+        200,
+        None,
+        &[
+            ("Content-Type", format.content_type()),
+            ("Content-Length", &content_length),
+            ("Content-Disposition", &disposition),
+        ],
+    )?;
+
+    // once the headers are out, a file that went missing can only cut the response short
+    let mut writer = ArchiveWriter::new(format);
+    for ((_, path), entry) in files.iter().zip(&entries) {
+        let mut file = File::open(path)?;
+        response.write_all(&writer.begin(entry))?;
+        copy(&mut file, entry.size, &mut response, |chunk| writer.data(chunk))?;
+        response.write_all(&writer.end())?;
+    }
+    response.write_all(&writer.finish())?;
+    Ok(())
+}
+
+pub fn register_handlers(server: &mut EspHttpServer) -> Result<()> {
+    server.fn_handler("/api/files", Method::Get, |request| {
+        let filter = match filter(request.uri()) {
+            Ok(f) => f,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        let listing = recorder::with_store(|store| Ok(store.select(&filter).map(Listing::from).collect::<Vec<_>>()));
+        match listing {
+            Ok(files) => reply_json(request, 200, &serde_json::json!({ "files": files, "stats": recorder::stats() })),
+            Err(e) => reply_error(request, 503, &e.to_string()),
+        }
+    })?;
+
+    // a filter is required, so a bare DELETE does not wipe everything
+    server.fn_handler("/api/files", Method::Delete, |request| {
+        let filter = match filter(request.uri()) {
+            Ok(f) if f.is_empty() => return reply_error(request, 400, "give from, to or event"),
+            Ok(f) => f,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        match recorder::with_store(|store| store.remove_matching(&filter)) {
+            Ok(deleted) => reply_json(request, 200, &serde_json::json!({ "deleted": deleted })),
+            Err(e) => reply_error(request, 500, &e.to_string()),
+        }
+    })?;
+
+    server.fn_handler("/api/files/*", Method::Get, |request| {
+        let tail = tail(request.uri()).to_owned();
+        let result = match tail.strip_prefix(EVENTS) {
+            Some(spec) => send_archive(request, spec),
+            None => send_file(request, &tail),
+        };
+        if let Err(e) = &result {
+            error!("files: {}: {:?}", tail, e);
+        }
+        result
+    })?;
+
+    server.fn_handler("/api/files/*", Method::Delete, |request| {
+        let tail = tail(request.uri()).to_owned();
+        let result = recorder::with_store(|store| {
+            let Some(id) = store.find(&tail).map(|r| r.id) else {
+                return Ok(false);
+            };
+            store.remove(id)?;
+            Ok(true)
+        });
+        match result {
+            Ok(true) => reply_json(request, 200, &serde_json::json!({ "deleted": 1 })),
+            Ok(false) => reply_error(request, 404, "no such file"),
+            Err(e) => reply_error(request, 500, &e.to_string()),
+        }
+    })?;
+
+    Ok(())
+}
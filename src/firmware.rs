@@ -0,0 +1,582 @@
+// Checks on firmware images before they are allowed into an OTA slot.
+//
+// An ESP32 app image starts with a 24 byte image header and an 8 byte segment
+// header, followed by the `esp_app_desc_t` the build embeds; only that fixed
+// layout is read. The update manifests a server offers, and the versions they
+// are compared by, live here too, away from the ESP-IDF calls in `ota`.
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const IMAGE_MAGIC: u8 = 0xE9;
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+const APP_DESC_OFFSET: usize = 0x20;
+const VERSION_OFFSET: usize = APP_DESC_OFFSET + 16;
+const PROJECT_NAME_OFFSET: usize = APP_DESC_OFFSET + 48;
+const IDF_VERSION_OFFSET: usize = APP_DESC_OFFSET + 112;
+/// Bytes needed before `AppInfo::parse` can say anything.
+pub const HEAD_LEN: usize = IDF_VERSION_OFFSET + 32;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum FirmwareError {
+    #[error("not an ESP32 app image")]
+    NotAnImage,
+    #[error("the image has no app description")]
+    NoAppDesc,
+    #[error("image is {0} bytes, more than the {1} byte slot")]
+    TooLarge(u64, u64),
+    #[error("image ends after {0} of {1} bytes")]
+    Truncated(u64, u64),
+    #[error("SHA-256 mismatch, got {0}")]
+    DigestMismatch(String),
+    #[error("invalid SHA-256, expected 64 hex digits")]
+    BadDigest,
+}
+
+/// What the image says about itself.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct AppInfo {
+    pub version: String,
+    pub project_name: String,
+    pub idf_version: String,
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+impl AppInfo {
+    /// Reads the app description from the first `HEAD_LEN` bytes of an image.
+    pub fn parse(head: &[u8]) -> Result<Self, FirmwareError> {
+        if head.first() != Some(&IMAGE_MAGIC) {
+            return Err(FirmwareError::NotAnImage);
+        }
+        if head.len() < HEAD_LEN {
+            return Err(FirmwareError::NoAppDesc);
+        }
+        let magic = u32::from_le_bytes(head[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].try_into().unwrap());
+        if magic != APP_DESC_MAGIC {
+            return Err(FirmwareError::NoAppDesc);
+        }
+        Ok(Self {
+            version: c_string(&head[VERSION_OFFSET..VERSION_OFFSET + 32]),
+            project_name: c_string(&head[PROJECT_NAME_OFFSET..PROJECT_NAME_OFFSET + 32]),
+            idf_version: c_string(&head[IDF_VERSION_OFFSET..IDF_VERSION_OFFSET + 32]),
+        })
+    }
+}
+
+pub fn parse_sha256(hex_digest: &str) -> Result<[u8; 32], FirmwareError> {
+    let mut digest = [0u8; 32];
+    hex::decode_to_slice(hex_digest.trim(), &mut digest).map_err(|_| FirmwareError::BadDigest)?;
+    Ok(digest)
+}
+
+/// Follows an image as it streams by: size limits, the app description and the digest.
+pub struct ImageCheck {
+    hasher: Sha256,
+    head: Vec<u8>,
+    written: u64,
+    expected_len: Option<u64>,
+    max_len: u64,
+    info: Option<AppInfo>,
+}
+
+impl ImageCheck {
+    pub fn new(expected_len: Option<u64>, max_len: u64) -> Result<Self, FirmwareError> {
+        if let Some(len) = expected_len.filter(|&len| len > max_len) {
+            return Err(FirmwareError::TooLarge(len, max_len));
+        }
+        Ok(Self { hasher: Sha256::new(), head: Vec::with_capacity(HEAD_LEN), written: 0, expected_len, max_len, info: None })
+    }
+
+    /// Checks the next piece of the image before it is written anywhere.
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), FirmwareError> {
+        self.written += chunk.len() as u64;
+        if self.written > self.expected_len.unwrap_or(self.max_len) {
+            return Err(FirmwareError::TooLarge(self.written, self.expected_len.unwrap_or(self.max_len)));
+        }
+        if self.info.is_none() {
+            let wanted = (HEAD_LEN - self.head.len()).min(chunk.len());
+            self.head.extend_from_slice(&chunk[..wanted]);
+            if self.head.first().is_some_and(|&b| b != IMAGE_MAGIC) {
+                return Err(FirmwareError::NotAnImage);
+            }
+            if self.head.len() == HEAD_LEN {
+                self.info = Some(AppInfo::parse(&self.head)?);
+            }
+        }
+        self.hasher.update(chunk);
+        Ok(())
+    }
+
+    pub fn expected_len(&self) -> Option<u64> {
+        self.expected_len
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Checks that the whole image arrived and matches `expected`.
+    pub fn finish(self, expected: &[u8; 32]) -> Result<AppInfo, FirmwareError> {
+        if let Some(len) = self.expected_len.filter(|&len| len != self.written) {
+            return Err(FirmwareError::Truncated(self.written, len));
+        }
+        let info = self.info.ok_or(FirmwareError::NoAppDesc)?;
+        let digest: [u8; 32] = self.hasher.finalize().into();
+        if &digest != expected {
+            return Err(FirmwareError::DigestMismatch(hex::encode(digest)));
+        }
+        Ok(info)
+    }
+}
+
+/// One dot-separated part of a pre-release; numbers sort before words.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Identifier {
+    Numeric(u64),
+    Alphanumeric(String),
+}
+
+/// A `major.minor.patch` version with an optional `-pre` part, as in `Cargo.toml`.
+///
+/// The derived order is semver precedence: `rc.2 < rc.10 < rc.beta`, and a
+/// shorter pre-release sorts before a longer one it starts.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    numbers: [u64; 3],
+    /// `Some` for releases, which sort after their own pre-releases.
+    release: Option<()>,
+    pre: Vec<Identifier>,
+}
+
+impl Version {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim().trim_start_matches('v');
+        let text = text.split_once('+').map_or(text, |(version, _build)| version);
+        let (core, pre) = text.split_once('-').map_or((text, None), |(core, pre)| (core, Some(pre)));
+        let mut numbers = [0u64; 3];
+        let mut parts = core.split('.');
+        for number in numbers.iter_mut() {
+            *number = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        let pre = match pre {
+            Some(pre) => pre.split('.').map(Self::identifier).collect::<Option<_>>()?,
+            None => Vec::new(),
+        };
+        Some(Self { numbers, release: pre.is_empty().then_some(()), pre })
+    }
+
+    fn identifier(part: &str) -> Option<Identifier> {
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            return None;
+        }
+        if part.bytes().all(|b| b.is_ascii_digit()) {
+            part.parse().ok().map(Identifier::Numeric)
+        } else {
+            Some(Identifier::Alphanumeric(part.to_owned()))
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ManifestError {
+    #[error("manifest version {0:?} is not a version")]
+    BadVersion(String),
+    #[error("invalid public key")]
+    BadKey,
+    #[error("invalid signature encoding")]
+    BadSignature,
+    #[error("signature does not match the manifest")]
+    Forged,
+    #[error("invalid manifest: {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Firmware(#[from] FirmwareError),
+}
+
+/// What the update server offers, signed with the fleet's Ed25519 key.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    /// Hex SHA-256 of the image.
+    pub sha256: String,
+    /// Hex Ed25519 signature of `signed_message`.
+    pub signature: String,
+}
+
+impl Manifest {
+    /// `version` and `sha256` on two lines; the URL is left out so images can move.
+    pub fn signed_message(&self) -> String {
+        format!("{}\n{}", self.version, self.sha256.trim().to_ascii_lowercase())
+    }
+
+    /// Checks the signature against `public_key` and returns the image digest.
+    pub fn verify(&self, public_key: &str) -> Result<[u8; 32], ManifestError> {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(public_key.trim(), &mut key).map_err(|_| ManifestError::BadKey)?;
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|_| ManifestError::BadKey)?;
+        let mut signature = [0u8; 64];
+        hex::decode_to_slice(self.signature.trim(), &mut signature).map_err(|_| ManifestError::BadSignature)?;
+        let signature = ed25519_dalek::Signature::from_bytes(&signature);
+        key.verify_strict(self.signed_message().as_bytes(), &signature)
+            .map_err(|_| ManifestError::Forged)?;
+        Ok(parse_sha256(&self.sha256)?)
+    }
+
+    pub fn parsed_version(&self) -> Result<Version, ManifestError> {
+        Version::parse(&self.version).ok_or_else(|| ManifestError::BadVersion(self.version.clone()))
+    }
+}
+
+/// Reads what the update server returned: the manifest and the digest its
+/// image must have if it is correctly signed and newer than `running`.
+pub fn evaluate_manifest(body: &[u8], public_key: &str, running: &Version) -> Result<Option<(Manifest, [u8; 32])>, ManifestError> {
+    let manifest: Manifest = serde_json::from_slice(body).map_err(|e| ManifestError::Malformed(e.to_string()))?;
+    let digest = manifest.verify(public_key)?;
+    if manifest.parsed_version()? <= *running {
+        return Ok(None);
+    }
+    Ok(Some((manifest, digest)))
+}
+
+/// Daily stretch of local time in which updates may be installed, may span midnight.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct MaintenanceWindow {
+    /// Minutes after local midnight.
+    pub start_minute: u16,
+    pub end_minute: u16,
+}
+
+impl MaintenanceWindow {
+    pub fn is_valid(&self) -> bool {
+        self.start_minute < 24 * 60 && self.end_minute < 24 * 60 && self.start_minute != self.end_minute
+    }
+
+    pub fn contains(&self, minute_of_day: u16) -> bool {
+        if self.start_minute < self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute_of_day)
+        } else {
+            minute_of_day >= self.start_minute || minute_of_day < self.end_minute
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use ed25519_dalek::{Signer, SigningKey};
+
+    use super::*;
+
+    const IMAGE_LEN: usize = 3000;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn public_key() -> String {
+        hex::encode(key().verifying_key().as_bytes())
+    }
+
+    fn signed(version: &str) -> Manifest {
+        let mut manifest = Manifest {
+            version: version.to_owned(),
+            url: "http://updates.local/camera.bin".to_owned(),
+            sha256: hex::encode(Sha256::digest(image(version, IMAGE_LEN))),
+            signature: String::new(),
+        };
+        manifest.signature = hex::encode(key().sign(manifest.signed_message().as_bytes()).to_bytes());
+        manifest
+    }
+
+    fn version(text: &str) -> Version {
+        Version::parse(text).unwrap_or_else(|| panic!("{:?} should parse", text))
+    }
+
+    /// An app image as the build lays it out, `len` bytes long.
+    fn image(version: &str, len: usize) -> Vec<u8> {
+        let mut image = vec![0u8; len.max(HEAD_LEN)];
+        image[0] = IMAGE_MAGIC;
+        image[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        image[VERSION_OFFSET..VERSION_OFFSET + version.len()].copy_from_slice(version.as_bytes());
+        image[PROJECT_NAME_OFFSET..PROJECT_NAME_OFFSET + 11].copy_from_slice(b"ttgo-camera");
+        image[IDF_VERSION_OFFSET..IDF_VERSION_OFFSET + 6].copy_from_slice(b"v5.1.1");
+        for (i, byte) in image.iter_mut().enumerate().skip(HEAD_LEN) {
+            *byte = i as u8;
+        }
+        image
+    }
+
+    /// Runs `image` through a check in pieces of `chunk` bytes.
+    fn checked(image: &[u8], declared: Option<u64>, max_len: u64, chunk: usize) -> Result<ImageCheck, FirmwareError> {
+        let mut check = ImageCheck::new(declared, max_len)?;
+        for piece in image.chunks(chunk) {
+            check.update(piece)?;
+        }
+        Ok(check)
+    }
+
+    fn digest(bytes: &[u8]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    #[test]
+    fn accepts_an_image_with_the_expected_digest() {
+        let image = image("1.2.0", 5000);
+        let check = checked(&image, Some(5000), 8192, 1024).unwrap();
+        assert_eq!(check.written(), 5000);
+        let info = check.finish(&digest(&image)).unwrap();
+        assert_eq!(info, AppInfo { version: "1.2.0".to_owned(), project_name: "ttgo-camera".to_owned(), idf_version: "v5.1.1".to_owned() });
+        assert_eq!(parse_sha256(&hex::encode(digest(&image)).to_uppercase()), Ok(digest(&image)));
+        assert_eq!(parse_sha256("abc"), Err(FirmwareError::BadDigest));
+    }
+
+    #[test]
+    fn reads_a_header_split_across_chunks() {
+        let image = image("1.2.0", 600);
+        for chunk in [1, 7, 31, HEAD_LEN - 1, HEAD_LEN, HEAD_LEN + 1] {
+            let info = checked(&image, Some(600), 8192, chunk).unwrap().finish(&digest(&image));
+            assert_eq!(info.map(|i| i.version), Ok("1.2.0".to_owned()), "chunks of {}", chunk);
+        }
+    }
+
+    #[test]
+    fn rejects_a_digest_mismatch() {
+        let image = image("1.2.0", 5000);
+        let mut tampered = image.clone();
+        tampered[4000] ^= 1;
+        let check = checked(&tampered, Some(5000), 8192, 1024).unwrap();
+        assert_eq!(check.finish(&digest(&image)), Err(FirmwareError::DigestMismatch(hex::encode(digest(&tampered)))));
+    }
+
+    #[test]
+    fn rejects_an_image_that_ends_early() {
+        let image = image("1.2.0", 5000);
+        let check = checked(&image[..4000], Some(5000), 8192, 1024).unwrap();
+        assert_eq!(check.finish(&digest(&image)).unwrap_err(), FirmwareError::Truncated(4000, 5000));
+    }
+
+    #[test]
+    fn rejects_an_image_too_large_for_the_slot() {
+        let image = image("1.2.0", 5000);
+        // declared up front
+        assert_eq!(ImageCheck::new(Some(5000), 4096).err(), Some(FirmwareError::TooLarge(5000, 4096)));
+        // no length given, the bytes keep coming
+        assert_eq!(checked(&image, None, 4096, 1024).err(), Some(FirmwareError::TooLarge(5000, 4096)));
+        // more bytes than were declared
+        assert_eq!(checked(&image, Some(3000), 4096, 1024).err(), Some(FirmwareError::TooLarge(3072, 3000)));
+    }
+
+    #[test]
+    fn rejects_what_is_not_an_app_image() {
+        let mut elf = image("1.2.0", 600);
+        elf[..4].copy_from_slice(b"\x7fELF");
+        assert_eq!(checked(&elf, Some(600), 8192, 1).err(), Some(FirmwareError::NotAnImage));
+        assert_eq!(AppInfo::parse(&elf), Err(FirmwareError::NotAnImage));
+
+        let mut no_desc = image("1.2.0", 600);
+        no_desc[APP_DESC_OFFSET] ^= 0xff;
+        assert_eq!(checked(&no_desc, Some(600), 8192, 64).err(), Some(FirmwareError::NoAppDesc));
+
+        // an image that ends before the app description could be read
+        let short = &image("1.2.0", 600)[..HEAD_LEN - 1];
+        assert_eq!(AppInfo::parse(short), Err(FirmwareError::NoAppDesc));
+        let check = checked(short, None, 8192, 16).unwrap();
+        assert_eq!(check.finish(&digest(short)).unwrap_err(), FirmwareError::NoAppDesc);
+    }
+
+    #[test]
+    fn versions_follow_semver_precedence() {
+        let ascending = [
+            "0.9.12",
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0-rc.2",
+            "1.0.0-rc.10",
+            "1.0.0",
+            "1.0.1",
+            "1.10.0",
+        ];
+        for pair in ascending.windows(2) {
+            assert!(version(pair[0]) < version(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+        assert_eq!(version("v1.2.3+build.5"), version("1.2.3"));
+        assert_eq!(version(" 1.2.3-rc.1 "), version("1.2.3-rc.1"));
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_version() {
+        for text in ["", "1.2", "1.2.3.4", "1.2.x", "1.2.3-", "1.2.3-rc..1", "1.2.3-rc_1"] {
+            assert_eq!(Version::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn accepts_a_correctly_signed_manifest() {
+        let manifest = signed("1.2.0");
+        assert_eq!(manifest.verify(&public_key()), Ok(digest(&image("1.2.0", IMAGE_LEN))));
+    }
+
+    #[test]
+    fn rejects_a_tampered_or_foreign_manifest() {
+        let mut tampered = signed("1.2.0");
+        tampered.version = "9.9.9".to_owned();
+        assert_eq!(tampered.verify(&public_key()), Err(ManifestError::Forged));
+
+        let mut other_image = signed("1.2.0");
+        other_image.sha256 = hex::encode(digest(&image("1.1.0", IMAGE_LEN)));
+        assert_eq!(other_image.verify(&public_key()), Err(ManifestError::Forged));
+
+        let foreign = hex::encode(SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes());
+        assert_eq!(signed("1.2.0").verify(&foreign), Err(ManifestError::Forged));
+
+        assert_eq!(signed("1.2.0").verify("abcd"), Err(ManifestError::BadKey));
+        let mut garbled = signed("1.2.0");
+        garbled.signature.truncate(10);
+        assert_eq!(garbled.verify(&public_key()), Err(ManifestError::BadSignature));
+
+        // the URL is not signed, so images can move
+        let mut moved = signed("1.2.0");
+        moved.url = "https://mirror.example/camera.bin".to_owned();
+        assert!(moved.verify(&public_key()).is_ok());
+    }
+
+    #[test]
+    fn windows_may_span_midnight() {
+        let night = MaintenanceWindow { start_minute: 22 * 60, end_minute: 2 * 60 };
+        assert!(night.is_valid());
+        for minute in [22 * 60, 23 * 60 + 59, 0, 2 * 60 - 1] {
+            assert!(night.contains(minute), "{}", minute);
+        }
+        for minute in [2 * 60, 12 * 60, 22 * 60 - 1] {
+            assert!(!night.contains(minute), "{}", minute);
+        }
+
+        let lunch = MaintenanceWindow { start_minute: 12 * 60, end_minute: 13 * 60 };
+        assert!(lunch.contains(12 * 60) && !lunch.contains(13 * 60) && !lunch.contains(0));
+
+        assert!(!MaintenanceWindow { start_minute: 60, end_minute: 60 }.is_valid());
+        assert!(!MaintenanceWindow { start_minute: 0, end_minute: 24 * 60 }.is_valid());
+    }
+
+    /// Answers every GET with the body `routes` has for its path, or a 404.
+    fn serve(listener: TcpListener, routes: Vec<(String, Vec<u8>)>) {
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap();
+                let (status, body) = match routes.iter().find(|(p, _)| p == path) {
+                    Some((_, body)) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", b"".as_slice()),
+                };
+                let mut stream = reader.into_inner();
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, body.len()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+    }
+
+    /// Sends a GET for `url` and returns the Content-Length with the unread body.
+    fn get(url: &str) -> (Option<u64>, BufReader<TcpStream>) {
+        let (host, path) = url.strip_prefix("http://").unwrap().split_once('/').unwrap();
+        let mut stream = TcpStream::connect(host).unwrap();
+        write!(stream, "GET /{} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert!(status.starts_with("HTTP/1.1 200"), "{}: {}", url, status.trim_end());
+        let mut len = None;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.trim_end().split_once(':') else {
+                break;
+            };
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = value.trim().parse().ok();
+            }
+        }
+        (len, reader)
+    }
+
+    /// Fetches and checks an image the way `ota::download` does, a small read at a time.
+    fn download(manifest: &Manifest, digest: &[u8; 32]) -> Result<AppInfo, FirmwareError> {
+        let (len, mut body) = get(&manifest.url);
+        let mut check = ImageCheck::new(len, 64 * 1024)?;
+        let mut buf = [0u8; 100];
+        loop {
+            let n = body.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            check.update(&buf[..n])?;
+        }
+        check.finish(digest)
+    }
+
+    #[test]
+    fn downloads_and_verifies_an_update_from_the_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let image = image("1.2.0-rc.10", IMAGE_LEN);
+        let mut tampered = image.clone();
+        tampered[IMAGE_LEN - 1] ^= 1;
+        let mut manifest = signed("1.2.0-rc.10");
+        manifest.url = format!("{}/camera.bin", base);
+        serve(listener, vec![
+            ("/manifest.json".to_owned(), serde_json::to_vec(&manifest).unwrap()),
+            ("/camera.bin".to_owned(), image),
+            ("/tampered.bin".to_owned(), tampered.clone()),
+        ]);
+
+        let (_, mut body) = get(&format!("{}/manifest.json", base));
+        let mut manifest_json = Vec::new();
+        body.read_to_end(&mut manifest_json).unwrap();
+        let (offered, digest) = evaluate_manifest(&manifest_json, &public_key(), &version("1.2.0-rc.2")).unwrap().unwrap();
+        assert_eq!(offered, manifest);
+        assert_eq!(download(&offered, &digest).map(|info| info.version), Ok("1.2.0-rc.10".to_owned()));
+
+        // the URL is not signed, but whatever it points to still has to match the digest
+        let moved = Manifest { url: format!("{}/tampered.bin", base), ..offered };
+        assert_eq!(download(&moved, &digest), Err(FirmwareError::DigestMismatch(hex::encode(Sha256::digest(&tampered)))));
+    }
+
+    #[test]
+    fn evaluates_manifests() {
+        // nothing to do when the server is behind or level
+        let running = version("1.2.0-rc.10");
+        for offered in ["1.2.0-rc.2", "1.2.0-rc.10", "1.0.5"] {
+            let body = serde_json::to_vec(&signed(offered)).unwrap();
+            assert_eq!(evaluate_manifest(&body, &public_key(), &running), Ok(None), "{}", offered);
+        }
+
+        let mut forged = signed("1.2.0");
+        forged.version = "2.0.0".to_owned();
+        let body = serde_json::to_vec(&forged).unwrap();
+        assert_eq!(evaluate_manifest(&body, &public_key(), &running), Err(ManifestError::Forged));
+
+        let body = b"<html>captive portal</html>";
+        assert!(matches!(evaluate_manifest(body, &public_key(), &running), Err(ManifestError::Malformed(_))));
+    }
+}
@@ -0,0 +1,656 @@
+// Topics and Home Assistant MQTT discovery documents.
+//
+// Everything the camera publishes lives under one base topic; the discovery
+// documents tell Home Assistant which of those topics make up which entity.
+// What goes to the broker on connect is sent through the `Broker` trait, which
+// the esp-mqtt session implements and the tests implement over plain TCP.
+
+use serde_json::{json, Value};
+
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+pub const ON: &str = "ON";
+pub const OFF: &str = "OFF";
+
+/// What Home Assistant shows on the device page.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub model: String,
+    pub sw_version: String,
+    /// Link to the web UI.
+    pub configuration_url: Option<String>,
+}
+
+/// The state topics below one base, e.g. `ttgo-camera/a1b2c3`.
+#[derive(Clone, Debug)]
+pub struct Topics {
+    base: String,
+}
+
+impl Topics {
+    pub fn new(base: &str) -> Self {
+        Self { base: base.trim_end_matches('/').to_owned() }
+    }
+
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn topic(&self, leaf: &str) -> String {
+        format!("{}/{}", self.base, leaf)
+    }
+
+    /// `online`/`offline`, the last will resets it to `offline`.
+    pub fn availability(&self) -> String {
+        self.topic("status")
+    }
+
+    /// `ON` while the PIR sees motion.
+    pub fn motion(&self) -> String {
+        self.topic("motion")
+    }
+
+    /// `{"event_type": "press"}` for every gesture, not retained.
+    pub fn button(&self) -> String {
+        self.topic("button")
+    }
+
+    /// JSON object, see `Diagnostics` in `mqtt`.
+    pub fn diagnostics(&self) -> String {
+        self.topic("diagnostics")
+    }
+
+    /// `ON` while motion triggers captures.
+    pub fn armed(&self) -> String {
+        self.topic("armed")
+    }
+
+    /// Where the command `name` is sent, see `commands`.
+    pub fn command(&self, name: &str) -> String {
+        format!("{}/cmd/{}", self.base, name)
+    }
+
+    /// Subscription covering every command topic.
+    pub fn commands(&self) -> String {
+        self.command("+")
+    }
+
+    /// The command name if `topic` is a command topic.
+    pub fn command_name<'t>(&self, topic: &'t str) -> Option<&'t str> {
+        topic.strip_prefix(self.base.as_str())?.strip_prefix("/cmd/").filter(|name| !name.is_empty() && !name.contains('/'))
+    }
+
+    /// Default topic for command acknowledgements.
+    pub fn acks(&self) -> String {
+        self.topic("ack")
+    }
+}
+
+/// One discovery document: `(config topic, payload)`.
+pub type Announcement = (String, String);
+
+/// Home Assistant only allows `[a-zA-Z0-9_-]` in node and object ids.
+pub fn sanitize_id(id: &str) -> String {
+    id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+struct Entity {
+    component: &'static str,
+    object_id: &'static str,
+    name: &'static str,
+    config: Value,
+}
+
+fn config_topic(prefix: &str, component: &str, node_id: &str, object_id: &str) -> String {
+    format!("{}/{}/{}/{}/config", prefix, component, node_id, object_id)
+}
+
+fn camera_entity(topic: &str) -> Entity {
+    Entity {
+        component: "camera",
+        object_id: "camera",
+        name: "Camera",
+        config: json!({ "topic": topic }),
+    }
+}
+
+fn entities(topics: &Topics, camera_topic: Option<&str>) -> Vec<Entity> {
+    let diagnostic = |object_id, name, template: &str, extra: Value| {
+        let mut config = json!({
+            "state_topic": topics.diagnostics(),
+            "value_template": template,
+            "entity_category": "diagnostic",
+        });
+        merge(&mut config, extra);
+        Entity { component: "sensor", object_id, name, config }
+    };
+
+    let mut entities = vec![
+        Entity {
+            component: "binary_sensor",
+            object_id: "motion",
+            name: "Motion",
+            config: json!({
+                "state_topic": topics.motion(),
+                "device_class": "motion",
+                "payload_on": ON,
+                "payload_off": OFF,
+            }),
+        },
+        Entity {
+            component: "event",
+            object_id: "button",
+            name: "Button",
+            config: json!({
+                "state_topic": topics.button(),
+                "device_class": "button",
+                "event_types": ["press", "long_press"],
+            }),
+        },
+        Entity {
+            component: "switch",
+            object_id: "armed",
+            name: "Armed",
+            config: json!({
+                "state_topic": topics.armed(),
+                "command_topic": topics.command("arm"),
+                "payload_on": r#"{"armed":true}"#,
+                "payload_off": r#"{"armed":false}"#,
+                "state_on": ON,
+                "state_off": OFF,
+                "icon": "mdi:shield-lock",
+            }),
+        },
+        Entity {
+            component: "button",
+            object_id: "snapshot",
+            name: "Snapshot",
+            config: json!({
+                "command_topic": topics.command("snapshot"),
+                "payload_press": "{}",
+                "icon": "mdi:camera",
+            }),
+        },
+        Entity {
+            component: "button",
+            object_id: "reboot",
+            name: "Reboot",
+            config: json!({
+                "command_topic": topics.command("reboot"),
+                "payload_press": "{}",
+                "device_class": "restart",
+                "entity_category": "config",
+            }),
+        },
+        Entity {
+            // reads the availability topic itself, so it goes off with the last will
+            component: "binary_sensor",
+            object_id: "connectivity",
+            name: "Connectivity",
+            config: json!({
+                "state_topic": topics.availability(),
+                "device_class": "connectivity",
+                "entity_category": "diagnostic",
+                "payload_on": ONLINE,
+                "payload_off": OFFLINE,
+            }),
+        },
+        diagnostic("rssi", "Wi-Fi signal", "{{ value_json.rssi }}", json!({
+            "device_class": "signal_strength",
+            "unit_of_measurement": "dBm",
+            "state_class": "measurement",
+        })),
+        diagnostic("ip", "IP address", "{{ value_json.ip }}", json!({})),
+        diagnostic("uptime", "Uptime", "{{ value_json.uptime_secs }}", json!({
+            "device_class": "duration",
+            "unit_of_measurement": "s",
+            "state_class": "total_increasing",
+        })),
+        diagnostic("free_heap", "Free heap", "{{ value_json.free_heap }}", json!({
+            "device_class": "data_size",
+            "unit_of_measurement": "B",
+            "state_class": "measurement",
+        })),
+        diagnostic("time_status", "Clock", "{{ value_json.time_status }}", json!({})),
+        diagnostic("outbox", "Outbox", "{{ value_json.outbox_depth }}", json!({
+            "state_class": "measurement",
+        })),
+    ];
+    entities.extend(camera_topic.map(camera_entity));
+    entities
+}
+
+fn merge(into: &mut Value, extra: Value) {
+    if let (Value::Object(into), Value::Object(extra)) = (into, extra) {
+        into.extend(extra);
+    }
+}
+
+/// Retained discovery documents for every entity of the camera.
+///
+/// Without a `camera_topic` the camera entity is retracted instead, in case an
+/// earlier configuration announced it.
+pub fn announcements(prefix: &str, device: &DeviceInfo, topics: &Topics, camera_topic: Option<&str>) -> Vec<Announcement> {
+    let node_id = sanitize_id(&device.id);
+    let mut device_json = json!({
+        "identifiers": [device.id],
+        "name": device.name,
+        "manufacturer": "LilyGO",
+        "model": device.model,
+        "sw_version": device.sw_version,
+    });
+    if let Some(url) = &device.configuration_url {
+        merge(&mut device_json, json!({ "configuration_url": url }));
+    }
+
+    let mut announcements: Vec<Announcement> = entities(topics, camera_topic)
+        .into_iter()
+        .map(|entity| {
+            let mut config = json!({
+                "name": entity.name,
+                "unique_id": format!("{}_{}", node_id, entity.object_id),
+                "object_id": format!("{}_{}", node_id, entity.object_id),
+                "availability_topic": topics.availability(),
+                "payload_available": ONLINE,
+                "payload_not_available": OFFLINE,
+                "device": device_json,
+            });
+            merge(&mut config, entity.config);
+            (config_topic(prefix, entity.component, &node_id, entity.object_id), config.to_string())
+        })
+        .collect();
+    if camera_topic.is_none() {
+        let camera = camera_entity("");
+        announcements.push((config_topic(prefix, camera.component, &node_id, camera.object_id), String::new()));
+    }
+    announcements
+}
+
+/// Empty retained payloads that make Home Assistant forget the entities.
+pub fn retractions(prefix: &str, device_id: &str, topics: &Topics) -> Vec<Announcement> {
+    let node_id = sanitize_id(device_id);
+    entities(topics, Some(""))
+        .into_iter()
+        .map(|entity| (config_topic(prefix, entity.component, &node_id, entity.object_id), String::new()))
+        .collect()
+}
+
+/// The two things `announce` needs from an MQTT client, both at QoS 1.
+pub trait Broker {
+    type Error;
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<(), Self::Error>;
+    fn subscribe(&mut self, filter: &str) -> Result<(), Self::Error>;
+}
+
+/// The will to register on every connect: `offline`, retained, on the availability topic.
+pub fn last_will(topics: &Topics) -> (String, &'static str) {
+    (topics.availability(), OFFLINE)
+}
+
+/// Where the discovery documents go and what they describe.
+#[derive(Clone, Copy, Debug)]
+pub struct Discovery<'a> {
+    pub prefix: &'a str,
+    pub device: &'a DeviceInfo,
+    pub camera_topic: Option<&'a str>,
+}
+
+/// Retained state that follows the announcement.
+#[derive(Clone, Copy, Debug)]
+pub struct State<'a> {
+    /// Unknown until the PIR first reports.
+    pub motion: Option<bool>,
+    pub armed: bool,
+    pub diagnostics: &'a [u8],
+}
+
+/// Everything a fresh subscriber needs, sent on every (re)connect: the broker
+/// may have restarted without persistence, and the last will has replaced
+/// `online` either way.
+pub fn announce<B: Broker>(broker: &mut B, topics: &Topics, discovery: Option<Discovery<'_>>, state: &State<'_>) -> Result<(), B::Error> {
+    broker.subscribe(&topics.commands())?;
+    broker.publish(&topics.availability(), true, ONLINE.as_bytes())?;
+    if let Some(discovery) = discovery {
+        for (topic, payload) in announcements(discovery.prefix, discovery.device, topics, discovery.camera_topic) {
+            broker.publish(&topic, true, payload.as_bytes())?;
+        }
+    }
+    let on_off = |on: bool| if on { ON } else { OFF }.as_bytes();
+    if let Some(motion) = state.motion {
+        broker.publish(&topics.motion(), true, on_off(motion))?;
+    }
+    broker.publish(&topics.armed(), true, on_off(state.armed))?;
+    broker.publish(&topics.diagnostics(), true, state.diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::{self, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    // Just enough of MQTT 3.1.1 for a broker that keeps retained messages,
+    // records subscriptions and sends the last will when a client drops.
+
+    const CONNECT: u8 = 1;
+    const PUBLISH: u8 = 3;
+    const SUBSCRIBE: u8 = 8;
+    const PINGREQ: u8 = 12;
+    const DISCONNECT: u8 = 14;
+
+    fn read_packet(stream: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first)?;
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        stream.read_exact(&mut body)?;
+        Ok((first[0], body))
+    }
+
+    fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![first];
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            out.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn put_str(out: &mut Vec<u8>, s: &[u8]) {
+        out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+        out.extend_from_slice(s);
+    }
+
+    fn take_str<'a>(body: &mut &'a [u8]) -> &'a [u8] {
+        let len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+        let (s, rest) = body[2..].split_at(len);
+        *body = rest;
+        s
+    }
+
+    #[derive(Default)]
+    struct BrokerState {
+        retained: BTreeMap<String, Vec<u8>>,
+        /// Filters each connection subscribed to, in connect order.
+        subscriptions: Vec<Vec<String>>,
+        wills: usize,
+    }
+
+    type Shared = Arc<Mutex<BrokerState>>;
+
+    fn stand_in_broker() -> (SocketAddr, Shared) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Shared::default();
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = shared.clone();
+                thread::spawn(move || serve(stream.unwrap(), state));
+            }
+        });
+        (addr, state)
+    }
+
+    fn retain(state: &Shared, topic: &str, payload: &[u8]) {
+        let mut state = state.lock().unwrap();
+        if payload.is_empty() {
+            state.retained.remove(topic);
+        } else {
+            state.retained.insert(topic.to_owned(), payload.to_vec());
+        }
+    }
+
+    fn serve(mut stream: TcpStream, state: Shared) -> io::Result<()> {
+        let (first, body) = read_packet(&mut stream)?;
+        assert_eq!(first >> 4, CONNECT);
+        let mut rest = body.as_slice();
+        assert_eq!(take_str(&mut rest), b"MQTT");
+        let (level, flags) = (rest[0], rest[1]);
+        assert_eq!(level, 4);
+        rest = &rest[4..];
+        take_str(&mut rest);
+        let will = (flags & 0x04 != 0).then(|| {
+            let topic = String::from_utf8(take_str(&mut rest).to_vec()).unwrap();
+            (topic, take_str(&mut rest).to_vec(), flags & 0x20 != 0)
+        });
+        stream.write_all(&packet(0x20, &[0, 0]))?;
+
+        let index = {
+            let mut state = state.lock().unwrap();
+            state.subscriptions.push(Vec::new());
+            state.subscriptions.len() - 1
+        };
+        loop {
+            let Ok((first, body)) = read_packet(&mut stream) else {
+                // gone without a DISCONNECT, which is what the will is for
+                if let Some((topic, payload, retained)) = &will {
+                    if *retained {
+                        retain(&state, topic, payload);
+                    }
+                    state.lock().unwrap().wills += 1;
+                }
+                return Ok(());
+            };
+            match first >> 4 {
+                PUBLISH => {
+                    let mut rest = body.as_slice();
+                    let topic = String::from_utf8(take_str(&mut rest).to_vec()).unwrap();
+                    let qos = (first >> 1) & 3;
+                    let id = (qos > 0).then(|| {
+                        let id = [rest[0], rest[1]];
+                        rest = &rest[2..];
+                        id
+                    });
+                    if first & 1 != 0 {
+                        retain(&state, &topic, rest);
+                    }
+                    if let Some(id) = id {
+                        stream.write_all(&packet(0x40, &id))?;
+                    }
+                },
+                SUBSCRIBE => {
+                    let (id, mut rest) = body.split_at(2);
+                    let mut granted = id.to_vec();
+                    while !rest.is_empty() {
+                        let filter = String::from_utf8(take_str(&mut rest).to_vec()).unwrap();
+                        granted.push(rest[0]);
+                        rest = &rest[1..];
+                        state.lock().unwrap().subscriptions[index].push(filter);
+                    }
+                    stream.write_all(&packet(0x90, &granted))?;
+                },
+                PINGREQ => stream.write_all(&packet(0xd0, &[]))?,
+                DISCONNECT => return Ok(()),
+                other => panic!("unexpected packet type {}", other),
+            }
+        }
+    }
+
+    /// A QoS 1 client that waits for every acknowledgement.
+    struct TestClient {
+        stream: TcpStream,
+        next_id: u16,
+    }
+
+    impl TestClient {
+        fn connect(addr: SocketAddr, client_id: &str, will: &(String, &str)) -> io::Result<Self> {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let mut body = Vec::new();
+            put_str(&mut body, b"MQTT");
+            // level 4, clean session, will at QoS 1 and retained, 60s keep alive
+            body.extend_from_slice(&[4, 0x02 | 0x04 | 0x08 | 0x20, 0, 60]);
+            put_str(&mut body, client_id.as_bytes());
+            put_str(&mut body, will.0.as_bytes());
+            put_str(&mut body, will.1.as_bytes());
+            stream.write_all(&packet(0x10, &body))?;
+            let (first, body) = read_packet(&mut stream)?;
+            assert_eq!((first, body.as_slice()), (0x20, [0, 0].as_slice()));
+            Ok(Self { stream, next_id: 1 })
+        }
+
+        fn packet_id(&mut self) -> [u8; 2] {
+            self.next_id += 1;
+            self.next_id.to_be_bytes()
+        }
+
+        fn expect_ack(&mut self, first: u8, id: [u8; 2]) -> io::Result<()> {
+            let (got, body) = read_packet(&mut self.stream)?;
+            assert_eq!((got, &body[..2]), (first, id.as_slice()));
+            Ok(())
+        }
+
+        /// Drops the connection the way a power cut would, without a DISCONNECT.
+        fn vanish(self) {
+            self.stream.shutdown(Shutdown::Both).unwrap();
+        }
+    }
+
+    impl Broker for TestClient {
+        type Error = io::Error;
+
+        fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> io::Result<()> {
+            let id = self.packet_id();
+            let mut body = Vec::new();
+            put_str(&mut body, topic.as_bytes());
+            body.extend_from_slice(&id);
+            body.extend_from_slice(payload);
+            self.stream.write_all(&packet(0x32 | u8::from(retain), &body))?;
+            self.expect_ack(0x40, id)
+        }
+
+        fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+            let id = self.packet_id();
+            let mut body = id.to_vec();
+            put_str(&mut body, filter.as_bytes());
+            body.push(1);
+            self.stream.write_all(&packet(0x82, &body))?;
+            self.expect_ack(0x90, id)
+        }
+    }
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            id: "a1b2c3".to_owned(),
+            name: "ttgo-camera-a1b2c3".to_owned(),
+            model: "ttgo-t-camera-v16".to_owned(),
+            sw_version: "0.1.0".to_owned(),
+            configuration_url: Some("http://192.168.1.20/".to_owned()),
+        }
+    }
+
+    fn retained(state: &Shared, topic: &str) -> Option<String> {
+        state.lock().unwrap().retained.get(topic).map(|p| String::from_utf8_lossy(p).into_owned())
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn announces_on_every_connect_and_leaves_a_will() {
+        let (addr, state) = stand_in_broker();
+        let topics = Topics::new("ttgo-camera/a1b2c3");
+        let device = device();
+        let discovery = Discovery { prefix: "homeassistant", device: &device, camera_topic: Some("ttgo-camera/a1b2c3/camera") };
+        let will = last_will(&topics);
+        assert_eq!(will, ("ttgo-camera/a1b2c3/status".to_owned(), OFFLINE));
+
+        let mut client = TestClient::connect(addr, "ttgo-a1b2c3", &will).unwrap();
+        let status = State { motion: None, armed: true, diagnostics: br#"{"rssi":-60}"# };
+        announce(&mut client, &topics, Some(discovery), &status).unwrap();
+
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/status").as_deref(), Some(ONLINE));
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/armed").as_deref(), Some(ON));
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/diagnostics").as_deref(), Some(r#"{"rssi":-60}"#));
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/motion"), None);
+        let documents = announcements("homeassistant", &device, &topics, discovery.camera_topic);
+        assert_eq!(documents.len(), 13);
+        for (topic, payload) in &documents {
+            let stored = retained(&state, topic).unwrap_or_else(|| panic!("{} was not retained", topic));
+            assert_eq!(&stored, payload);
+            let config: Value = serde_json::from_str(&stored).unwrap();
+            assert_eq!(config["availability_topic"], "ttgo-camera/a1b2c3/status");
+            assert_eq!(config["device"]["identifiers"][0], "a1b2c3");
+        }
+        assert_eq!(state.lock().unwrap().subscriptions[0], ["ttgo-camera/a1b2c3/cmd/+"]);
+
+        // the broker marks the camera offline when it drops off
+        client.vanish();
+        wait_for("the last will", || state.lock().unwrap().wills == 1);
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/status").as_deref(), Some(OFFLINE));
+
+        // and the next session puts everything back, with what changed meanwhile
+        let mut client = TestClient::connect(addr, "ttgo-a1b2c3", &will).unwrap();
+        let status = State { motion: Some(true), armed: false, diagnostics: br#"{"rssi":-71}"# };
+        announce(&mut client, &topics, Some(discovery), &status).unwrap();
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/status").as_deref(), Some(ONLINE));
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/motion").as_deref(), Some(ON));
+        assert_eq!(retained(&state, "ttgo-camera/a1b2c3/armed").as_deref(), Some(OFF));
+        assert_eq!(state.lock().unwrap().subscriptions[1], ["ttgo-camera/a1b2c3/cmd/+"]);
+        assert_eq!(state.lock().unwrap().wills, 1);
+    }
+
+    #[test]
+    fn discovery_off_announces_no_entities() {
+        let (addr, state) = stand_in_broker();
+        let topics = Topics::new("cams/garage/");
+        let mut client = TestClient::connect(addr, "garage", &last_will(&topics)).unwrap();
+        announce(&mut client, &topics, None, &State { motion: Some(false), armed: false, diagnostics: b"{}" }).unwrap();
+
+        let state = state.lock().unwrap();
+        let topics: Vec<&str> = state.retained.keys().map(String::as_str).collect();
+        assert_eq!(topics, ["cams/garage/armed", "cams/garage/diagnostics", "cams/garage/motion", "cams/garage/status"]);
+    }
+
+    #[test]
+    fn announcements_without_a_camera_retract_it() {
+        let topics = Topics::new("ttgo-camera/a1b2c3");
+        let documents = announcements("homeassistant", &device(), &topics, None);
+        let camera = documents.iter().find(|(topic, _)| topic == "homeassistant/camera/a1b2c3/camera/config").unwrap();
+        assert_eq!(camera.1, "");
+
+        let retractions = retractions("homeassistant", "a1b2c3", &topics);
+        assert_eq!(retractions.len(), documents.len());
+        assert!(retractions.iter().all(|(_, payload)| payload.is_empty()));
+    }
+
+    #[test]
+    fn command_topics() {
+        let topics = Topics::new("ttgo-camera/a1b2c3");
+        assert_eq!(topics.commands(), "ttgo-camera/a1b2c3/cmd/+");
+        assert_eq!(topics.command_name("ttgo-camera/a1b2c3/cmd/reboot"), Some("reboot"));
+        assert_eq!(topics.command_name("ttgo-camera/a1b2c3/cmd/"), None);
+        assert_eq!(topics.command_name("ttgo-camera/a1b2c3/cmd/a/b"), None);
+        assert_eq!(topics.command_name("ttgo-camera/other/cmd/reboot"), None);
+        assert_eq!(sanitize_id("a1:b2.c3"), "a1_b2_c3");
+    }
+}
@@ -1,95 +1,303 @@
+// Outbound HTTP(S) client.
+//
+// Request bodies are streamed from memory or any `std::io::Read` with a known
+// length, responses are handed to the caller to read at its own pace. Anything
+// but a 2xx status comes back as `HttpError::Status`.
+
+use crate::mdns::FIRMWARE_VERSION;
 use crate::preludes::*;
-use embedded_svc::http::client::Client as HttpClient;
-use embedded_svc::http::Method;
-use embedded_svc::io::Write;
-use embedded_svc::utils::io;
+use embedded_svc::http::client::{Client as HttpClient, Response as ClientResponse};
+use embedded_svc::http::{Headers, Method, Status};
+use esp_idf_svc::handle::RawHandle;
 use esp_idf_svc::http::client::{
     Configuration as HttpConfiguration, EspHttpConnection, FollowRedirectsPolicy,
 };
-use esp_idf_sys::esp_crt_bundle_attach;
-use std::vec::Vec;
-
-static COMMON_HEADERS: &'static [(&'static str, &'static str); 3] = &[
-    ("User-Agent", "curl/8.1.2"),
-    ("accept", "*/*"),
-    ("content-type", "application/x-dephy"),
-];
-
-// lazy_static! {
-//     static ref HTTP_CLIENT: Arc<Mutex<HttpClient<EspHttpConnection>>> =
-//         Arc::new(Mutex::new(create_default_client().unwrap()));
-// }
-
-pub fn create_default_client() -> Result<HttpClient<EspHttpConnection>> {
-    let http = HttpConfiguration {
-        buffer_size: None,
-        buffer_size_tx: None,
-        timeout: Some(Duration::from_secs(18)),
-        follow_redirects_policy: FollowRedirectsPolicy::FollowGetHead,
-        client_certificate: None,
-        private_key: None,
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use esp_idf_sys::{
+    esp_crt_bundle_attach, esp_http_client_get_and_clear_last_tls_error, EspError, ESP_ERR_ESP_TLS_BASE,
+    ESP_ERR_ESP_TLS_CONNECTION_TIMEOUT, ESP_ERR_HTTP_CONNECT, ESP_ERR_HTTP_CONNECTING, ESP_ERR_HTTP_EAGAIN,
+    ESP_ERR_TIMEOUT,
+};
+use thiserror::Error;
+
+const CHUNK_LEN: usize = 1024;
+/// Most of an error body worth keeping for the logs.
+const ERROR_BODY_LEN: usize = 256;
+/// Default limit for responses read into memory.
+pub const MAX_BODY_LEN: usize = 64 * 1024;
+/// esp-tls codes below this are about resolving and reaching the host, the
+/// mbedtls and wolfSSL ones above it about the handshake and certificates.
+const TLS_LAYER_BASE: u32 = ESP_ERR_ESP_TLS_BASE + 0x10;
+const TLS_END: u32 = ESP_ERR_ESP_TLS_BASE + 0x100;
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("HTTP {status}: {body}")]
+    Status { status: u16, body: String },
+    #[error("request timed out")]
+    Timeout,
+    #[error("TLS: {0}")]
+    Tls(EspError),
+    #[error("connection failed: {0}")]
+    Connect(EspError),
+    #[error(transparent)]
+    Io(EspError),
+    #[error("reading request body: {0}")]
+    Body(#[from] std::io::Error),
+    #[error("response is larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+impl HttpError {
+    fn classify(e: EspError) -> Self {
+        let code = e.code() as u32;
+        match code {
+            ESP_ERR_TIMEOUT | ESP_ERR_HTTP_EAGAIN | ESP_ERR_ESP_TLS_CONNECTION_TIMEOUT => HttpError::Timeout,
+            ESP_ERR_HTTP_CONNECT | ESP_ERR_HTTP_CONNECTING => HttpError::Connect(e),
+            _ if (ESP_ERR_ESP_TLS_BASE..TLS_LAYER_BASE).contains(&code) => HttpError::Connect(e),
+            _ if (TLS_LAYER_BASE..TLS_END).contains(&code) => HttpError::Tls(e),
+            _ => HttpError::Io(e),
+        }
+    }
+
+    /// Worth trying again later, as opposed to a request the server rejected.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            HttpError::Status { status, .. } => *status == 408 || *status == 429 || *status >= 500,
+            HttpError::Timeout | HttpError::Connect(_) | HttpError::Io(_) => true,
+            HttpError::Tls(_) | HttpError::Body(_) | HttpError::TooLarge(_) => false,
+        }
+    }
+}
+
+impl From<EspIOError> for HttpError {
+    fn from(e: EspIOError) -> Self {
+        HttpError::classify(e.0)
+    }
+}
+
+impl From<EspError> for HttpError {
+    fn from(e: EspError) -> Self {
+        HttpError::classify(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    pub timeout: Duration,
+    pub user_agent: String,
+    /// Sent with every request, before the per-request headers.
+    pub headers: Vec<(String, String)>,
+    pub follow_redirects: bool,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(18),
+            user_agent: format!("ttgo-camera/{}", FIRMWARE_VERSION),
+            headers: Vec::new(),
+            follow_redirects: true,
+        }
+    }
+}
+
+pub enum Body<'b> {
+    Empty,
+    Bytes(&'b [u8]),
+    /// Streamed in chunks, `len` must match what the reader yields.
+    Reader { len: u64, reader: &'b mut dyn std::io::Read },
+}
+
+impl Body<'_> {
+    fn len(&self) -> u64 {
+        match self {
+            Body::Empty => 0,
+            Body::Bytes(b) => b.len() as u64,
+            Body::Reader { len, .. } => *len,
+        }
+    }
+}
+
+/// A 2xx response whose body has not been read yet.
+pub struct Response<'a, 'c> {
+    inner: &'a mut ClientResponse<&'c mut EspHttpConnection>,
+}
+
+impl Response<'_, '_> {
+    pub fn status(&self) -> u16 {
+        self.inner.status()
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.inner.header(name)
+    }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.inner.content_len()
+    }
+
+    /// Reads into `buf`, 0 means the body is done.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
+        Ok(self.inner.read(buf)?)
+    }
+
+    /// Reads the whole body, failing rather than truncating past `limit`.
+    pub fn read_to_end(&mut self, limit: usize) -> Result<Vec<u8>, HttpError> {
+        let mut body = Vec::new();
+        let mut chunk = [0u8; CHUNK_LEN];
+        loop {
+            let n = self.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(body);
+            }
+            if body.len() + n > limit {
+                return Err(HttpError::TooLarge(limit));
+            }
+            body.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Streams the body into `out`, returns the number of bytes copied.
+    pub fn copy_to(&mut self, out: &mut impl std::io::Write) -> Result<u64, HttpError> {
+        let mut total = 0;
+        let mut chunk = [0u8; CHUNK_LEN];
+        loop {
+            let n = self.read(&mut chunk)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            out.write_all(&chunk[..n])?;
+            total += n as u64;
+        }
+    }
+}
+
+pub struct Http {
+    client: HttpClient<EspHttpConnection>,
+    options: HttpOptions,
+}
+
+pub fn create_client(options: HttpOptions) -> Result<Http, HttpError> {
+    let config = HttpConfiguration {
+        timeout: Some(options.timeout),
+        follow_redirects_policy: if options.follow_redirects {
+            FollowRedirectsPolicy::FollowGetHead
+        } else {
+            FollowRedirectsPolicy::FollowNone
+        },
         use_global_ca_store: true,
         crt_bundle_attach: Some(esp_crt_bundle_attach),
+        ..Default::default()
     };
-    let http = EspHttpConnection::new(&http)?;
-    Ok(HttpClient::wrap(http))
+    let connection = EspHttpConnection::new(&config)?;
+    Ok(Http { client: HttpClient::wrap(connection), options })
 }
 
-pub fn request_text<'a>(
-    url: &str,
-    method: Option<Method>,
-    user_headers: &[(&str, &str)],
-    body_buf: Option<&'a [u8]>,
-) -> Result<String> {
-    let (buf, bytes_read) = request(url, method, user_headers, body_buf)?;
-    let buf = &buf[..bytes_read];
-    let ret = std::str::from_utf8(&buf)?;
-    debug!(
-        "Response body (truncated to {} bytes): {:?}",
-        bytes_read, ret
-    );
-    Ok(ret.to_string())
+pub fn create_default_client() -> Result<Http, HttpError> {
+    create_client(HttpOptions::default())
 }
 
-fn request<'a>(
-    url: &'a str,
-    method: Option<Method>,
-    user_headers: &[(&'a str, &'a str)],
-    body_buf: Option<&'a [u8]>,
-) -> Result<([u8; 2048], usize)> {
-    let mut headers = Vec::new();
-    headers.extend(COMMON_HEADERS.clone().into_iter());
+impl Http {
+    pub fn options(&self) -> &HttpOptions {
+        &self.options
+    }
+
+    /// Sends a request and hands a successful response to `on_response`.
+    pub fn request<R>(
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Body<'_>,
+        on_response: impl FnOnce(&mut Response<'_, '_>) -> Result<R, HttpError>,
+    ) -> Result<R, HttpError> {
+        let result = self.send(method, url, headers, body, on_response);
+        result.map_err(|e| self.tls_failure(e))
+    }
 
-    let mut len = "0".to_string();
-    if let Some(buf) = body_buf {
-        len = buf.len().to_string();
+    /// esp_http_client reports a failed handshake or a rejected certificate as
+    /// `ESP_ERR_HTTP_CONNECT`, the last esp-tls error tells them apart from a
+    /// host that is just not reachable.
+    fn tls_failure(&mut self, error: HttpError) -> HttpError {
+        let HttpError::Connect(e) = error else {
+            return error;
+        };
+        // the return value is the esp-tls error, `code` the raw mbedtls one behind it
+        let (mut code, mut flags) = (0, 0);
+        let client = self.client.connection().handle();
+        let last = unsafe { esp_http_client_get_and_clear_last_tls_error(client, &mut code, &mut flags) };
+        if flags != 0 || (TLS_LAYER_BASE..TLS_END).contains(&(last as u32)) {
+            warn!("TLS error {:#x} (mbedtls -{:#x}), certificate verification flags {:#x}", last, code.unsigned_abs(), flags);
+            return HttpError::Tls(EspError::from(last).unwrap_or(e));
+        }
+        HttpError::Connect(e)
     }
-    let e = [("content-length", &len[..])];
-    headers.extend(e.clone().into_iter());
 
-    headers.extend_from_slice(user_headers);
+    fn send<R>(
+        &mut self,
+        method: Method,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Body<'_>,
+        on_response: impl FnOnce(&mut Response<'_, '_>) -> Result<R, HttpError>,
+    ) -> Result<R, HttpError> {
+        let len = body.len().to_string();
+        let mut all_headers: Vec<(&str, &str)> = vec![("User-Agent", self.options.user_agent.as_str())];
+        all_headers.extend(self.options.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        if !matches!(body, Body::Empty) {
+            all_headers.push(("Content-Length", &len));
+        }
+        all_headers.extend_from_slice(headers);
 
-    let h = headers.clone();
-    let mut client = create_default_client()?;
-    let mut request = client.request(
-        if let Some(m) = method { m } else { Method::Get },
-        url,
-        h.as_slice(),
-    )?;
-    debug!("-> GET {}", url);
-    if let Some(buf) = body_buf {
-        request.write_all(buf)?;
+        debug!("-> {:?} {}", method, url);
+        let mut request = self.client.request(method, url, &all_headers)?;
+        match body {
+            Body::Empty => {},
+            Body::Bytes(bytes) => request.write_all(bytes)?,
+            Body::Reader { mut len, reader } => {
+                let mut chunk = [0u8; CHUNK_LEN];
+                while len > 0 {
+                    let n = reader.read(&mut chunk[..CHUNK_LEN.min(len as usize)])?;
+                    if n == 0 {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                    request.write_all(&chunk[..n])?;
+                    len -= n as u64;
+                }
+            },
+        }
         request.flush()?;
+        let mut response = request.submit()?;
+
+        let status = response.status();
+        debug!("<- {} {}", status, url);
+        if !(200..300).contains(&status) {
+            let mut snippet = [0u8; ERROR_BODY_LEN];
+            let n = response.read(&mut snippet).unwrap_or(0);
+            return Err(HttpError::Status { status, body: String::from_utf8_lossy(&snippet[..n]).into_owned() });
+        }
+        on_response(&mut Response { inner: &mut response })
     }
-    let mut response = request.submit()?;
 
-    // Process response
-    let status = response.status();
-    debug!("<- {}", status);
-    let (_headers, mut body) = response.split();
-    let mut buf = [0u8; 2048];
-    let bytes_read = io::try_read_full(&mut body, &mut buf).map_err(|e| e.0)?;
-    debug!("Read {} bytes", bytes_read);
+    pub fn get(&mut self, url: &str, headers: &[(&str, &str)], limit: usize) -> Result<Vec<u8>, HttpError> {
+        self.request(Method::Get, url, headers, Body::Empty, |r| r.read_to_end(limit))
+    }
 
-    Ok((buf, bytes_read))
+    pub fn post(&mut self, url: &str, headers: &[(&str, &str)], body: Body<'_>) -> Result<Vec<u8>, HttpError> {
+        self.request(Method::Post, url, headers, body, |r| r.read_to_end(MAX_BODY_LEN))
+    }
+}
+
+/// One-off request that returns the response body as text.
+pub fn request_text(
+    url: &str,
+    method: Option<Method>,
+    user_headers: &[(&str, &str)],
+    body_buf: Option<&[u8]>,
+) -> Result<String, HttpError> {
+    let mut client = create_default_client()?;
+    let body = body_buf.map_or(Body::Empty, Body::Bytes);
+    let bytes = client.request(method.unwrap_or(Method::Get), url, user_headers, body, |r| r.read_to_end(MAX_BODY_LEN))?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
 }
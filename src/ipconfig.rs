@@ -0,0 +1,108 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::ipv4::{self, ClientConfiguration, ClientSettings, DHCPClientSettings, Mask, Subnet};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration};
+use serde::{Deserialize, Serialize};
+
+use crate::mdns;
+use crate::settings::{Key, SETTINGS};
+
+pub const IP_SETTINGS: Key<IpSettings> = Key::new("ip_settings", IpSettings::default);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum IpSettings {
+    Dhcp {
+        /// Name sent to the DHCP server, the mDNS hostname when unset.
+        #[serde(default)]
+        hostname: Option<String>,
+    },
+    Static {
+        ip: Ipv4Addr,
+        prefix_len: u8,
+        gateway: Ipv4Addr,
+        #[serde(default)]
+        dns: Option<Ipv4Addr>,
+        #[serde(default)]
+        secondary_dns: Option<Ipv4Addr>,
+    },
+}
+
+impl Default for IpSettings {
+    fn default() -> Self {
+        IpSettings::Dhcp { hostname: None }
+    }
+}
+
+impl IpSettings {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            IpSettings::Dhcp { hostname: Some(name) } => mdns::validate_hostname(name),
+            IpSettings::Dhcp { hostname: None } => Ok(()),
+            IpSettings::Static { ip, prefix_len, gateway, .. } => {
+                if !(1..=30).contains(prefix_len) {
+                    return Err(anyhow!("prefix_len must be between 1 and 30"));
+                }
+                if ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() {
+                    return Err(anyhow!("{} is not a usable host address", ip));
+                }
+                let mask = u32::MAX << (32 - prefix_len);
+                if u32::from(*ip) & mask != u32::from(*gateway) & mask {
+                    return Err(anyhow!("gateway {} is outside {}/{}", gateway, ip, prefix_len));
+                }
+                Ok(())
+            },
+        }
+    }
+
+    pub fn is_static(&self) -> bool {
+        matches!(self, IpSettings::Static { .. })
+    }
+
+    /// The name sent to the DHCP server, none with a static address.
+    pub fn dhcp_hostname(&self) -> Option<String> {
+        match self {
+            IpSettings::Dhcp { hostname } => Some(hostname.clone().unwrap_or_else(mdns::hostname)),
+            IpSettings::Static { .. } => None,
+        }
+    }
+
+    fn ip_configuration(&self) -> Result<ipv4::Configuration> {
+        let client = match self {
+            IpSettings::Dhcp { .. } => {
+                let name = self.dhcp_hostname().unwrap_or_default();
+                let mut dhcp_name: heapless::String<30> = heapless::String::new();
+                dhcp_name.push_str(&name[..name.len().min(30)]).map_err(|_| anyhow!("hostname too long"))?;
+                ClientConfiguration::DHCP(DHCPClientSettings { hostname: Some(dhcp_name) })
+            },
+            IpSettings::Static { ip, prefix_len, gateway, dns, secondary_dns } => {
+                ClientConfiguration::Fixed(ClientSettings {
+                    ip: *ip,
+                    subnet: Subnet { gateway: *gateway, mask: Mask(*prefix_len) },
+                    dns: *dns,
+                    secondary_dns: *secondary_dns,
+                })
+            },
+        };
+        Ok(ipv4::Configuration::Client(client))
+    }
+
+    /// Builds a station netif that uses these settings.
+    pub fn sta_netif(&self) -> Result<EspNetif> {
+        let conf = NetifConfiguration {
+            ip_configuration: self.ip_configuration()?,
+            ..NetifConfiguration::wifi_default_client()
+        };
+        Ok(EspNetif::new_with_conf(&conf)?)
+    }
+}
+
+pub fn load() -> Result<IpSettings> {
+    SETTINGS.lock().get(&IP_SETTINGS)
+}
+
+pub fn store(settings: &IpSettings) -> Result<()> {
+    settings.validate()?;
+    SETTINGS.lock().set(&IP_SETTINGS, settings)
+}
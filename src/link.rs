@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::ffi::{c_void, CStr};
+use std::ptr;
+
+use anyhow::Result;
+use esp_idf_svc::ipv4::IpInfo;
+use embedded_svc::wifi::{AccessPointInfo, AuthMethod};
+use esp_idf_sys::{
+    esp_event_base_t, esp_event_handler_register, esp_timer_get_time, esp_wifi_sta_get_ap_info,
+    wifi_ap_record_t, wifi_event_sta_disconnected_t, wifi_event_t_WIFI_EVENT_STA_DISCONNECTED,
+    WIFI_EVENT,
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::connection::ConnState;
+use crate::preludes::*;
+
+const DISCONNECT_HISTORY: usize = 16;
+
+lazy_static! {
+    static ref LINK: Mutex<LinkState> = Mutex::new(LinkState::default());
+}
+
+#[derive(Default)]
+struct LinkState {
+    state: Option<ConnState>,
+    ssid: Option<String>,
+    ip: Option<IpConfig>,
+    reconnects: u32,
+    disconnects: VecDeque<DisconnectRecord>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct IpConfig {
+    pub ip: String,
+    pub prefix_len: u8,
+    pub gateway: String,
+    pub dns: Vec<String>,
+}
+
+impl From<&IpInfo> for IpConfig {
+    fn from(info: &IpInfo) -> Self {
+        Self {
+            ip: info.ip.to_string(),
+            prefix_len: info.subnet.mask.0,
+            gateway: info.subnet.gateway.to_string(),
+            dns: info.dns.iter().chain(info.secondary_dns.iter()).map(|d| d.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DisconnectRecord {
+    pub reason: u8,
+    pub reason_text: &'static str,
+    pub uptime_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ScanEntry {
+    pub ssid: String,
+    pub bssid: String,
+    pub channel: u8,
+    pub rssi: i8,
+    pub auth: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LinkReport {
+    pub state: String,
+    pub ssid: Option<String>,
+    pub bssid: Option<String>,
+    pub rssi: Option<i8>,
+    pub channel: Option<u8>,
+    pub phy_mode: Option<&'static str>,
+    pub reconnects: u32,
+    pub ip: Option<IpConfig>,
+    pub disconnects: Vec<DisconnectRecord>,
+}
+
+pub fn uptime_secs() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1_000_000) as u64
+}
+
+fn format_bssid(bssid: &[u8; 6]) -> String {
+    bssid.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+fn auth_method_str(method: Option<AuthMethod>) -> &'static str {
+    match method {
+        None | Some(AuthMethod::None) => "open",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa-psk",
+        Some(AuthMethod::WPA2Personal) => "wpa2-psk",
+        Some(AuthMethod::WPAWPA2Personal) => "wpa/wpa2-psk",
+        Some(AuthMethod::WPA2Enterprise) => "wpa2-enterprise",
+        Some(AuthMethod::WPA3Personal) => "wpa3-psk",
+        Some(AuthMethod::WPA2WPA3Personal) => "wpa2/wpa3-psk",
+        Some(AuthMethod::WAPIPersonal) => "wapi-psk",
+    }
+}
+
+/// Names for the `wifi_err_reason_t` codes seen in the field.
+pub fn disconnect_reason_str(reason: u8) -> &'static str {
+    match reason {
+        1 => "unspecified",
+        2 => "auth expired",
+        3 => "deauth: leaving",
+        4 => "assoc expired",
+        5 => "too many stations",
+        6 | 7 => "not authenticated/associated",
+        8 => "disassoc: leaving",
+        15 => "4-way handshake timeout",
+        16 => "group key update timeout",
+        200 => "beacon timeout",
+        201 => "no AP found",
+        202 => "auth failed",
+        203 => "assoc failed",
+        204 => "handshake timeout",
+        205 => "connection failed",
+        _ => "other",
+    }
+}
+
+fn phy_mode_str(record: &wifi_ap_record_t) -> &'static str {
+    if record.phy_11n() != 0 {
+        "11n"
+    } else if record.phy_11g() != 0 {
+        "11g"
+    } else if record.phy_11b() != 0 {
+        "11b"
+    } else if record.phy_lr() != 0 {
+        "lr"
+    } else {
+        "unknown"
+    }
+}
+
+fn record_ssid(record: &wifi_ap_record_t) -> String {
+    CStr::from_bytes_until_nul(&record.ssid)
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+unsafe extern "C" fn on_sta_disconnected(
+    _arg: *mut c_void,
+    _base: esp_event_base_t,
+    _id: i32,
+    data: *mut c_void,
+) {
+    if data.is_null() {
+        return;
+    }
+    let event = &*(data as *const wifi_event_sta_disconnected_t);
+    let record = DisconnectRecord {
+        reason: event.reason,
+        reason_text: disconnect_reason_str(event.reason),
+        uptime_secs: uptime_secs(),
+    };
+    let mut link = LINK.lock();
+    if link.disconnects.len() == DISCONNECT_HISTORY {
+        link.disconnects.pop_front();
+    }
+    link.disconnects.push_back(record);
+}
+
+/// Records disconnect reasons, the typed Wi-Fi events don't carry them.
+pub fn register_disconnect_history() -> Result<()> {
+    esp!(unsafe {
+        esp_event_handler_register(
+            WIFI_EVENT,
+            wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as i32,
+            Some(on_sta_disconnected),
+            ptr::null_mut(),
+        )
+    })?;
+    Ok(())
+}
+
+pub fn set_state(state: ConnState, reconnects: u32) {
+    let mut link = LINK.lock();
+    link.state = Some(state);
+    link.reconnects = reconnects;
+    if state != ConnState::Connected {
+        link.ssid = None;
+        link.ip = None;
+    }
+}
+
+pub fn set_connected(ssid: &str, ip: &IpInfo) {
+    let mut link = LINK.lock();
+    link.ssid = Some(ssid.to_owned());
+    link.ip = Some(ip.into());
+}
+
+pub fn is_connected() -> bool {
+    LINK.lock().state == Some(ConnState::Connected)
+}
+
+/// Current IP configuration of the station, if it has one.
+pub fn ip_config() -> Option<IpConfig> {
+    LINK.lock().ip.clone()
+}
+
+impl From<&AccessPointInfo> for ScanEntry {
+    fn from(ap: &AccessPointInfo) -> Self {
+        Self {
+            ssid: ap.ssid.to_string(),
+            bssid: format_bssid(&ap.bssid),
+            channel: ap.channel,
+            rssi: ap.signal_strength,
+            auth: auth_method_str(ap.auth_method),
+        }
+    }
+}
+
+pub fn report() -> LinkReport {
+    let mut record = wifi_ap_record_t::default();
+    let live = esp!(unsafe { esp_wifi_sta_get_ap_info(&mut record) }).ok().map(|_| record);
+
+    let link = LINK.lock();
+    LinkReport {
+        state: link.state.map(|s| format!("{:?}", s)).unwrap_or_else(|| "Idle".to_owned()),
+        ssid: live.as_ref().map(record_ssid).or_else(|| link.ssid.clone()),
+        bssid: live.as_ref().map(|r| format_bssid(&r.bssid)),
+        rssi: live.as_ref().map(|r| r.rssi),
+        channel: live.as_ref().map(|r| r.primary),
+        phy_mode: live.as_ref().map(phy_mode_str),
+        reconnects: link.reconnects,
+        ip: link.ip.clone(),
+        disconnects: link.disconnects.iter().cloned().collect(),
+    }
+}
@@ -2,7 +2,7 @@
 use anyhow::Result as AnyResult;
 use edge_executor::Executor;
 use esp_camera_rs::Camera;
-use preludes::InfoSender;
+use preludes::{InfoReceiver, InfoSender};
 use std::{
     time::{Instant, Duration},
     sync::{Arc, Mutex},
@@ -16,32 +16,55 @@ use esp_idf_svc::{
 };
 use log::*;
 
+mod api;
+mod clock;
+mod captures;
+mod commands;
+mod connection;
+mod delivery;
+mod files;
+mod firmware;
 // mod app;
 // mod ble;
 // mod build_env;
 // mod crypto;
-// mod http;
+mod http;
 // mod key_inspect;
-// mod mqtt;
+mod homeassistant;
+mod ipconfig;
+mod link;
+mod mdns;
+mod mqtt;
+mod multipart;
+mod networks;
 mod ntp;
+mod ota;
+mod outbox;
+mod panel;
 mod peripherals;
+mod posix_tz;
+mod power;
 mod preludes;
+mod provisioning;
+mod recorder;
+mod sensor;
+mod settings;
+mod sntp;
+mod storage;
+mod template;
 mod wifi;
 mod small_display;
+mod timezone;
+mod transfer;
+mod upload;
+mod webhook;
 mod window;
 
-use crate::{wifi::{app_wifi_loop, initial_wifi_connect}, peripherals::{take_i2c, SYS_LOOP, PERIPHERALS, ESP_TASK_TIMER_SVR, create_esp_wifi}};
+use crate::{wifi::app_wifi_loop, peripherals::{take_i2c, SYS_LOOP, PERIPHERALS, ESP_TASK_TIMER_SVR, create_esp_wifi}};
 use crate::small_display::*;
+use crate::panel::{AnyPanel, PanelConfig};
 
 
-#[toml_cfg::toml_config]
-pub struct Config {
-    #[default("")]
-    wifi_ssid: &'static str,
-    #[default("")]
-    wifi_psk: &'static str,
-}
-
 // Chip: ESP32-WROVER-B
 // Protocol: Wi-Fi 802.11 b/g/n & bluetooth 4.2 BLE & BR/EDR
 // Flash: 4MB
@@ -62,11 +85,14 @@ fn init_http(cam: Arc<Mutex<Camera>>, tx: InfoSender) -> AnyResult<EspHttpServer
     let httpd_config = esp_idf_svc::http::server::Configuration {
         session_timeout: Duration::from_secs(5*50),
         uri_match_wildcard: true,
+        // the API has outgrown the default of 32 handlers
+        max_uri_handlers: 64,
         ..Default::default()
     };
     let mut server = EspHttpServer::new(&httpd_config)?;
 
     server.fn_handler("/", esp_idf_svc::http::Method::Get, move |request| {
+        let _busy = power::busy();
         let mut time = Instant::now();
         info!("handling request");
         if let Err(e) = tx.send(InfoUpdate::Msg("handling request".to_owned())) {
@@ -110,6 +136,23 @@ fn init_http(cam: Arc<Mutex<Camera>>, tx: InfoSender) -> AnyResult<EspHttpServer
         Ok(())
     })?;
 
+    api::register_wifi_networks(&mut server)?;
+    api::register_wifi_diagnostics(&mut server)?;
+    api::register_device(&mut server)?;
+    api::register_network(&mut server)?;
+    api::register_power(&mut server)?;
+    api::register_status(&mut server)?;
+    api::register_timezone(&mut server)?;
+    api::register_time_sources(&mut server)?;
+    api::register_upload(&mut server)?;
+    api::register_webhook(&mut server)?;
+    api::register_mqtt(&mut server)?;
+    api::register_commands(&mut server)?;
+    api::register_recorder(&mut server)?;
+    ota::register_handlers(&mut server)?;
+    files::register_handlers(&mut server)?;
+    provisioning::register_handlers(&mut server)?;
+
     Ok(server)
 }
 
@@ -122,19 +165,51 @@ where
     tx.send(InfoUpdate::Motion(pir.get_level().into()))?;
     loop {
         pir.wait_for_any_edge().await?;
-        tx.send(InfoUpdate::Motion(pir.get_level().into()))?;
+        let level = pir.get_level();
+        tx.send(InfoUpdate::Motion(level.into()))?;
+        if level == gpio::Level::High && commands::is_armed() {
+            upload::request_capture(upload::Trigger::Motion);
+        }
     }
 }
 
+// holding the button this long and releasing it starts Wi-Fi provisioning
+const PROVISIONING_HOLD: Duration = Duration::from_secs(5);
+
 async fn button_task<P>(mut button: PinDriver<'_, P, Input>, tx: InfoSender) -> AnyResult<()>
 where
     P: InputPin,
 {
+    let mut pressed_at: Option<Instant> = None;
     tx.send(InfoUpdate::Button(button.get_level().into()))?;
     loop {
         button.wait_for_any_edge().await?;
-        tx.send(InfoUpdate::Button(button.get_level().into()))?;
+        let level = button.get_level();
+        tx.send(InfoUpdate::Button(level.into()))?;
         // std::thread::sleep(Duration::from_secs(1));
+
+        // the button pulls the pin low while pressed
+        if level == gpio::Level::Low {
+            pressed_at = Some(Instant::now());
+        } else if let Some(at) = pressed_at.take() {
+            if at.elapsed() >= PROVISIONING_HOLD {
+                tx.send(InfoUpdate::Gesture(Gesture::LongPress))?;
+                tx.send(InfoUpdate::Msg("setup requested".to_owned()))?;
+                provisioning::request_provisioning();
+            } else {
+                tx.send(InfoUpdate::Gesture(Gesture::Press))?;
+            }
+        }
+    }
+}
+
+/// Lets the webhooks and MQTT see every update on its way to the display.
+async fn info_fanout(rx: InfoReceiver, display: InfoSender) -> AnyResult<()> {
+    loop {
+        let update = rx.recv_async().await?;
+        webhook::observe(&update);
+        mqtt::observe(&update);
+        display.send(update)?;
     }
 }
 
@@ -153,14 +228,18 @@ fn main() -> AnyResult<()> {
     let wakeup_reason = WakeupReason::get();
     info!("Last wakeup was due to {:#?}", wakeup_reason);
 
+    if let Err(e) = timezone::apply() {
+        error!("timezone: {}", e);
+    }
+
     let i2c = take_i2c();
-    let sd_iface = bld_interface(i2c)?;
-    // let sd = SmallDisplay::new(sd_iface, DisplaySize128x64, DisplayRotation::Rotate0);
+    let panel = AnyPanel::new(i2c, PanelConfig::from_config());
 
     let (tx, rx) = flume::unbounded::<InfoUpdate>();
+    let (display_tx, display_rx) = flume::unbounded::<InfoUpdate>();
 
     let wifi: EspWifi<'static> = create_esp_wifi();
-    let mut mywifi: AsyncWifi<EspWifi<'static>> = AsyncWifi::wrap(wifi, SYS_LOOP.clone(), ESP_TASK_TIMER_SVR.clone()).unwrap();
+    let mywifi: AsyncWifi<EspWifi<'static>> = AsyncWifi::wrap(wifi, SYS_LOOP.clone(), ESP_TASK_TIMER_SVR.clone()).unwrap();
 
     let p = PERIPHERALS.clone();
     let mut p = p.lock();
@@ -220,6 +299,26 @@ fn main() -> AnyResult<()> {
         Some(cam_scl.into_ref().map_into()),
     )?;
     let camera_mutex = Arc::new(Mutex::new(camera));
+    if let Err(e) = sensor::apply(&camera_mutex) {
+        error!("sensor: {}", e);
+    }
+    commands::init(camera_mutex.clone(), tx.clone())?;
+    if let Err(e) = ota::confirm_when_healthy(camera_mutex.clone()) {
+        error!("ota: {}", e);
+    }
+    if let Err(e) = storage::mount() {
+        error!("storage: {}", e);
+    }
+    if let Err(e) = delivery::start() {
+        error!("outbox: {}", e);
+    }
+    if let Err(e) = recorder::start() {
+        error!("recorder: {}", e);
+    }
+    upload::start(camera_mutex.clone(), tx.clone())?;
+    webhook::start()?;
+    mqtt::start()?;
+    ota::start()?;
     let _http = match init_http(camera_mutex, tx.clone()) {
         Err(e) => {
             error!("init_http: {}", e);
@@ -227,13 +326,17 @@ fn main() -> AnyResult<()> {
         }
         Ok(h) => h,
     };
+    if let Err(e) = mdns::start() {
+        error!("mdns: {}", e);
+    }
+    clock::start(tx.clone())?;
 
     let ex: Executor<'_, 64> = edge_executor::Executor::default();
     edge_executor::block_on( async move {
-        let _ = futures::executor::block_on(initial_wifi_connect(&mut mywifi, tx.clone()));
         let _button_task = ex.spawn(button_task(push_button, tx.clone()));
         let _pir_task = ex.spawn(pir_task(pir, tx.clone()));
-        let _disp_task = ex.spawn(display_runner(sd_iface, rx));
+        let _fanout_task = ex.spawn(info_fanout(rx, display_tx));
+        let _disp_task = ex.spawn(display_runner(panel, display_rx));
         let _wifi_loop = ex.spawn( app_wifi_loop(mywifi, tx.clone()) );
         while ex.try_tick() {
             std::thread::sleep(Duration::from_micros(250));
@@ -0,0 +1,86 @@
+use anyhow::{anyhow, Result};
+use esp_idf_svc::mdns::EspMdns;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::peripherals::device_id;
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+
+pub const MODEL: &str = "ttgo-t-camera-v16";
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+const INSTANCE_NAME: &str = "TTGO Camera";
+const HTTP_PORT: u16 = 80;
+
+/// Empty means "derive from the MAC".
+pub const HOSTNAME: Key<String> = Key::new("hostname", String::new);
+
+lazy_static! {
+    static ref MDNS: Mutex<Option<EspMdns>> = Mutex::new(None);
+}
+
+pub fn default_hostname() -> String {
+    format!("ttgo-camera-{}", device_id())
+}
+
+/// The configured hostname, used for mDNS and as the DHCP client name.
+pub fn hostname() -> String {
+    match SETTINGS.lock().get(&HOSTNAME) {
+        Ok(name) if !name.is_empty() => name,
+        Ok(_) => default_hostname(),
+        Err(e) => {
+            error!("hostname setting: {}", e);
+            default_hostname()
+        },
+    }
+}
+
+/// Checks the name is a valid single DNS label.
+pub fn validate_hostname(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+    if !valid {
+        return Err(anyhow!("hostname must be 1 to 63 lowercase letters, digits or '-'"));
+    }
+    Ok(())
+}
+
+/// Stores a new hostname (empty resets to the default) and re-announces it.
+pub fn set_hostname(name: &str) -> Result<String> {
+    if !name.is_empty() {
+        validate_hostname(name)?;
+    }
+    SETTINGS.lock().set(&HOSTNAME, &name.to_owned())?;
+    let name = hostname();
+    if let Some(mdns) = MDNS.lock().as_mut() {
+        mdns.set_hostname(&name)?;
+    }
+    Ok(name)
+}
+
+/// Starts the responder; it follows the network interfaces as they come up.
+pub fn start() -> Result<()> {
+    let name = hostname();
+    let id = device_id();
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(&name)?;
+    mdns.set_instance_name(INSTANCE_NAME)?;
+
+    let txt = [
+        ("model", MODEL),
+        ("fw", FIRMWARE_VERSION),
+        ("id", id.as_str()),
+        ("snapshot", "/"),
+        ("api", "/api"),
+    ];
+    mdns.add_service(Some(INSTANCE_NAME), "_http", "_tcp", HTTP_PORT, &txt)?;
+    // lets NVRs and scripts find cameras without probing every web server
+    mdns.add_service(Some(INSTANCE_NAME), "_ttgocam", "_tcp", HTTP_PORT, &txt)?;
+
+    info!("mDNS: advertising {}.local", name);
+    *MDNS.lock() = Some(mdns);
+    Ok(())
+}
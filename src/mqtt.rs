@@ -0,0 +1,627 @@
+// MQTT client publishing the camera state, announced to Home Assistant.
+//
+// The client and everything published through it belong to the mqtt thread.
+// Updates reach it from `observe` on the `InfoUpdate` stream and connection
+// events from the esp-mqtt task, both through one channel. Availability is a
+// retained `online` on connect and the broker publishes the last will
+// `offline` when the camera drops off.
+//
+// Commands arrive on `<base>/cmd/<name>` with their arguments as a JSON
+// object, optionally carrying an `id` and a `response_topic`. They run
+// through `commands::execute` like the HTTP API's, and every one is
+// answered on the response topic (default `<base>/ack`) with the same `id`.
+//
+// Snapshots are taken by the upload thread and handed over as JPEG bytes for
+// the camera topic. Frames over the size limit are either split into chunks
+// on `<camera topic>/chunks/<id>/<index>/<count>` or retaken with more
+// compression, and a minimum interval keeps motion from flooding the broker.
+
+use std::sync::Mutex as StdMutex;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use embedded_hal::digital::PinState;
+use esp_camera_rs::Camera;
+use embedded_svc::mqtt::client::{Details, Event, Message as _, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration};
+use esp_idf_sys::{esp_crt_bundle_attach, esp_get_free_heap_size};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, TimeStatus};
+use crate::commands::{self, Command};
+use crate::delivery;
+use crate::homeassistant::{self, Broker, DeviceInfo, Discovery, State, Topics, OFF, OFFLINE, ON};
+use crate::link;
+use crate::mdns;
+use crate::peripherals::device_id;
+use crate::preludes::*;
+use crate::sensor;
+use crate::settings::{Key, SETTINGS};
+use crate::small_display::Gesture;
+use crate::upload::{Capture, Trigger};
+
+pub const MQTT_SETTINGS: Key<MqttSettings> = Key::new("mqtt", MqttSettings::default);
+
+const PENDING_MESSAGES: usize = 32;
+const MIN_DIAGNOSTICS_INTERVAL_SECS: u32 = 10;
+const MIN_SNAPSHOT_BYTES: usize = 4 * 1024;
+const MAX_SNAPSHOT_BYTES: usize = 256 * 1024;
+
+lazy_static! {
+    static ref MESSAGES: (flume::Sender<Message>, flume::Receiver<Message>) = flume::bounded(PENDING_MESSAGES);
+    static ref STATUS: Mutex<MqttStatus> = Mutex::new(MqttStatus::default());
+    static ref LAST_SNAPSHOT: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// What to do with a frame larger than `SnapshotSettings::max_bytes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Oversize {
+    /// Publish it in pieces below `<topic>/chunks`, and a recompressed copy to the topic itself.
+    Chunk,
+    /// Only publish a copy taken again with more JPEG compression until it fits.
+    Recompress,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SnapshotSettings {
+    pub enabled: bool,
+    /// Defaults to `<base>/camera`, announced as a Home Assistant camera.
+    pub topic: Option<String>,
+    pub on_motion: bool,
+    /// Snapshots closer together than this are skipped.
+    pub min_interval_secs: u32,
+    pub max_bytes: usize,
+    pub oversize: Oversize,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            topic: None,
+            on_motion: true,
+            min_interval_secs: 10,
+            max_bytes: 128 * 1024,
+            oversize: Oversize::Chunk,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub enabled: bool,
+    /// `mqtt://host:1883`, or `mqtts://host:8883` checked against the certificate bundle.
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Defaults to `ttgo-<device id>`.
+    pub client_id: Option<String>,
+    /// Defaults to `ttgo-camera/<device id>`.
+    pub base_topic: Option<String>,
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    pub keep_alive_secs: u16,
+    pub diagnostics_interval_secs: u32,
+    pub snapshots: SnapshotSettings,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            username: None,
+            password: None,
+            client_id: None,
+            base_topic: None,
+            discovery: true,
+            discovery_prefix: "homeassistant".to_owned(),
+            keep_alive_secs: 60,
+            diagnostics_interval_secs: 60,
+            snapshots: SnapshotSettings::default(),
+        }
+    }
+}
+
+impl MqttSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && !(self.url.starts_with("mqtt://") || self.url.starts_with("mqtts://")) {
+            return Err(anyhow!("url must be an mqtt:// or mqtts:// URL"));
+        }
+        let topics = [
+            ("base_topic", self.base_topic.as_deref()),
+            ("discovery_prefix", Some(self.discovery_prefix.as_str())),
+            ("snapshots.topic", self.snapshots.topic.as_deref()),
+        ];
+        for (what, topic) in topics {
+            if let Some(topic) = topic {
+                if topic.is_empty() || topic.contains(['+', '#', '\0']) || topic.starts_with('/') {
+                    return Err(anyhow!("{} must be a plain topic without wildcards", what));
+                }
+            }
+        }
+        if self.client_id.as_ref().is_some_and(|id| id.is_empty() || id.len() > 64) {
+            return Err(anyhow!("client_id must be 1 to 64 characters"));
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err(anyhow!("a password needs a username"));
+        }
+        if self.diagnostics_interval_secs < MIN_DIAGNOSTICS_INTERVAL_SECS {
+            return Err(anyhow!("diagnostics_interval_secs must be at least {}", MIN_DIAGNOSTICS_INTERVAL_SECS));
+        }
+        if !(MIN_SNAPSHOT_BYTES..=MAX_SNAPSHOT_BYTES).contains(&self.snapshots.max_bytes) {
+            return Err(anyhow!("snapshots.max_bytes must be between {} and {}", MIN_SNAPSHOT_BYTES, MAX_SNAPSHOT_BYTES));
+        }
+        Ok(())
+    }
+
+    pub fn client_id(&self) -> String {
+        self.client_id.clone().unwrap_or_else(|| format!("ttgo-{}", device_id()))
+    }
+
+    pub fn topics(&self) -> Topics {
+        match &self.base_topic {
+            Some(base) => Topics::new(base),
+            None => Topics::new(&format!("ttgo-camera/{}", device_id())),
+        }
+    }
+
+    /// Where snapshots go, if they are turned on.
+    pub fn camera_topic(&self) -> Option<String> {
+        if !self.snapshots.enabled {
+            return None;
+        }
+        Some(self.snapshots.topic.clone().unwrap_or_else(|| self.topics().topic("camera")))
+    }
+
+    /// Copy that is safe to show, the password stays on the device.
+    pub fn redacted(&self) -> Self {
+        Self { password: self.password.as_ref().map(|_| "***".to_owned()), ..self.clone() }
+    }
+}
+
+pub fn load() -> Result<MqttSettings> {
+    SETTINGS.lock().get(&MQTT_SETTINGS)
+}
+
+/// Stores new settings and reconnects with them; "***" keeps the stored password.
+pub fn store(mut settings: MqttSettings) -> Result<()> {
+    if settings.password.as_deref() == Some("***") {
+        settings.password = load()?.password;
+    }
+    settings.validate()?;
+    SETTINGS.lock().set(&MQTT_SETTINGS, &settings)?;
+    let _ = MESSAGES.0.try_send(Message::Reconfigure);
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MqttStatus {
+    pub enabled: bool,
+    pub connected: bool,
+    pub base_topic: Option<String>,
+    pub connects: u32,
+    pub last_error: Option<String>,
+}
+
+pub fn status() -> MqttStatus {
+    STATUS.lock().clone()
+}
+
+/// Published to `diagnostics` as one retained JSON object.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostics {
+    pub rssi: Option<i8>,
+    pub ip: Option<String>,
+    pub uptime_secs: u64,
+    pub free_heap: u32,
+    pub time_status: TimeStatus,
+    pub outbox_depth: usize,
+    pub reconnects: u32,
+    pub firmware: &'static str,
+}
+
+impl Diagnostics {
+    pub fn collect() -> Self {
+        let link = link::report();
+        Self {
+            rssi: link.rssi,
+            ip: link.ip.map(|ip| ip.ip),
+            uptime_secs: link::uptime_secs(),
+            free_heap: unsafe { esp_get_free_heap_size() },
+            time_status: clock::status(),
+            outbox_depth: delivery::stats().map_or(0, |s| s.depth),
+            reconnects: link.reconnects,
+            firmware: mdns::FIRMWARE_VERSION,
+        }
+    }
+}
+
+enum Message {
+    Connected,
+    Disconnected,
+    Error(String),
+    Motion(bool),
+    Button(&'static str),
+    Armed(bool),
+    Command { name: String, payload: Vec<u8> },
+    Snapshot(Vec<u8>),
+    /// Something in the diagnostics changed, publish them now.
+    Diagnostics,
+    Reconfigure,
+}
+
+fn send(message: Message) {
+    if MESSAGES.0.try_send(message).is_err() {
+        debug!("mqtt: queue full, dropping an update");
+    }
+}
+
+/// Picks the updates that are published out of the display stream.
+pub fn observe(update: &InfoUpdate) {
+    match update {
+        InfoUpdate::Motion(level) => send(Message::Motion(*level == PinState::High)),
+        InfoUpdate::Gesture(Gesture::Press) => send(Message::Button("press")),
+        InfoUpdate::Gesture(Gesture::LongPress) => send(Message::Button("long_press")),
+        InfoUpdate::Armed(armed) => send(Message::Armed(*armed)),
+        InfoUpdate::Addr(_) | InfoUpdate::Rssi(None) | InfoUpdate::TimeSync(_) => send(Message::Diagnostics),
+        _ => {},
+    }
+}
+
+/// Whether a capture for `trigger` should also go to the broker; claims the rate limit slot.
+pub fn wants_snapshot(trigger: Trigger) -> bool {
+    let Ok(settings) = load() else {
+        return false;
+    };
+    let snapshots = &settings.snapshots;
+    let wanted = settings.enabled
+        && snapshots.enabled
+        && STATUS.lock().connected
+        && match trigger {
+            Trigger::Motion => snapshots.on_motion,
+            Trigger::Manual => true,
+            Trigger::Schedule => false,
+        };
+    if !wanted {
+        return false;
+    }
+    let mut last = LAST_SNAPSHOT.lock();
+    let interval = Duration::from_secs(snapshots.min_interval_secs.into());
+    if last.is_some_and(|at| at.elapsed() < interval) {
+        debug!("mqtt: snapshot skipped, the last one was less than {:?} ago", interval);
+        return false;
+    }
+    *last = Some(Instant::now());
+    true
+}
+
+/// Hands a capture to the mqtt thread. The camera topic always gets a frame
+/// that fits, retaken smaller if it has to be; in `Chunk` mode the original
+/// goes out in chunks as well.
+pub fn publish_snapshot(camera: &StdMutex<Camera>, capture: &Capture) -> Result<()> {
+    let snapshots = load()?.snapshots;
+    if capture.jpeg.len() <= snapshots.max_bytes {
+        return queue_snapshot(capture.jpeg.clone());
+    }
+    if snapshots.oversize == Oversize::Chunk {
+        queue_snapshot(capture.jpeg.clone())?;
+    }
+    // Home Assistant only ever reads the camera topic itself
+    queue_snapshot(sensor::capture_within(camera, snapshots.max_bytes)?)
+}
+
+fn queue_snapshot(jpeg: Vec<u8>) -> Result<()> {
+    MESSAGES.0.try_send(Message::Snapshot(jpeg)).map_err(|_| anyhow!("mqtt queue is full"))
+}
+
+struct Session {
+    client: EspMqttClient<'static>,
+    settings: MqttSettings,
+    topics: Topics,
+    connected: bool,
+    snapshots_sent: u32,
+    /// Commands whose retained copy was cleared and whose empty echo is still due.
+    clearing: Vec<String>,
+}
+
+impl Session {
+    fn open(settings: MqttSettings) -> Result<Self> {
+        let topics = settings.topics();
+        let (will_topic, will_payload) = homeassistant::last_will(&topics);
+        let client_id = settings.client_id();
+        let config = MqttClientConfiguration {
+            client_id: Some(&client_id),
+            username: settings.username.as_deref(),
+            password: settings.password.as_deref(),
+            keep_alive_interval: Some(Duration::from_secs(settings.keep_alive_secs.into())),
+            lwt: Some(LwtConfiguration {
+                topic: &will_topic,
+                payload: will_payload.as_bytes(),
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        };
+        let command_topics = topics.clone();
+        let client = EspMqttClient::new(&settings.url, &config, move |event| match event {
+            Ok(Event::Connected(_)) => send(Message::Connected),
+            Ok(Event::Disconnected) => send(Message::Disconnected),
+            Ok(Event::Received(message)) => {
+                // commands are small, anything split across chunks is not one
+                if !matches!(message.details(), Details::Complete) {
+                    return;
+                }
+                let topic = message.topic().map(|t| t.to_string()).unwrap_or_default();
+                if let Some(name) = command_topics.command_name(&topic) {
+                    send(Message::Command { name: name.to_owned(), payload: message.data().to_vec() });
+                }
+            },
+            Err(e) => send(Message::Error(e.to_string())),
+            _ => {},
+        })?;
+        info!("mqtt: connecting to {} as {}", settings.url, client_id);
+        Ok(Self { client, settings, topics, connected: false, snapshots_sent: 0, clearing: Vec::new() })
+    }
+
+    fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            id: device_id(),
+            name: mdns::hostname(),
+            model: mdns::MODEL.to_owned(),
+            sw_version: mdns::FIRMWARE_VERSION.to_owned(),
+            configuration_url: link::ip_config().map(|ip| format!("http://{}/", ip.ip)),
+        }
+    }
+
+    fn announce(&mut self, motion: Option<bool>) -> Result<()> {
+        let topics = self.topics.clone();
+        let prefix = self.settings.discovery_prefix.clone();
+        let device = self.device_info();
+        let camera_topic = self.settings.camera_topic();
+        let discovery = self.settings.discovery.then_some(Discovery {
+            prefix: &prefix,
+            device: &device,
+            camera_topic: camera_topic.as_deref(),
+        });
+        let diagnostics = serde_json::to_vec(&Diagnostics::collect())?;
+        let state = State { motion, armed: commands::is_armed(), diagnostics: &diagnostics };
+        homeassistant::announce(self, &topics, discovery, &state)
+    }
+
+    fn publish_armed(&mut self, armed: bool) -> Result<()> {
+        self.publish(&self.topics.armed(), true, if armed { ON } else { OFF }.as_bytes())
+    }
+
+    /// Publishes a JPEG whole, or in chunks when it is over the limit.
+    fn publish_snapshot(&mut self, jpeg: &[u8]) -> Result<()> {
+        let Some(topic) = self.settings.camera_topic() else {
+            return Ok(());
+        };
+        let max_bytes = self.settings.snapshots.max_bytes;
+        self.snapshots_sent = self.snapshots_sent.wrapping_add(1);
+        if jpeg.len() <= max_bytes {
+            return self.publish(&topic, false, jpeg);
+        }
+        let count = jpeg.len().div_ceil(max_bytes);
+        for (index, chunk) in jpeg.chunks(max_bytes).enumerate() {
+            let chunk_topic = format!("{}/chunks/{}/{}/{}", topic, self.snapshots_sent, index, count);
+            self.publish(&chunk_topic, false, chunk)?;
+        }
+        debug!("mqtt: snapshot of {} bytes sent in {} chunks", jpeg.len(), count);
+        Ok(())
+    }
+
+    /// Runs a command and publishes the acknowledgement.
+    ///
+    /// A command published with the retain flag would come back on every
+    /// subscribe, and esp-mqtt doesn't tell retained deliveries apart, so the
+    /// command topic is cleared with an empty retained message once the command
+    /// has run. The broker sends that empty message back to us; it is dropped
+    /// here instead of being run as the command without arguments.
+    fn handle_command(&mut self, name: &str, payload: &[u8]) -> Result<()> {
+        if payload.is_empty() {
+            if let Some(index) = self.clearing.iter().position(|n| n == name) {
+                self.clearing.swap_remove(index);
+                return Ok(());
+            }
+        }
+        let envelope = Envelope::split(payload);
+        let outcome = envelope.args.and_then(|args| Command::parse(name, &args)).and_then(commands::execute);
+        let mut ack = serde_json::json!({ "id": envelope.id, "command": name, "ok": outcome.is_ok() });
+        match outcome {
+            Ok(result) => ack["result"] = result,
+            Err(e) => {
+                warn!("mqtt command {}: {}", name, e);
+                ack["error"] = e.to_string().into();
+            },
+        }
+        let topic = envelope.response_topic.unwrap_or_else(|| self.topics.acks());
+        self.publish(&topic, false, ack.to_string().as_bytes())?;
+        self.publish(&self.topics.command(name), true, &[])?;
+        self.clearing.push(name.to_owned());
+        Ok(())
+    }
+
+    fn publish_motion(&mut self, motion: bool) -> Result<()> {
+        self.publish(&self.topics.motion(), true, if motion { ON } else { OFF }.as_bytes())
+    }
+
+    fn publish_diagnostics(&mut self) -> Result<()> {
+        let payload = serde_json::to_vec(&Diagnostics::collect())?;
+        self.publish(&self.topics.diagnostics(), true, &payload)
+    }
+
+    /// Leaves cleanly: `offline` now, and no entities left behind if discovery was turned off.
+    fn close(mut self, retract: bool) {
+        if !self.connected {
+            return;
+        }
+        if retract {
+            let retractions = homeassistant::retractions(&self.settings.discovery_prefix, &device_id(), &self.topics);
+            for (topic, payload) in retractions {
+                let _ = self.publish(&topic, true, payload.as_bytes());
+            }
+        }
+        let _ = self.publish(&self.topics.availability(), true, OFFLINE.as_bytes());
+    }
+}
+
+impl Broker for Session {
+    type Error = anyhow::Error;
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<()> {
+        self.client.publish(topic, QoS::AtLeastOnce, retain, payload)?;
+        Ok(())
+    }
+
+    fn subscribe(&mut self, filter: &str) -> Result<()> {
+        self.client.subscribe(filter, QoS::AtLeastOnce)?;
+        Ok(())
+    }
+}
+
+/// The parts of a command payload that are about the reply rather than the command.
+struct Envelope {
+    id: Option<serde_json::Value>,
+    response_topic: Option<String>,
+    args: Result<Vec<u8>>,
+}
+
+impl Envelope {
+    fn split(payload: &[u8]) -> Self {
+        let mut envelope = Self { id: None, response_topic: None, args: Ok(payload.to_vec()) };
+        let Ok(serde_json::Value::Object(mut object)) = serde_json::from_slice(payload) else {
+            return envelope;
+        };
+        envelope.id = object.remove("id");
+        match object.remove("response_topic") {
+            None => {},
+            Some(serde_json::Value::String(topic)) if !topic.is_empty() && !topic.contains(['+', '#']) => {
+                envelope.response_topic = Some(topic);
+            },
+            Some(_) => envelope.args = Err(anyhow!("response_topic must be a topic without wildcards")),
+        }
+        if envelope.args.is_ok() {
+            envelope.args = serde_json::to_vec(&object).map_err(Into::into);
+        }
+        envelope
+    }
+}
+
+fn open_session() -> Option<Session> {
+    let settings = match load() {
+        Ok(settings) if settings.enabled => settings,
+        Ok(_) => return None,
+        Err(e) => {
+            error!("mqtt settings: {}", e);
+            return None;
+        },
+    };
+    let base_topic = settings.topics().base().to_owned();
+    let session = Session::open(settings);
+    let mut status = STATUS.lock();
+    *status = MqttStatus { enabled: true, base_topic: Some(base_topic), connects: status.connects, ..Default::default() };
+    match session {
+        Ok(session) => Some(session),
+        Err(e) => {
+            error!("mqtt: {}", e);
+            status.last_error = Some(e.to_string());
+            None
+        },
+    }
+}
+
+fn run() {
+    let mut session = open_session();
+    let mut motion: Option<bool> = None;
+    let mut next_diagnostics = Instant::now();
+
+    loop {
+        let wait = match &session {
+            Some(s) if s.connected => next_diagnostics.saturating_duration_since(Instant::now()),
+            _ => Duration::from_secs(60),
+        };
+        let message = match MESSAGES.1.recv_timeout(wait) {
+            Ok(message) => Some(message),
+            Err(flume::RecvTimeoutError::Timeout) => None,
+            Err(flume::RecvTimeoutError::Disconnected) => return,
+        };
+
+        if let Some(Message::Motion(level)) = message {
+            motion = Some(level);
+        }
+        if let Some(Message::Reconfigure) = message {
+            if let Some(old) = session.take() {
+                let retract = old.settings.discovery && !load().is_ok_and(|s| s.enabled && s.discovery);
+                old.close(retract);
+            }
+            let connects = STATUS.lock().connects;
+            *STATUS.lock() = MqttStatus { connects, ..Default::default() };
+            session = open_session();
+            continue;
+        }
+        let Some(s) = session.as_mut() else {
+            continue;
+        };
+
+        let result = match message {
+            Some(Message::Connected) => {
+                s.connected = true;
+                // echoes lost with the old connection never arrive
+                s.clearing.clear();
+                let mut status = STATUS.lock();
+                status.connected = true;
+                status.connects += 1;
+                status.last_error = None;
+                drop(status);
+                info!("mqtt: connected");
+                next_diagnostics = Instant::now() + Duration::from_secs(s.settings.diagnostics_interval_secs.into());
+                s.announce(motion)
+            },
+            Some(Message::Disconnected) => {
+                s.connected = false;
+                STATUS.lock().connected = false;
+                warn!("mqtt: disconnected");
+                Ok(())
+            },
+            Some(Message::Error(e)) => {
+                STATUS.lock().last_error = Some(e);
+                Ok(())
+            },
+            // the broker keeps the retained state, so these can wait for the next connect
+            _ if !s.connected => Ok(()),
+            Some(Message::Motion(level)) => s.publish_motion(level),
+            Some(Message::Armed(armed)) => s.publish_armed(armed),
+            Some(Message::Command { name, payload }) => s.handle_command(&name, &payload),
+            Some(Message::Snapshot(jpeg)) => s.publish_snapshot(&jpeg),
+            Some(Message::Button(event_type)) => {
+                let payload = serde_json::json!({ "event_type": event_type }).to_string();
+                s.publish(&s.topics.button(), false, payload.as_bytes())
+            },
+            Some(Message::Diagnostics) | None => {
+                next_diagnostics = Instant::now() + Duration::from_secs(s.settings.diagnostics_interval_secs.into());
+                s.publish_diagnostics()
+            },
+            Some(Message::Reconfigure) => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("mqtt publish: {}", e);
+        }
+    }
+}
+
+pub fn start() -> Result<()> {
+    thread::Builder::new()
+        .name("mqtt".to_owned())
+        .stack_size(16 * 1024)
+        .spawn(run)?;
+    Ok(())
+}
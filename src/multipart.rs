@@ -0,0 +1,213 @@
+// The request an upload makes: its headers, and a body of `head`, the JPEG
+// and `tail`, so the frame is streamed as it is rather than copied into a
+// form first.
+//
+// Nothing here needs ESP-IDF; the tests post a request to a stand-in server
+// on the loopback interface and take the form apart again there.
+
+use std::io::{Cursor, Read};
+
+/// Where the image goes in a `multipart/form-data` upload.
+#[derive(Clone, Copy, Debug)]
+pub struct FormFile<'a> {
+    pub boundary: &'a str,
+    pub field: &'a str,
+    pub filename: &'a str,
+}
+
+/// Everything about one upload but the image bytes.
+#[derive(Clone, Debug)]
+pub struct UploadRequest {
+    pub headers: Vec<(String, String)>,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl UploadRequest {
+    /// The metadata goes into `X-Camera-*` headers, and into form fields too
+    /// when `form` is given; without one the body is the bare JPEG.
+    pub fn new(metadata: &[(&str, String)], extra_headers: &[(String, String)], form: Option<FormFile<'_>>) -> Self {
+        let mut headers: Vec<(String, String)> = metadata
+            .iter()
+            .map(|(name, value)| (format!("X-Camera-{}", name.replace('_', "-")), value.clone()))
+            .collect();
+        headers.extend(extra_headers.iter().cloned());
+        let Some(form) = form else {
+            headers.push(("Content-Type".to_owned(), "image/jpeg".to_owned()));
+            return Self { headers, head: Vec::new(), tail: Vec::new() };
+        };
+
+        headers.push(("Content-Type".to_owned(), format!("multipart/form-data; boundary={}", form.boundary)));
+        let boundary = form.boundary;
+        let mut head = String::new();
+        for (name, value) in metadata {
+            head.push_str(&format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+            ));
+        }
+        head.push_str(&format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: image/jpeg\r\n\r\n",
+            form.field, form.filename,
+        ));
+        let tail = format!("\r\n--{boundary}--\r\n");
+        Self { headers, head: head.into_bytes(), tail: tail.into_bytes() }
+    }
+
+    pub fn header_refs(&self) -> Vec<(&str, &str)> {
+        self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect()
+    }
+
+    pub fn content_length(&self, jpeg: &[u8]) -> u64 {
+        (self.head.len() + jpeg.len() + self.tail.len()) as u64
+    }
+
+    pub fn body<'a>(&'a self, jpeg: &'a [u8]) -> impl Read + 'a {
+        Cursor::new(&self.head).chain(jpeg).chain(Cursor::new(&self.tail))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use super::*;
+
+    /// A JPEG-ish payload with a line that looks like a boundary in it.
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\r\n--ttgo-camera-0000\r\n\0\xff\xd9";
+
+    struct Received {
+        request_line: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Accepts one request, answers `204`, and hands back what came in.
+    fn stand_in() -> (String, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                headers.push((name.to_owned(), value.trim().to_owned()));
+            }
+            let received = Received { request_line: request_line.trim_end().to_owned(), headers, body: Vec::new() };
+            let len: usize = received.header("Content-Length").unwrap().parse().unwrap();
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            Received { body, ..received }
+        });
+        (addr, server)
+    }
+
+    /// Sends `request` the way the HTTP client does: headers, a Content-Length and the streamed body.
+    fn post(addr: &str, request: &UploadRequest, jpeg: &[u8]) -> io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        let mut head = format!("POST /upload HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n", addr, request.content_length(jpeg));
+        for (name, value) in request.header_refs() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        io::copy(&mut request.body(jpeg), &mut stream)?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        Ok(status.trim_end().to_owned())
+    }
+
+    /// Splits a form into (headers, content) parts.
+    fn parts<'a>(body: &'a [u8], boundary: &str) -> Vec<(String, &'a [u8])> {
+        let delimiter = format!("\r\n--{}", boundary);
+        let body = body.strip_prefix(&delimiter.as_bytes()[2..]).expect("starts with the boundary");
+        let mut parts = Vec::new();
+        let mut rest = body;
+        loop {
+            if rest == b"--\r\n" {
+                return parts;
+            }
+            rest = rest.strip_prefix(b"\r\n").expect("a line break after the boundary");
+            let end = rest.windows(delimiter.len()).position(|w| w == delimiter.as_bytes()).expect("a closing boundary");
+            let part = &rest[..end];
+            let split = part.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+            parts.push((String::from_utf8(part[..split].to_vec()).unwrap(), &part[split + 4..]));
+            rest = &rest[end + delimiter.len()..];
+        }
+    }
+
+    fn metadata() -> Vec<(&'static str, String)> {
+        vec![
+            ("device_id", "a1b2c3".to_owned()),
+            ("trigger", "motion".to_owned()),
+            ("timestamp", "2024-05-01T12:00:00.000Z".to_owned()),
+        ]
+    }
+
+    #[test]
+    fn multipart_upload_reaches_the_server_intact() {
+        let (addr, server) = stand_in();
+        let extra = [("Authorization".to_owned(), "Bearer secret".to_owned())];
+        let form = FormFile { boundary: "ttgo-camera-0123456789abcdef", field: "image", filename: "a1b2c3-motion.jpg" };
+        let request = UploadRequest::new(&metadata(), &extra, Some(form));
+        assert_eq!(post(&addr, &request, JPEG).unwrap(), "HTTP/1.1 204 No Content");
+
+        let received = server.join().unwrap();
+        assert_eq!(received.request_line, "POST /upload HTTP/1.1");
+        assert_eq!(received.header("Authorization"), Some("Bearer secret"));
+        assert_eq!(received.header("X-Camera-Device-Id"), Some("a1b2c3"));
+        assert_eq!(received.header("X-Camera-Timestamp"), Some("2024-05-01T12:00:00.000Z"));
+        assert_eq!(received.header("Content-Type"), Some("multipart/form-data; boundary=ttgo-camera-0123456789abcdef"));
+        assert_eq!(received.body.len() as u64, request.content_length(JPEG));
+
+        let parts = parts(&received.body, form.boundary);
+        assert_eq!(parts.len(), 4);
+        for ((name, value), (headers, content)) in metadata().iter().zip(&parts) {
+            assert_eq!(headers, &format!("Content-Disposition: form-data; name=\"{}\"", name));
+            assert_eq!(*content, value.as_bytes());
+        }
+        let (headers, content) = &parts[3];
+        assert_eq!(
+            headers,
+            "Content-Disposition: form-data; name=\"image\"; filename=\"a1b2c3-motion.jpg\"\r\nContent-Type: image/jpeg"
+        );
+        assert_eq!(*content, JPEG);
+    }
+
+    #[test]
+    fn raw_upload_is_the_bare_jpeg() {
+        let (addr, server) = stand_in();
+        let request = UploadRequest::new(&metadata(), &[], None);
+        post(&addr, &request, JPEG).unwrap();
+
+        let received = server.join().unwrap();
+        assert_eq!(received.header("Content-Type"), Some("image/jpeg"));
+        assert_eq!(received.header("X-Camera-Trigger"), Some("motion"));
+        assert_eq!(received.body, JPEG);
+    }
+
+    #[test]
+    fn extra_headers_come_before_the_content_type() {
+        let extra = [("X-Camera-Trigger".to_owned(), "override".to_owned())];
+        let request = UploadRequest::new(&metadata()[..2], &extra, None);
+        let names: Vec<&str> = request.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["X-Camera-device-id", "X-Camera-trigger", "X-Camera-Trigger", "Content-Type"]);
+    }
+}
@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Result};
+use embedded_svc::wifi::AccessPointInfo;
+use serde::{Deserialize, Serialize};
+
+use crate::settings::{SETTINGS, WIFI_NETWORKS};
+
+pub const MAX_KNOWN_NETWORKS: usize = 8;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    #[serde(default)]
+    pub psk: String,
+    /// Higher wins, RSSI only breaks ties between equal priorities.
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// What the API hands out, the PSK never leaves the device.
+#[derive(Clone, Debug, Serialize)]
+pub struct NetworkSummary<'a> {
+    pub ssid: &'a str,
+    pub priority: u8,
+    pub has_psk: bool,
+}
+
+impl KnownNetwork {
+    pub fn summary(&self) -> NetworkSummary<'_> {
+        NetworkSummary {
+            ssid: &self.ssid,
+            priority: self.priority,
+            has_psk: !self.psk.is_empty(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Candidate {
+    pub network: KnownNetwork,
+    pub ap: AccessPointInfo,
+}
+
+/// Pairs scan results with known networks, best first: by priority, then by signal strength.
+pub fn rank_candidates(scan: &[AccessPointInfo], known: &[KnownNetwork]) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = scan
+        .iter()
+        .filter_map(|ap| {
+            known
+                .iter()
+                .find(|n| ap.ssid.as_str() == n.ssid)
+                .map(|n| Candidate { network: n.clone(), ap: ap.clone() })
+        })
+        .collect();
+    candidates.sort_by(|a, b| {
+        b.network.priority.cmp(&a.network.priority)
+            .then(b.ap.signal_strength.cmp(&a.ap.signal_strength))
+    });
+    candidates
+}
+
+pub fn load_networks() -> Result<Vec<KnownNetwork>> {
+    SETTINGS.lock().get(&WIFI_NETWORKS)
+}
+
+/// Adds the network, or replaces the entry with the same SSID.
+pub fn upsert_network(network: KnownNetwork) -> Result<()> {
+    if network.ssid.is_empty() || network.ssid.len() > 32 {
+        return Err(anyhow!("ssid must be 1 to 32 bytes"));
+    }
+    if network.psk.len() > 64 {
+        return Err(anyhow!("psk must be at most 64 bytes"));
+    }
+    SETTINGS.lock().update(&WIFI_NETWORKS, |networks| {
+        match networks.iter_mut().find(|n| n.ssid == network.ssid) {
+            Some(existing) => *existing = network,
+            None if networks.len() >= MAX_KNOWN_NETWORKS => {
+                return Err(anyhow!("at most {} networks can be stored", MAX_KNOWN_NETWORKS));
+            },
+            None => networks.push(network),
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+pub fn remove_network(ssid: &str) -> Result<bool> {
+    let mut removed = false;
+    SETTINGS.lock().update(&WIFI_NETWORKS, |networks| {
+        let before = networks.len();
+        networks.retain(|n| n.ssid != ssid);
+        removed = networks.len() != before;
+        Ok(())
+    })?;
+    Ok(removed)
+}
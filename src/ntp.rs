@@ -1,72 +1,198 @@
+// Time sources: NTP servers from the settings and DHCP, and an HTTP `Date`
+// header as the last resort. The exchange itself is in sntp.rs.
+
+use crate::http::{self, Body, HttpOptions};
 use crate::preludes::*;
-use byteorder::{BigEndian, ReadBytesExt};
-use esp_idf_sys::{settimeofday, time_t, timeval};
-use std::io::{Cursor, Seek, SeekFrom};
-use std::net::UdpSocket;
+use crate::settings::{Key, SETTINGS};
+use crate::sntp::{query, Sample};
+use chrono::DateTime;
+use embedded_svc::http::Method;
+use esp_idf_sys::{
+    esp_sntp_getserver, esp_sntp_servermode_dhcp, lwip_ip_addr_type_IPADDR_TYPE_V4,
+    settimeofday, time_t, timeval, CONFIG_LWIP_SNTP_MAX_SERVERS,
+};
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, UdpSocket};
 use std::ptr::null;
-use std::thread;
-use std::time::Duration;
-use anyhow::Result;
-
-fn unpack_ntp_data(buffer: &[u8; 48]) -> u64 {
-    let mut reader = Cursor::new(buffer);
-    reader.seek(SeekFrom::Current(40)).unwrap();
-    let ntp_second = reader.read_u32::<BigEndian>().unwrap();
-    u64::from(ntp_second)
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+
+pub const NTP_SETTINGS: Key<NtpSettings> = Key::new("ntp", NtpSettings::default);
+
+/// Steps the system clock by `offset_micros`.
+pub fn set_system_time(offset_micros: i64) -> Result<SystemTime> {
+    let now = SystemTime::now();
+    let offset = Duration::from_micros(offset_micros.unsigned_abs());
+    let corrected = if offset_micros >= 0 { now + offset } else { now - offset };
+    let since_unix = corrected.duration_since(UNIX_EPOCH)?;
+    let time = timeval {
+        tv_sec: since_unix.as_secs() as time_t,
+        tv_usec: since_unix.subsec_micros() as _,
+    };
+    esp!(unsafe { settimeofday(&time, null()) })?;
+    Ok(corrected)
 }
 
-pub fn request(client: &UdpSocket, server: &str) -> Result<u64> {
-    client.connect(format!("{server}:123"))?;
-    let mut request_data = vec![0; 48];
-    request_data[0] = 0x1b;
-    client.send(&request_data)?;
-    let mut buf = [0; 48];
-    client.recv(&mut buf)?;
-    let ntp_second = unpack_ntp_data(&buf);
-    let unix_second = ntp_second - 2208988800;
-
-    Ok(unix_second)
+/// Where the time comes from, in the order they are tried.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NtpSettings {
+    /// Host names or addresses, tried after any servers from DHCP.
+    pub servers: Vec<String>,
+    /// Use the servers handed out in DHCP option 42.
+    pub use_dhcp: bool,
+    /// URL of a local web server whose `Date` header is used when UDP/123 is blocked.
+    pub http_fallback: Option<String>,
 }
 
-pub fn ntp_sync() -> Result<()> {
+impl Default for NtpSettings {
+    fn default() -> Self {
+        Self {
+            servers: DEFAULT_SERVERS.iter().map(|s| (*s).to_owned()).collect(),
+            use_dhcp: true,
+            http_fallback: None,
+        }
+    }
+}
+
+impl NtpSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.servers.len() > MAX_SERVERS {
+            return Err(anyhow!("at most {} servers", MAX_SERVERS));
+        }
+        for server in &self.servers {
+            if server.is_empty() || server.len() > 64 || server.contains(|c: char| c.is_whitespace() || c == ':') {
+                return Err(anyhow!("{:?} is not a host name or address", server));
+            }
+        }
+        if let Some(url) = &self.http_fallback {
+            if !(url.starts_with("http://") || url.starts_with("https://")) {
+                return Err(anyhow!("http_fallback must be an http:// or https:// URL"));
+            }
+        }
+        if self.servers.is_empty() && !self.use_dhcp && self.http_fallback.is_none() {
+            return Err(anyhow!("no time source left"));
+        }
+        Ok(())
+    }
+}
+
+pub fn load_settings() -> Result<NtpSettings> {
+    SETTINGS.lock().get(&NTP_SETTINGS)
+}
+
+pub fn store_settings(settings: &NtpSettings) -> Result<()> {
+    settings.validate()?;
+    SETTINGS.lock().set(&NTP_SETTINGS, settings)
+}
+
+/// Lets lwIP keep the servers from DHCP option 42, has to run before the first lease.
+pub fn enable_dhcp_servers() {
+    unsafe { esp_sntp_servermode_dhcp(true) };
+}
+
+/// Servers handed out by DHCP on the current lease.
+pub fn dhcp_servers() -> Vec<Ipv4Addr> {
+    (0..CONFIG_LWIP_SNTP_MAX_SERVERS as u8)
+        .filter_map(|idx| {
+            let addr = unsafe { esp_sntp_getserver(idx).as_ref() }?;
+            if u32::from(addr.type_) != lwip_ip_addr_type_IPADDR_TYPE_V4 {
+                return None;
+            }
+            // lwIP keeps addresses in network order
+            let ip = Ipv4Addr::from(u32::from_be(unsafe { addr.u_addr.ip4.addr }));
+            (!ip.is_unspecified()).then_some(ip)
+        })
+        .collect()
+}
+
+/// Estimates the offset from the `Date` header of `url`. Only good to about a
+/// second, but works wherever plain HTTP does.
+pub fn http_date_sample(url: &str) -> Result<Sample> {
+    let mut client = http::create_client(HttpOptions {
+        timeout: Duration::from_secs(5),
+        ..Default::default()
+    })?;
+    let sent = Instant::now();
+    let date = client.request(Method::Head, url, &[], Body::Empty, |r| Ok(r.header("Date").map(str::to_owned)))?;
+    let received = SystemTime::now();
+    let round_trip = sent.elapsed();
+
+    let date = date.ok_or_else(|| anyhow!("{} sent no Date header", url))?;
+    let server = DateTime::parse_from_rfc2822(&date)?;
+    // the header is truncated to the second, so aim for the middle of it
+    let server_micros = server.timestamp() * 1_000_000 + 500_000;
+    let local_micros = received.duration_since(UNIX_EPOCH)?.as_micros() as i64;
+    let delay_micros = round_trip.as_micros() as i64;
+    Ok(Sample {
+        offset_micros: server_micros + delay_micros / 2 - local_micros,
+        delay_micros,
+        // no stratum to speak of, report it as the least trustworthy one
+        stratum: 16,
+    })
+}
+
+/// A sample that was used to set the clock, and where it came from.
+#[derive(Clone, Debug)]
+pub struct Synced {
+    pub sample: Sample,
+    pub source: String,
+}
+
+/// Asks each server in turn and sets the clock from the first valid reply,
+/// falling back to the HTTP `Date` header when none answers.
+pub fn ntp_sync() -> Result<Synced> {
+    let settings = load_settings().unwrap_or_else(|e| {
+        error!("ntp settings: {}", e);
+        NtpSettings::default()
+    });
+
+    let mut servers: Vec<String> = Vec::new();
+    if settings.use_dhcp {
+        servers.extend(dhcp_servers().iter().map(Ipv4Addr::to_string));
+    }
+    for server in settings.servers {
+        if !servers.contains(&server) {
+            servers.push(server);
+        }
+    }
+
     let client = UdpSocket::bind("0.0.0.0:0")?;
     client.set_read_timeout(Some(Duration::from_secs(3)))?;
 
-    if let Some(res) = {
-        let mut res = None;
-        for s in NTP_SERVERS.into_iter() {
-            info!("Trying to sync time with {}...", s);
-            match request(&client, s) {
-                Ok(t) => {
-                    res = Some(t);
-                    break;
-                }
-                Err(e) => {
-                    // no more processes means timed-out
-                    error!("Failed to sync time with {}: {}", s, e);
-                }
-            }
+    for s in servers {
+        info!("Trying to sync time with {}...", s);
+        match query(&client, &s) {
+            Ok(sample) => return apply_sample(sample, s),
+            Err(e) => {
+                error!("Failed to sync time with {}: {}", s, e);
+            },
         }
-        res
-    } {
-        unsafe {
-            let time = timeval {
-                tv_sec: res as time_t,
-                tv_usec: 0,
-            };
-            settimeofday(&time, null());
+    }
+
+    if let Some(url) = settings.http_fallback {
+        info!("Trying to sync time from the Date header of {}...", url);
+        match http_date_sample(&url) {
+            Ok(sample) => return apply_sample(sample, url),
+            Err(e) => error!("Failed to sync time from {}: {}", url, e),
         }
-        info!("Got time: {:?}", &res);
-    } else {
-        error!("Failed to sync time from NTP servers, resetting in 3s...");
-        thread::sleep(Duration::from_secs(3));
     }
 
-    drop(client);
-    Ok(())
+    Err(anyhow!("no time source gave a usable reply"))
 }
 
-static NTP_SERVERS: [&str; 5] = [
+fn apply_sample(sample: Sample, source: String) -> Result<Synced> {
+    let time = set_system_time(sample.offset_micros)?;
+    info!(
+        "Got time from {} (stratum {}): offset {}us, delay {}us, now {:?}",
+        source, sample.stratum, sample.offset_micros, sample.delay_micros, time,
+    );
+    Ok(Synced { sample, source })
+}
+
+const MAX_SERVERS: usize = 8;
+
+static DEFAULT_SERVERS: [&str; 5] = [
     "time.apple.com",
     "ntp.aliyun.com",
     "time.windows.com",
@@ -0,0 +1,568 @@
+// Firmware updates into the inactive OTA slot.
+//
+// Images are streamed into the slot the bootloader is not using, checked
+// against their SHA-256 on the way, and only then made the boot slot. A new
+// image boots as "pending verify": `confirm_when_healthy` marks it valid once
+// Wi-Fi and the camera work, and rolls back to the previous slot otherwise.
+// Resets before that point roll back in the bootloader.
+//
+// Images either get uploaded to `/api/ota`, or are pulled: the `ota_pull` task
+// polls a manifest signed with the fleet key and installs newer versions
+// inside the maintenance window.
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::thread;
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use chrono::Timelike;
+use embedded_svc::http::Headers;
+use embedded_svc::ota::SlotState;
+use esp_camera_rs::Camera;
+use esp_idf_svc::http::server::EspHttpServer;
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate};
+use esp_idf_sys::esp_ota_get_next_update_partition;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::api::{read_json, reply_error, reply_json, ApiRequest};
+use crate::clock;
+use crate::commands::{self, Command};
+use crate::firmware::{self, AppInfo, ImageCheck, MaintenanceWindow, Manifest, Version};
+use crate::http::{self, Body, HttpOptions};
+use crate::link;
+use crate::mdns::FIRMWARE_VERSION;
+use crate::power;
+use crate::preludes::*;
+use crate::provisioning;
+use crate::settings::{Key, SETTINGS};
+use crate::timezone;
+
+pub const OTA_SETTINGS: Key<OtaSettings> = Key::new("ota", OtaSettings::default);
+
+const CHUNK_LEN: usize = 4096;
+const MIN_TOKEN_LEN: usize = 12;
+/// How long a new image gets to bring up Wi-Fi and the camera.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(180);
+const HEALTH_CHECK: Duration = Duration::from_secs(5);
+const MAX_MANIFEST_LEN: usize = 4 * 1024;
+const MIN_CHECK_INTERVAL_SECS: u32 = 5 * 60;
+/// Lets Wi-Fi and the clock settle before the first check after boot.
+const FIRST_CHECK_DELAY: Duration = Duration::from_secs(120);
+/// How often a pending update looks at the clock, and the task at its settings.
+const PULL_TICK: Duration = Duration::from_secs(60);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+static UPDATING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref PROGRESS: Mutex<Progress> = Mutex::new(Progress::Idle);
+    static ref PULL: Mutex<PullState> = Mutex::new(PullState::default());
+    /// `true` installs whatever the check finds without waiting for the window.
+    static ref CHECKS: (flume::Sender<bool>, flume::Receiver<bool>) = flume::bounded(1);
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtaSettings {
+    /// SHA-256 of the update token; uploads are refused until one is set.
+    pub token_sha256: Option<String>,
+    pub pull: PullSettings,
+}
+
+/// Where to look for new firmware, and when to install it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PullSettings {
+    /// http(s) URL of the JSON `Manifest`, nothing is pulled without one.
+    pub manifest_url: Option<String>,
+    /// Hex Ed25519 key the manifests must be signed with.
+    pub public_key: Option<String>,
+    pub check_interval_secs: u32,
+    /// Local time in which updates are installed, any time if unset.
+    pub window: Option<MaintenanceWindow>,
+}
+
+impl Default for PullSettings {
+    fn default() -> Self {
+        Self { manifest_url: None, public_key: None, check_interval_secs: 6 * 3600, window: None }
+    }
+}
+
+impl PullSettings {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(url) = &self.manifest_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(anyhow!("manifest_url must be an http(s) URL"));
+            }
+            if self.public_key.is_none() {
+                return Err(anyhow!("a public_key is needed to check the manifests"));
+            }
+        }
+        if let Some(key) = &self.public_key {
+            let mut bytes = [0u8; 32];
+            hex::decode_to_slice(key.trim(), &mut bytes).map_err(|_| anyhow!("public_key must be 64 hex digits"))?;
+        }
+        if self.check_interval_secs < MIN_CHECK_INTERVAL_SECS {
+            return Err(anyhow!("check_interval_secs must be at least {}", MIN_CHECK_INTERVAL_SECS));
+        }
+        if self.window.is_some_and(|w| !w.is_valid()) {
+            return Err(anyhow!("the window needs two different minutes below {}", 24 * 60));
+        }
+        Ok(())
+    }
+}
+
+pub fn load() -> Result<OtaSettings> {
+    SETTINGS.lock().get(&OTA_SETTINGS)
+}
+
+fn store(settings: &OtaSettings) -> Result<()> {
+    SETTINGS.lock().set(&OTA_SETTINGS, settings)
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum Progress {
+    Idle,
+    Writing { written: u64, total: Option<u64> },
+    /// Boots into `version` on the next restart.
+    Installed { version: String },
+    Failed { error: String },
+}
+
+pub fn progress() -> Progress {
+    PROGRESS.lock().clone()
+}
+
+#[derive(Default)]
+struct PullState {
+    last_check: Option<Instant>,
+    last_error: Option<String>,
+    /// Newer than the running firmware and correctly signed.
+    available: Option<Manifest>,
+    waiting_for_window: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PullStatus {
+    pub enabled: bool,
+    pub checked_secs_ago: Option<u64>,
+    pub last_error: Option<String>,
+    pub available: Option<String>,
+    pub waiting_for_window: bool,
+}
+
+pub fn pull_status() -> PullStatus {
+    let enabled = load().is_ok_and(|s| s.pull.manifest_url.is_some());
+    let state = PULL.lock();
+    PullStatus {
+        enabled,
+        checked_secs_ago: state.last_check.map(|t| t.elapsed().as_secs()),
+        last_error: state.last_error.clone(),
+        available: state.available.as_ref().map(|m| m.version.clone()),
+        waiting_for_window: state.waiting_for_window,
+    }
+}
+
+/// Asks the pull task to look for an update now; `install_now` skips the window.
+pub fn request_check(install_now: bool) {
+    let _ = CHECKS.0.try_send(install_now);
+}
+
+/// Only one update at a time, released on drop.
+struct UpdateGuard;
+
+impl UpdateGuard {
+    fn take() -> Result<Self> {
+        if UPDATING.swap(true, Ordering::AcqRel) {
+            return Err(anyhow!("an update is already running"));
+        }
+        Ok(Self)
+    }
+}
+
+impl Drop for UpdateGuard {
+    fn drop(&mut self) {
+        UPDATING.store(false, Ordering::Release);
+    }
+}
+
+fn update_slot_size() -> Result<u64> {
+    let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+    if partition.is_null() {
+        return Err(anyhow!("no OTA slot to update, is the partition table OTA capable?"));
+    }
+    Ok(unsafe { (*partition).size }.into())
+}
+
+fn stream(
+    update: &mut EspOtaUpdate<'_>,
+    mut check: ImageCheck,
+    expected: &[u8; 32],
+    read: &mut dyn FnMut(&mut [u8]) -> Result<usize>,
+) -> Result<AppInfo> {
+    let total = check.expected_len();
+    let mut buf = vec![0u8; CHUNK_LEN];
+    loop {
+        let n = read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        check.update(&buf[..n])?;
+        update.write_all(&buf[..n])?;
+        *PROGRESS.lock() = Progress::Writing { written: check.written(), total };
+    }
+    Ok(check.finish(expected)?)
+}
+
+/// Writes an image into the update slot and makes it the boot slot if it checks out.
+///
+/// `read` fills the buffer like `std::io::Read` and returns 0 at the end.
+pub fn install(len: Option<u64>, expected: &[u8; 32], read: &mut dyn FnMut(&mut [u8]) -> Result<usize>) -> Result<AppInfo> {
+    let _guard = UpdateGuard::take()?;
+    let _busy = power::busy();
+    *PROGRESS.lock() = Progress::Writing { written: 0, total: len };
+
+    let result = (|| -> Result<AppInfo> {
+        let check = ImageCheck::new(len, update_slot_size()?)?;
+        let mut ota = EspOta::new()?;
+        let mut update = ota.initiate_update()?;
+        match stream(&mut update, check, expected, read) {
+            Ok(info) => {
+                update.complete()?;
+                Ok(info)
+            },
+            Err(e) => {
+                let _ = update.abort();
+                Err(e)
+            },
+        }
+    })();
+
+    *PROGRESS.lock() = match &result {
+        Ok(info) => {
+            info!("ota: installed {} ({})", info.version, info.project_name);
+            Progress::Installed { version: info.version.clone() }
+        },
+        Err(e) => {
+            error!("ota: {}", e);
+            Progress::Failed { error: e.to_string() }
+        },
+    };
+    result
+}
+
+/// Restarts into the new image once the caller has had time to answer.
+pub fn reboot() -> Result<()> {
+    commands::execute(Command::Reboot).map(|_| ())
+}
+
+fn token_digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Compares without giving away how much of it matched.
+fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn check_token(token: Option<&str>) -> Result<(), (u16, &'static str)> {
+    let stored = load().ok().and_then(|s| s.token_sha256).ok_or((403, "set an update token first"))?;
+    match token {
+        Some(token) if same(&token_digest(token), &stored) => Ok(()),
+        _ => Err((401, "wrong or missing update token")),
+    }
+}
+
+fn bearer<'r>(request: &'r ApiRequest<'_, '_>) -> Option<&'r str> {
+    request.header("Authorization")?.strip_prefix("Bearer ")
+}
+
+#[derive(Serialize)]
+struct SlotReport {
+    label: String,
+    state: String,
+    version: Option<String>,
+}
+
+fn running_slot() -> Result<SlotReport> {
+    let slot = EspOta::new()?.get_running_slot()?;
+    Ok(SlotReport {
+        label: slot.label.to_string(),
+        state: format!("{:?}", slot.state),
+        version: slot.firmware.map(|f| f.version.to_string()),
+    })
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    current: Option<String>,
+    token: String,
+}
+
+pub fn register_handlers(server: &mut EspHttpServer) -> Result<()> {
+    server.fn_handler("/api/ota", Method::Get, |request| {
+        reply_json(request, 200, &serde_json::json!({
+            "firmware": FIRMWARE_VERSION,
+            "running": running_slot().ok(),
+            "progress": progress(),
+            "token_set": load()?.token_sha256.is_some(),
+            "pull": pull_status(),
+        }))
+    })?;
+
+    // the raw image as the body, its hex SHA-256 in X-Firmware-SHA256
+    server.fn_handler("/api/ota", Method::Post, |mut request| {
+        if let Err((status, message)) = check_token(bearer(&request)) {
+            return reply_error(request, status, message);
+        }
+        let expected = match request.header("X-Firmware-SHA256").map(firmware::parse_sha256) {
+            Some(Ok(digest)) => digest,
+            Some(Err(e)) => return reply_error(request, 400, &e.to_string()),
+            None => return reply_error(request, 400, "X-Firmware-SHA256 header is required"),
+        };
+        let Some(len) = request.content_len() else {
+            return reply_error(request, 411, "Content-Length is required");
+        };
+
+        let result = install(Some(len), &expected, &mut |buf| {
+            request.read(buf).map_err(|e| anyhow!("reading the image: {:?}", e))
+        });
+        match result {
+            Ok(info) => {
+                reply_json(request, 200, &serde_json::json!({ "ok": true, "version": info.version, "rebooting": true }))?;
+                reboot()?;
+                Ok(())
+            },
+            Err(e) => reply_error(request, 400, &e.to_string()),
+        }
+    })?;
+
+    // the first token needs someone at the camera: the setup portal only runs
+    // without Wi-Fi credentials or after a long button press; changing it needs
+    // the current one
+    server.fn_handler("/api/ota/token", Method::Post, |mut request| {
+        let body: TokenRequest = match read_json(&mut request) {
+            Ok(b) => b,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        let mut settings = load()?;
+        if settings.token_sha256.is_some() {
+            if let Err((status, message)) = check_token(body.current.as_deref()) {
+                return reply_error(request, status, message);
+            }
+        } else if !provisioning::is_active() {
+            return reply_error(request, 403, "the first token can only be set while the setup portal runs, hold the button to start it");
+        }
+        if body.token.len() < MIN_TOKEN_LEN {
+            return reply_error(request, 400, &format!("the token needs at least {} characters", MIN_TOKEN_LEN));
+        }
+        settings.token_sha256 = Some(token_digest(&body.token));
+        store(&settings)?;
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    // the pull settings decide what gets installed, so they need the token too
+    server.fn_handler("/api/ota/pull", Method::Get, |request| {
+        if let Err((status, message)) = check_token(bearer(&request)) {
+            return reply_error(request, status, message);
+        }
+        reply_json(request, 200, &load()?.pull)
+    })?;
+
+    server.fn_handler("/api/ota/pull", Method::Post, |mut request| {
+        if let Err((status, message)) = check_token(bearer(&request)) {
+            return reply_error(request, status, message);
+        }
+        let pull: PullSettings = match read_json(&mut request) {
+            Ok(p) => p,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = pull.validate() {
+            return reply_error(request, 400, &e.to_string());
+        }
+        let mut settings = load()?;
+        settings.pull = pull;
+        store(&settings)?;
+        request_check(false);
+        reply_json(request, 200, &settings.pull)
+    })?;
+
+    server.fn_handler("/api/ota/check", Method::Post, |mut request| {
+        if let Err((status, message)) = check_token(bearer(&request)) {
+            return reply_error(request, status, message);
+        }
+        let body: CheckRequest = match read_json(&mut request) {
+            Ok(b) => b,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        request_check(body.now);
+        reply_json(request, 202, &serde_json::json!({ "ok": true, "now": body.now }))
+    })?;
+
+    Ok(())
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CheckRequest {
+    now: bool,
+}
+
+/// The manifest if it is signed and newer than the running firmware.
+fn check(settings: &PullSettings) -> Result<Option<(Manifest, [u8; 32])>> {
+    let (Some(url), Some(key)) = (&settings.manifest_url, &settings.public_key) else {
+        return Ok(None);
+    };
+    let body = http::create_default_client()?.get(url, &[("Accept", "application/json")], MAX_MANIFEST_LEN)?;
+    let running = Version::parse(FIRMWARE_VERSION).ok_or_else(|| anyhow!("running version {:?} is not a version", FIRMWARE_VERSION))?;
+    let found = firmware::evaluate_manifest(&body, key, &running)?;
+    match &found {
+        Some((manifest, _)) => info!("ota: {} is available", manifest.version),
+        None => debug!("ota: {} is current", FIRMWARE_VERSION),
+    }
+    Ok(found)
+}
+
+fn download(manifest: &Manifest, digest: &[u8; 32]) -> Result<AppInfo> {
+    let mut client = http::create_client(HttpOptions { timeout: DOWNLOAD_TIMEOUT, ..Default::default() })?;
+    client.request(Method::Get, &manifest.url, &[], Body::Empty, |response| {
+        let len = response.content_length();
+        Ok(install(len, digest, &mut |buf| Ok(response.read(buf)?)))
+    })?
+}
+
+/// Unknown while the clock has not been set, which keeps updates waiting.
+fn in_window(window: Option<MaintenanceWindow>) -> bool {
+    let Some(window) = window else {
+        return true;
+    };
+    if !clock::is_synced() {
+        return false;
+    }
+    let now = timezone::now_local();
+    window.contains((now.hour() * 60 + now.minute()) as u16)
+}
+
+fn run_pull() {
+    let mut next_check = Instant::now() + FIRST_CHECK_DELAY;
+    let mut pending: Option<(Manifest, [u8; 32])> = None;
+
+    loop {
+        let wait = next_check.saturating_duration_since(Instant::now()).min(PULL_TICK);
+        let install_now = match CHECKS.1.recv_timeout(wait) {
+            Ok(install_now) => {
+                next_check = Instant::now();
+                install_now
+            },
+            Err(flume::RecvTimeoutError::Timeout) => false,
+            Err(flume::RecvTimeoutError::Disconnected) => return,
+        };
+
+        let settings = load().map(|s| s.pull).unwrap_or_default();
+        if settings.manifest_url.is_none() {
+            pending = None;
+            *PULL.lock() = PullState::default();
+            continue;
+        }
+
+        if Instant::now() >= next_check {
+            next_check = Instant::now() + Duration::from_secs(settings.check_interval_secs.into());
+            if link::is_connected() {
+                let result = check(&settings);
+                let mut state = PULL.lock();
+                state.last_check = Some(Instant::now());
+                match result {
+                    Ok(found) => {
+                        state.last_error = None;
+                        pending = found;
+                    },
+                    Err(e) => {
+                        error!("ota: checking {:?}: {}", settings.manifest_url, e);
+                        state.last_error = Some(e.to_string());
+                    },
+                }
+                state.available = pending.as_ref().map(|(manifest, _)| manifest.clone());
+            }
+        }
+
+        let Some((manifest, digest)) = &pending else {
+            continue;
+        };
+        let ready = install_now || in_window(settings.window);
+        PULL.lock().waiting_for_window = !ready;
+        if !ready {
+            continue;
+        }
+        info!("ota: downloading {} from {}", manifest.version, manifest.url);
+        match download(manifest, digest) {
+            Ok(_) => {
+                if let Err(e) = reboot() {
+                    error!("ota: {}", e);
+                }
+                return;
+            },
+            Err(e) => {
+                // the next check starts over, the server may have fixed the image by then
+                PULL.lock().last_error = Some(e.to_string());
+                pending = None;
+            },
+        }
+    }
+}
+
+/// Starts polling the manifest server, it stays idle while none is configured.
+pub fn start() -> Result<()> {
+    thread::Builder::new()
+        .name("ota_pull".to_owned())
+        .stack_size(16 * 1024)
+        .spawn(run_pull)?;
+    Ok(())
+}
+
+fn camera_works(camera: &StdMutex<Camera>) -> bool {
+    camera.lock().is_ok_and(|camera| camera.get_framebuffer().is_some())
+}
+
+fn confirm(camera: Arc<StdMutex<Camera>>) {
+    let deadline = Instant::now() + HEALTH_TIMEOUT;
+    let mut camera_ok = false;
+    while Instant::now() < deadline {
+        camera_ok = camera_ok || camera_works(&camera);
+        if camera_ok && link::is_connected() {
+            match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+                Ok(()) => info!("ota: new firmware {} marked valid", FIRMWARE_VERSION),
+                Err(e) => error!("ota: marking the firmware valid: {}", e),
+            }
+            return;
+        }
+        thread::sleep(HEALTH_CHECK);
+    }
+
+    error!("ota: no {} after {:?}, rolling back", if camera_ok { "Wi-Fi" } else { "camera" }, HEALTH_TIMEOUT);
+    match EspOta::new() {
+        Ok(mut ota) => error!("ota: rollback failed: {}", ota.mark_running_slot_invalid_and_reboot()),
+        Err(e) => error!("ota: rollback failed: {}", e),
+    }
+}
+
+/// Starts the health check if this is the first boot of a new image.
+pub fn confirm_when_healthy(camera: Arc<StdMutex<Camera>>) -> Result<()> {
+    let state = EspOta::new()?.get_running_slot()?.state;
+    if state != SlotState::Unverified {
+        return Ok(());
+    }
+    info!("ota: first boot of {}, waiting for Wi-Fi and the camera", FIRMWARE_VERSION);
+    thread::Builder::new()
+        .name("ota_confirm".to_owned())
+        .stack_size(8 * 1024)
+        .spawn(move || confirm(camera))?;
+    Ok(())
+}
@@ -0,0 +1,363 @@
+// Persistent queue of outbound messages.
+//
+// Each message is one file named after its sequence number, holding a JSON
+// header and the raw payload. Files are written under a temporary name and
+// renamed into place, so a power cut leaves either the whole message or none
+// of it. The queue is plain `std` file handling, and its tests run against a
+// scratch directory on the build machine.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+const EXTENSION: &str = "msg";
+const TEMP_EXTENSION: &str = "tmp";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Upload,
+    Webhook,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub kind: Kind,
+    /// Whatever the sender needs to rebuild the request.
+    pub meta: serde_json::Value,
+    pub created_unix: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub id: u64,
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct OutboxStats {
+    pub depth: usize,
+    /// Flash taken, in whole clusters.
+    pub bytes: u64,
+    pub max_bytes: u64,
+    /// Messages dropped to make room since boot.
+    pub evicted: u32,
+}
+
+pub struct Outbox {
+    dir: PathBuf,
+    max_bytes: u64,
+    /// Files take whole clusters of this size, and `max_bytes` counts clusters.
+    cluster_bytes: u64,
+    next_id: u64,
+    evicted: u32,
+}
+
+impl Outbox {
+    /// Opens the queue in `dir`, dropping anything a power cut left half written.
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64, cluster_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let mut next_id = 1;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|e| e.to_str()) {
+                Some(TEMP_EXTENSION) => fs::remove_file(&path)?,
+                Some(EXTENSION) => {
+                    if let Some(id) = id_of(&path) {
+                        next_id = next_id.max(id + 1);
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(Self { dir, max_bytes, cluster_bytes, next_id, evicted: 0 })
+    }
+
+    fn on_flash(&self, len: u64) -> u64 {
+        let cluster = self.cluster_bytes.max(1);
+        len.div_ceil(cluster) * cluster
+    }
+
+    fn path_for(&self, id: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{:010}.{}", id, extension))
+    }
+
+    /// Ids and the flash their files take, oldest first.
+    fn entries(&self) -> Result<Vec<(u64, u64)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+                continue;
+            }
+            if let Some(id) = id_of(&path) {
+                entries.push((id, self.on_flash(entry.metadata()?.len())));
+            }
+        }
+        entries.sort_unstable();
+        Ok(entries)
+    }
+
+    pub fn stats(&self) -> Result<OutboxStats> {
+        let entries = self.entries()?;
+        Ok(OutboxStats {
+            depth: entries.len(),
+            bytes: entries.iter().map(|(_, len)| len).sum(),
+            max_bytes: self.max_bytes,
+            evicted: self.evicted,
+        })
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.entries()?.is_empty())
+    }
+
+    /// Appends a message, evicting the oldest ones until it fits.
+    pub fn push(&mut self, header: &Header, payload: &[u8]) -> Result<u64> {
+        let header_json = serde_json::to_vec(header)?;
+        let len = self.on_flash((4 + header_json.len() + payload.len()) as u64);
+        if len > self.max_bytes {
+            return Err(anyhow!("message of {} bytes exceeds the {} byte outbox", len, self.max_bytes));
+        }
+
+        let entries = self.entries()?;
+        let mut used: u64 = entries.iter().map(|(_, len)| len).sum();
+        for (id, size) in entries {
+            if used + len <= self.max_bytes {
+                break;
+            }
+            self.remove(id)?;
+            self.evicted += 1;
+            used -= size;
+        }
+
+        let id = self.next_id;
+        let temp = self.path_for(id, TEMP_EXTENSION);
+        {
+            let mut file = File::create(&temp)?;
+            file.write_all(&(header_json.len() as u32).to_le_bytes())?;
+            file.write_all(&header_json)?;
+            file.write_all(payload)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp, self.path_for(id, EXTENSION))?;
+        self.next_id += 1;
+        Ok(id)
+    }
+
+    /// The oldest message; unreadable ones are dropped on the way.
+    pub fn oldest(&mut self) -> Result<Option<Entry>> {
+        for (id, _) in self.entries()? {
+            match self.read(id) {
+                Ok(entry) => return Ok(Some(entry)),
+                Err(e) => {
+                    error!("outbox: dropping unreadable message {}: {}", id, e);
+                    self.remove(id)?;
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(&self, id: u64) -> Result<Entry> {
+        let mut file = File::open(self.path_for(id, EXTENSION))?;
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len)?;
+        let mut header_json = vec![0u8; u32::from_le_bytes(len) as usize];
+        file.read_exact(&mut header_json)?;
+        let header = serde_json::from_slice(&header_json)?;
+        let mut payload = Vec::new();
+        file.read_to_end(&mut payload)?;
+        Ok(Entry { id, header, payload })
+    }
+
+    pub fn remove(&mut self, id: u64) -> Result<()> {
+        match fs::remove_file(self.path_for(id, EXTENSION)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn id_of(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Exponential retry delay, reset on success or when the link comes back.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, failures: 0, next_attempt: None }
+    }
+
+    pub fn ready(&self, now: Instant) -> bool {
+        !matches!(self.next_attempt, Some(at) if now < at)
+    }
+
+    /// Time left until the next attempt, zero if it is due.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.next_attempt.map_or(Duration::ZERO, |at| at.saturating_duration_since(now))
+    }
+
+    pub fn failed(&mut self, now: Instant) -> Duration {
+        self.failures += 1;
+        let delay = self.base.saturating_mul(1 << (self.failures - 1).min(16)).min(self.max);
+        self.next_attempt = Some(now + delay);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(n: u64) -> Header {
+        Header { kind: Kind::Webhook, meta: serde_json::json!({ "n": n }), created_unix: Some(n) }
+    }
+
+    /// Size of the file for `header(n)` with `payload_len` bytes.
+    fn stored_len(n: u64, payload_len: usize) -> u64 {
+        (4 + serde_json::to_vec(&header(n)).unwrap().len() + payload_len) as u64
+    }
+
+    #[test]
+    fn delivers_in_push_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path(), 64 * 1024, 1).unwrap();
+        assert!(outbox.is_empty().unwrap());
+        let ids: Vec<u64> = (1..=3).map(|n| outbox.push(&header(n), &[n as u8; 10]).unwrap()).collect();
+        assert_eq!(ids, [1, 2, 3]);
+
+        for n in 1..=3 {
+            let entry = outbox.oldest().unwrap().unwrap();
+            assert_eq!(entry.header, header(n));
+            assert_eq!(entry.payload, [n as u8; 10]);
+            outbox.remove(entry.id).unwrap();
+        }
+        assert!(outbox.oldest().unwrap().is_none());
+        // removing twice is fine, the sender may race a restart
+        outbox.remove(1).unwrap();
+    }
+
+    #[test]
+    fn evicts_the_oldest_to_stay_within_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let message = stored_len(1, 100);
+        let mut outbox = Outbox::open(dir.path(), message * 3, 1).unwrap();
+        for n in 1..=3 {
+            outbox.push(&header(n), &[0; 100]).unwrap();
+        }
+        assert_eq!(outbox.stats().unwrap(), OutboxStats { depth: 3, bytes: message * 3, max_bytes: message * 3, evicted: 0 });
+
+        // one byte over makes room by dropping the two oldest
+        outbox.push(&header(4), &[0; 101]).unwrap();
+        let stats = outbox.stats().unwrap();
+        assert_eq!((stats.depth, stats.evicted), (2, 2));
+        assert!(stats.bytes <= stats.max_bytes);
+        assert_eq!(outbox.oldest().unwrap().unwrap().header, header(3));
+    }
+
+    #[test]
+    fn refuses_a_message_larger_than_the_outbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path(), 200, 1).unwrap();
+        outbox.push(&header(1), &[0; 10]).unwrap();
+        assert!(outbox.push(&header(2), &[0; 200]).is_err());
+        assert_eq!(outbox.stats().unwrap().depth, 1);
+    }
+
+    #[test]
+    fn counts_whole_clusters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path(), 3 * 4096, 4096).unwrap();
+        for n in 1..=3 {
+            outbox.push(&header(n), b"small").unwrap();
+        }
+        assert_eq!(outbox.stats().unwrap().bytes, 3 * 4096);
+
+        // however small, a fourth message needs a cluster of its own
+        outbox.push(&header(4), b"small").unwrap();
+        let stats = outbox.stats().unwrap();
+        assert_eq!((stats.depth, stats.bytes, stats.evicted), (3, 3 * 4096, 1));
+    }
+
+    #[test]
+    fn oldest_drops_unreadable_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut outbox = Outbox::open(dir.path(), 64 * 1024, 1).unwrap();
+        outbox.push(&header(1), b"first").unwrap();
+        outbox.push(&header(2), b"second").unwrap();
+        fs::write(dir.path().join("0000000001.msg"), [0xff, 0xff, 0, 0, b'{']).unwrap();
+
+        let entry = outbox.oldest().unwrap().unwrap();
+        assert_eq!((entry.id, entry.payload.as_slice()), (2, b"second".as_slice()));
+        assert!(!dir.path().join("0000000001.msg").exists());
+    }
+
+    #[test]
+    fn open_cleans_up_after_a_power_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut outbox = Outbox::open(dir.path(), 64 * 1024, 1).unwrap();
+            for n in 1..=5 {
+                outbox.push(&header(n), b"payload").unwrap();
+            }
+            outbox.remove(5).unwrap();
+            outbox.remove(1).unwrap();
+        }
+        // a message that was being written, and a file that is not ours
+        fs::write(dir.path().join("0000000006.tmp"), b"half").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"keep").unwrap();
+
+        let mut outbox = Outbox::open(dir.path(), 64 * 1024, 1).unwrap();
+        assert!(!dir.path().join("0000000006.tmp").exists());
+        assert!(dir.path().join("notes.txt").exists());
+        assert_eq!(outbox.stats().unwrap().depth, 3);
+        assert_eq!(outbox.oldest().unwrap().unwrap().id, 2);
+        // ids keep growing past the newest message left
+        assert_eq!(outbox.push(&header(6), b"payload").unwrap(), 5);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let now = Instant::now();
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        assert!(backoff.ready(now));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.failed(now).as_secs()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+        assert!(!backoff.ready(now + Duration::from_secs(59)));
+        assert!(backoff.ready(now + Duration::from_secs(60)));
+        assert_eq!(backoff.remaining(now + Duration::from_secs(45)), Duration::from_secs(15));
+
+        backoff.reset();
+        assert_eq!((backoff.failures(), backoff.remaining(now)), (0, Duration::ZERO));
+        for _ in 0..100 {
+            backoff.failed(now);
+        }
+        assert_eq!(backoff.failed(now), Duration::from_secs(60));
+    }
+}
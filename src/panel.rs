@@ -0,0 +1,240 @@
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+use esp_idf_hal::i2c::I2cDriver;
+use log::{info, warn};
+use ssd1306::{mode::BufferedGraphicsMode, prelude::*, Ssd1306, I2CDisplayInterface};
+
+use crate::small_display::SmallDisplayError;
+
+// The OLED variants found on our boards:
+//   TTGO T-Camera V16:    SSD1306 128x64
+//   other T-Camera revs:  SH1106 128x64, SSD1306 128x32
+#[toml_cfg::toml_config]
+pub struct DisplayConfig {
+    #[default("ssd1306")]
+    display_driver: &'static str,
+    #[default("128x64")]
+    display_size: &'static str,
+    #[default(0)]
+    display_rotation: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanelDriver {
+    Ssd1306,
+    Sh1106,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanelSize {
+    W128H64,
+    W128H32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanelRotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PanelConfig {
+    pub driver: PanelDriver,
+    pub size: PanelSize,
+    pub rotation: PanelRotation,
+}
+
+impl Default for PanelConfig {
+    fn default() -> Self {
+        Self {
+            driver: PanelDriver::Ssd1306,
+            size: PanelSize::W128H64,
+            rotation: PanelRotation::Rotate0,
+        }
+    }
+}
+
+impl PanelConfig {
+    /// Builds the panel config from cfg.toml, unknown values fall back to the V16 defaults.
+    pub fn from_config() -> Self {
+        let default = Self::default();
+        let driver = match DISPLAY_CONFIG.display_driver.to_ascii_lowercase().as_str() {
+            "ssd1306" => PanelDriver::Ssd1306,
+            "sh1106" => PanelDriver::Sh1106,
+            other => {
+                warn!("unknown display_driver {:?}, using {:?}", other, default.driver);
+                default.driver
+            },
+        };
+        let size = match DISPLAY_CONFIG.display_size {
+            "128x64" => PanelSize::W128H64,
+            "128x32" => PanelSize::W128H32,
+            other => {
+                warn!("unknown display_size {:?}, using {:?}", other, default.size);
+                default.size
+            },
+        };
+        let rotation = match DISPLAY_CONFIG.display_rotation {
+            0 => PanelRotation::Rotate0,
+            90 => PanelRotation::Rotate90,
+            180 => PanelRotation::Rotate180,
+            270 => PanelRotation::Rotate270,
+            other => {
+                warn!("unknown display_rotation {}, using {:?}", other, default.rotation);
+                default.rotation
+            },
+        };
+        Self { driver, size, rotation }
+    }
+}
+
+impl From<PanelRotation> for DisplayRotation {
+    fn from(value: PanelRotation) -> Self {
+        match value {
+            PanelRotation::Rotate0 => DisplayRotation::Rotate0,
+            PanelRotation::Rotate90 => DisplayRotation::Rotate90,
+            PanelRotation::Rotate180 => DisplayRotation::Rotate180,
+            PanelRotation::Rotate270 => DisplayRotation::Rotate270,
+        }
+    }
+}
+
+impl From<PanelRotation> for sh1106::prelude::DisplayRotation {
+    fn from(value: PanelRotation) -> Self {
+        match value {
+            PanelRotation::Rotate0 => sh1106::prelude::DisplayRotation::Rotate0,
+            PanelRotation::Rotate90 => sh1106::prelude::DisplayRotation::Rotate90,
+            PanelRotation::Rotate180 => sh1106::prelude::DisplayRotation::Rotate180,
+            PanelRotation::Rotate270 => sh1106::prelude::DisplayRotation::Rotate270,
+        }
+    }
+}
+
+/// A buffered monochrome panel the status pages can be drawn on.
+pub trait Panel: DrawTarget<Color = BinaryColor> {
+    fn init_panel(&mut self) -> Result<(), SmallDisplayError>;
+    fn clear_panel(&mut self);
+    fn flush_panel(&mut self) -> Result<(), SmallDisplayError>;
+}
+
+impl<DI, SIZE> Panel for Ssd1306<DI, SIZE, BufferedGraphicsMode<SIZE>>
+where
+    DI: WriteOnlyDataCommand,
+    SIZE: DisplaySize,
+{
+    fn init_panel(&mut self) -> Result<(), SmallDisplayError> {
+        Ok(self.init()?)
+    }
+
+    fn clear_panel(&mut self) {
+        self.clear_buffer();
+    }
+
+    fn flush_panel(&mut self) -> Result<(), SmallDisplayError> {
+        Ok(self.flush()?)
+    }
+}
+
+type Sh1106Panel<'d> = sh1106::mode::GraphicsMode<sh1106::interface::I2cInterface<I2cDriver<'d>>>;
+
+impl<'d> Panel for Sh1106Panel<'d> {
+    fn init_panel(&mut self) -> Result<(), SmallDisplayError> {
+        self.init().map_err(|e| SmallDisplayError::Other(format!("sh1106 init: {:?}", e)))
+    }
+
+    fn clear_panel(&mut self) {
+        self.clear();
+    }
+
+    fn flush_panel(&mut self) -> Result<(), SmallDisplayError> {
+        self.flush().map_err(|e| SmallDisplayError::Other(format!("sh1106 flush: {:?}", e)))
+    }
+}
+
+fn sh1106_panel(i2c: I2cDriver<'_>, size: sh1106::displaysize::DisplaySize, rotation: PanelRotation) -> Sh1106Panel<'_> {
+    sh1106::Builder::new()
+        .with_size(size)
+        .with_rotation(rotation.into())
+        .connect_i2c(i2c)
+        .into()
+}
+
+pub enum AnyPanel<'d> {
+    Ssd1306Large(Ssd1306<I2CInterface<I2cDriver<'d>>, DisplaySize128x64, BufferedGraphicsMode<DisplaySize128x64>>),
+    Ssd1306Small(Ssd1306<I2CInterface<I2cDriver<'d>>, DisplaySize128x32, BufferedGraphicsMode<DisplaySize128x32>>),
+    Sh1106(Sh1106Panel<'d>),
+}
+
+macro_rules! dispatch {
+    ($panel:expr, $p:ident => $body:expr) => {
+        match $panel {
+            AnyPanel::Ssd1306Large($p) => $body,
+            AnyPanel::Ssd1306Small($p) => $body,
+            AnyPanel::Sh1106($p) => $body,
+        }
+    };
+}
+
+impl<'d> AnyPanel<'d> {
+    pub fn new(i2c: I2cDriver<'d>, config: PanelConfig) -> Self {
+        info!("display panel: {:?}", config);
+        let rotation = config.rotation;
+        match (config.driver, config.size) {
+            (PanelDriver::Ssd1306, PanelSize::W128H64) => AnyPanel::Ssd1306Large(
+                Ssd1306::new(I2CDisplayInterface::new(i2c), DisplaySize128x64, rotation.into())
+                    .into_buffered_graphics_mode(),
+            ),
+            (PanelDriver::Ssd1306, PanelSize::W128H32) => AnyPanel::Ssd1306Small(
+                Ssd1306::new(I2CDisplayInterface::new(i2c), DisplaySize128x32, rotation.into())
+                    .into_buffered_graphics_mode(),
+            ),
+            (PanelDriver::Sh1106, PanelSize::W128H64) => AnyPanel::Sh1106(
+                sh1106_panel(i2c, sh1106::displaysize::DisplaySize::Display128x64, rotation),
+            ),
+            (PanelDriver::Sh1106, PanelSize::W128H32) => AnyPanel::Sh1106(
+                sh1106_panel(i2c, sh1106::displaysize::DisplaySize::Display128x32, rotation),
+            ),
+        }
+    }
+}
+
+impl<'d> Dimensions for AnyPanel<'d> {
+    fn bounding_box(&self) -> embedded_graphics::primitives::Rectangle {
+        dispatch!(self, p => p.bounding_box())
+    }
+}
+
+impl<'d> DrawTarget for AnyPanel<'d> {
+    type Color = BinaryColor;
+    type Error = SmallDisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        dispatch!(self, p => p.draw_iter(pixels).map_err(|e| SmallDisplayError::Draw(format!("{:?}", e))))
+    }
+
+    fn fill_solid(&mut self, area: &embedded_graphics::primitives::Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        dispatch!(self, p => p.fill_solid(area, color).map_err(|e| SmallDisplayError::Draw(format!("{:?}", e))))
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        dispatch!(self, p => DrawTarget::clear(p, color).map_err(|e| SmallDisplayError::Draw(format!("{:?}", e))))
+    }
+}
+
+impl<'d> Panel for AnyPanel<'d> {
+    fn init_panel(&mut self) -> Result<(), SmallDisplayError> {
+        dispatch!(self, p => p.init_panel())
+    }
+
+    fn clear_panel(&mut self) {
+        dispatch!(self, p => p.clear_panel())
+    }
+
+    fn flush_panel(&mut self) -> Result<(), SmallDisplayError> {
+        dispatch!(self, p => p.flush_panel())
+    }
+}
@@ -10,7 +10,7 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::timer::EspTaskTimerService;
 use esp_idf_svc::wifi::EspWifi;
-use esp_idf_sys::{esp_vfs_eventfd_config_t, esp_vfs_eventfd_register};
+use esp_idf_sys::{esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, esp_vfs_eventfd_config_t, esp_vfs_eventfd_register};
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 use std::sync::Arc;
@@ -33,6 +33,19 @@ pub fn patch_eventfd() {
     esp_nofail! { unsafe { esp_vfs_eventfd_register(&config) } }
 }
 
+/// The factory programmed station MAC, stable across firmware updates.
+pub fn device_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    esp_nofail! { unsafe { esp_read_mac(mac.as_mut_ptr(), esp_mac_type_t_ESP_MAC_WIFI_STA) } }
+    mac
+}
+
+/// Short unique id derived from the MAC, e.g. `a1b2c3`.
+pub fn device_id() -> String {
+    let mac = device_mac();
+    format!("{:02x}{:02x}{:02x}", mac[3], mac[4], mac[5])
+}
+
 pub fn create_esp_wifi() -> EspWifi<'static> {
     let p = PERIPHERALS.clone();
     let mut p = p.lock();
@@ -0,0 +1,458 @@
+// POSIX TZ strings (`std offset [dst [offset] [,start[/time],end[/time]]]`),
+// and the IANA names the settings accept in their place.
+//
+// Only chrono is needed, so the transition rules are tested on the host.
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, Offset, Utc};
+
+/// Days from 0001-01-01 (chrono's day 1) to 1970-01-01.
+const UNIX_EPOCH_DAYS_CE: i64 = 719_163;
+const SECS_PER_DAY: i64 = 86_400;
+
+/// Common zones and their current POSIX rules, anything else needs a TZ string.
+pub static ZONES: &[(&str, &str)] = &[
+    ("UTC", "UTC0"),
+    ("Etc/UTC", "UTC0"),
+    ("Europe/London", "GMT0BST,M3.5.0/1,M10.5.0"),
+    ("Europe/Dublin", "GMT0IST,M3.5.0/1,M10.5.0"),
+    ("Europe/Lisbon", "WET0WEST,M3.5.0/1,M10.5.0"),
+    ("Europe/Amsterdam", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Berlin", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Brussels", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Copenhagen", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Madrid", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Oslo", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Paris", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Prague", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Rome", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Stockholm", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Vienna", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Warsaw", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Zurich", "CET-1CEST,M3.5.0,M10.5.0/3"),
+    ("Europe/Athens", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Bucharest", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Helsinki", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Kyiv", "EET-2EEST,M3.5.0/3,M10.5.0/4"),
+    ("Europe/Istanbul", "<+03>-3"),
+    ("Europe/Moscow", "MSK-3"),
+    ("America/St_Johns", "NST3:30NDT,M3.2.0,M11.1.0"),
+    ("America/Halifax", "AST4ADT,M3.2.0,M11.1.0"),
+    ("America/New_York", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Toronto", "EST5EDT,M3.2.0,M11.1.0"),
+    ("America/Chicago", "CST6CDT,M3.2.0,M11.1.0"),
+    ("America/Mexico_City", "CST6"),
+    ("America/Denver", "MST7MDT,M3.2.0,M11.1.0"),
+    ("America/Phoenix", "MST7"),
+    ("America/Los_Angeles", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Vancouver", "PST8PDT,M3.2.0,M11.1.0"),
+    ("America/Anchorage", "AKST9AKDT,M3.2.0,M11.1.0"),
+    ("Pacific/Honolulu", "HST10"),
+    ("America/Bogota", "<-05>5"),
+    ("America/Santiago", "<-04>4<-03>,M9.1.6/24,M4.1.6/24"),
+    ("America/Sao_Paulo", "<-03>3"),
+    ("America/Argentina/Buenos_Aires", "<-03>3"),
+    ("Africa/Lagos", "WAT-1"),
+    ("Africa/Johannesburg", "SAST-2"),
+    ("Africa/Cairo", "EET-2EEST,M4.5.5/0,M10.5.4/24"),
+    ("Africa/Nairobi", "EAT-3"),
+    ("Asia/Dubai", "<+04>-4"),
+    ("Asia/Karachi", "PKT-5"),
+    ("Asia/Kolkata", "IST-5:30"),
+    ("Asia/Kathmandu", "<+0545>-5:45"),
+    ("Asia/Dhaka", "<+06>-6"),
+    ("Asia/Bangkok", "<+07>-7"),
+    ("Asia/Jakarta", "WIB-7"),
+    ("Asia/Shanghai", "CST-8"),
+    ("Asia/Hong_Kong", "HKT-8"),
+    ("Asia/Singapore", "<+08>-8"),
+    ("Asia/Taipei", "CST-8"),
+    ("Asia/Seoul", "KST-9"),
+    ("Asia/Tokyo", "JST-9"),
+    ("Australia/Perth", "AWST-8"),
+    ("Australia/Adelaide", "ACST-9:30ACDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Brisbane", "AEST-10"),
+    ("Australia/Melbourne", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Australia/Sydney", "AEST-10AEDT,M10.1.0,M4.1.0/3"),
+    ("Pacific/Auckland", "NZST-12NZDT,M9.5.0,M4.1.0/3"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Rule {
+    /// `Jn`: day 1 to 365, February 29th is never counted.
+    Julian1(u16),
+    /// `n`: day 0 to 365, counting February 29th.
+    Julian0(u16),
+    /// `Mm.w.d`: weekday `d` (0 is Sunday) of week `w` (5 is the last) of month `m`.
+    MonthWeekDay { month: u32, week: u32, weekday: u32 },
+}
+
+impl Rule {
+    fn date(self, year: i32) -> Option<NaiveDate> {
+        match self {
+            Rule::Julian1(n) => {
+                let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+                let ordinal = u32::from(n) + u32::from(leap && n >= 60);
+                NaiveDate::from_yo_opt(year, ordinal)
+            },
+            Rule::Julian0(n) => NaiveDate::from_yo_opt(year, u32::from(n) + 1),
+            Rule::MonthWeekDay { month, week, weekday } => {
+                let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+                let first_weekday = first.weekday().num_days_from_sunday();
+                let mut day = 1 + (weekday + 7 - first_weekday) % 7 + (week - 1) * 7;
+                // week 5 means the last one, which may be the 4th
+                while NaiveDate::from_ymd_opt(year, month, day).is_none() {
+                    day -= 7;
+                }
+                NaiveDate::from_ymd_opt(year, month, day)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Transition {
+    rule: Rule,
+    /// Local wall clock seconds after midnight, may be negative or past 24h.
+    time: i64,
+}
+
+impl Transition {
+    /// The transition instant in UTC seconds, given the offset in effect before it.
+    fn utc(&self, year: i32, offset_before: i64) -> Option<i64> {
+        let date = self.rule.date(year)?;
+        let days = i64::from(date.num_days_from_ce()) - UNIX_EPOCH_DAYS_CE;
+        Some(days * SECS_PER_DAY + self.time - offset_before)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Dst {
+    name: String,
+    offset: i64,
+    start: Transition,
+    end: Transition,
+}
+
+/// A parsed POSIX TZ string, offsets in seconds east of UTC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PosixTz {
+    std_name: String,
+    std_offset: i64,
+    dst: Option<Dst>,
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        let matched = self.peek() == Some(c);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let start = self.pos;
+        let name = if self.eat(b'<') {
+            while self.peek().is_some_and(|c| c != b'>') {
+                self.pos += 1;
+            }
+            let name = &self.s[start + 1..self.pos];
+            if !self.eat(b'>') {
+                return Err(anyhow!("unterminated <zone name>"));
+            }
+            name
+        } else {
+            while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                self.pos += 1;
+            }
+            &self.s[start..self.pos]
+        };
+        if name.len() < 3 {
+            return Err(anyhow!("zone names need at least 3 characters"));
+        }
+        Ok(String::from_utf8_lossy(name).into_owned())
+    }
+
+    fn number(&mut self, max: i64) -> Result<i64> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let value = std::str::from_utf8(&self.s[start..self.pos])?
+            .parse::<i64>()
+            .map_err(|_| anyhow!("expected a number at {}", start))?;
+        if value > max {
+            return Err(anyhow!("{} is out of range at {}", value, start));
+        }
+        Ok(value)
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds, as written.
+    fn hms(&mut self, max_hours: i64) -> Result<i64> {
+        let sign = if self.eat(b'-') {
+            -1
+        } else {
+            self.eat(b'+');
+            1
+        };
+        let mut secs = self.number(max_hours)? * 3600;
+        if self.eat(b':') {
+            secs += self.number(59)? * 60;
+            if self.eat(b':') {
+                secs += self.number(59)?;
+            }
+        }
+        Ok(sign * secs)
+    }
+
+    fn transition(&mut self) -> Result<Transition> {
+        let rule = if self.eat(b'J') {
+            let day = self.number(365)?;
+            if day == 0 {
+                return Err(anyhow!("Jn counts days from 1"));
+            }
+            Rule::Julian1(day as u16)
+        } else if self.eat(b'M') {
+            let month = self.number(12)? as u32;
+            let week = if self.eat(b'.') { self.number(5)? as u32 } else { 0 };
+            let weekday = if self.eat(b'.') { self.number(6)? as u32 } else { 7 };
+            if month == 0 || week == 0 || weekday > 6 {
+                return Err(anyhow!("expected Mm.w.d"));
+            }
+            Rule::MonthWeekDay { month, week, weekday }
+        } else {
+            Rule::Julian0(self.number(365)? as u16)
+        };
+        let time = if self.eat(b'/') { self.hms(167)? } else { 2 * 3600 };
+        Ok(Transition { rule, time })
+    }
+}
+
+impl PosixTz {
+    pub fn utc() -> Self {
+        Self { std_name: "UTC".to_owned(), std_offset: 0, dst: None }
+    }
+
+    pub fn parse(tz: &str) -> Result<Self> {
+        let mut p = Parser { s: tz.as_bytes(), pos: 0 };
+        let std_name = p.name()?;
+        // POSIX offsets count hours west of Greenwich
+        let std_offset = -p.hms(24)?;
+        let dst = if p.peek().is_some() {
+            let name = p.name()?;
+            let offset = match p.peek() {
+                Some(c) if c != b',' => -p.hms(24)?,
+                _ => std_offset + 3600,
+            };
+            let (start, end) = if p.eat(b',') {
+                let start = p.transition()?;
+                if !p.eat(b',') {
+                    return Err(anyhow!("expected the end of daylight saving time"));
+                }
+                (start, p.transition()?)
+            } else {
+                // what newlib and glibc assume when the rules are missing
+                let us = |month, week| Transition { rule: Rule::MonthWeekDay { month, week, weekday: 0 }, time: 2 * 3600 };
+                (us(3, 2), us(11, 1))
+            };
+            Some(Dst { name, offset, start, end })
+        } else {
+            None
+        };
+        if p.pos != tz.len() {
+            return Err(anyhow!("unexpected {:?} at {}", &tz[p.pos..], p.pos));
+        }
+        Ok(Self { std_name, std_offset, dst })
+    }
+
+    /// Offset east of UTC, zone abbreviation and whether it is daylight saving time.
+    pub fn offset_at(&self, unix_secs: i64) -> (i64, &str, bool) {
+        let standard = (self.std_offset, self.std_name.as_str(), false);
+        let Some(dst) = &self.dst else {
+            return standard;
+        };
+        let local_days = (unix_secs + self.std_offset).div_euclid(SECS_PER_DAY);
+        let Some(year) = NaiveDate::from_num_days_from_ce_opt((local_days + UNIX_EPOCH_DAYS_CE) as i32).map(|d| d.year()) else {
+            return standard;
+        };
+        let (Some(start), Some(end)) = (dst.start.utc(year, self.std_offset), dst.end.utc(year, dst.offset)) else {
+            return standard;
+        };
+        let in_dst = if start < end {
+            unix_secs >= start && unix_secs < end
+        } else {
+            // southern hemisphere, daylight saving time spans the new year
+            unix_secs < end || unix_secs >= start
+        };
+        if in_dst {
+            (dst.offset, dst.name.as_str(), true)
+        } else {
+            standard
+        }
+    }
+
+    pub fn to_local(&self, utc: DateTime<Utc>) -> DateTime<FixedOffset> {
+        let (offset, _, _) = self.offset_at(utc.timestamp());
+        let offset = FixedOffset::east_opt(offset as i32).unwrap_or_else(|| Utc.fix());
+        utc.with_timezone(&offset)
+    }
+}
+
+/// The POSIX rules for a zone name from `ZONES` or a TZ string.
+pub fn resolve(zone: &str) -> Result<String> {
+    if let Some((_, posix)) = ZONES.iter().find(|(name, _)| *name == zone) {
+        return Ok((*posix).to_owned());
+    }
+    match PosixTz::parse(zone) {
+        Ok(_) => Ok(zone.to_owned()),
+        Err(_) if looks_like_zone_name(zone) => {
+            Err(anyhow!("unknown zone {:?}, use a POSIX TZ string instead", zone))
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// `Area/City` style, as opposed to a TZ string whose rules contain a `/time`.
+fn looks_like_zone_name(zone: &str) -> bool {
+    let name_part = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphabetic())
+            && part.bytes().all(|c| c.is_ascii_alphanumeric() || b"_-+".contains(&c))
+    };
+    zone.contains('/') && zone.split('/').all(name_part)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(name: &str) -> PosixTz {
+        PosixTz::parse(&resolve(name).unwrap()).unwrap()
+    }
+
+    /// The offset and abbreviation a second before and at `at`.
+    fn around(tz: &PosixTz, at: i64) -> ((i64, &str), (i64, &str)) {
+        let (before, before_name, _) = tz.offset_at(at - 1);
+        let (after, after_name, _) = tz.offset_at(at);
+        ((before, before_name), (after, after_name))
+    }
+
+    #[test]
+    fn central_europe_switches_at_one_utc() {
+        let berlin = zone("Europe/Berlin");
+        // 2024-03-31 02:00 CET and 2024-10-27 03:00 CEST
+        assert_eq!(around(&berlin, 1_711_846_800), ((3600, "CET"), (7200, "CEST")));
+        assert_eq!(around(&berlin, 1_729_990_800), ((7200, "CEST"), (3600, "CET")));
+        assert!(berlin.offset_at(1_720_000_000).2);
+        assert!(!berlin.offset_at(1_700_000_000).2);
+    }
+
+    #[test]
+    fn us_eastern_switches_at_two_local() {
+        let new_york = zone("America/New_York");
+        // 2024-03-10 02:00 EST and 2024-11-03 02:00 EDT
+        assert_eq!(around(&new_york, 1_710_054_000), ((-18_000, "EST"), (-14_400, "EDT")));
+        assert_eq!(around(&new_york, 1_730_613_600), ((-14_400, "EDT"), (-18_000, "EST")));
+    }
+
+    #[test]
+    fn southern_summer_spans_the_new_year() {
+        let sydney = zone("Australia/Sydney");
+        // 2024-04-07 03:00 AEDT and 2024-10-06 02:00 AEST
+        assert_eq!(around(&sydney, 1_712_419_200), ((39_600, "AEDT"), (36_000, "AEST")));
+        assert_eq!(around(&sydney, 1_728_144_000), ((36_000, "AEST"), (39_600, "AEDT")));
+        // new year's day in Sydney is summer
+        assert_eq!(sydney.offset_at(1_704_067_200), (39_600, "AEDT", true));
+    }
+
+    #[test]
+    fn transitions_at_24_hours_fall_on_the_next_day() {
+        let santiago = zone("America/Santiago");
+        // the first Saturday of April and September 2024, at 24:00
+        assert_eq!(around(&santiago, 1_712_458_800), ((-10_800, "-03"), (-14_400, "-04")));
+        assert_eq!(around(&santiago, 1_725_768_000), ((-14_400, "-04"), (-10_800, "-03")));
+    }
+
+    #[test]
+    fn julian_days() {
+        // Jn never counts February 29th, so J60 is March 1st in every year
+        let tz = PosixTz::parse("EST5EDT,J60/0,J300").unwrap();
+        assert_eq!(around(&tz, 1_709_269_200), ((-18_000, "EST"), (-14_400, "EDT")));
+        assert_eq!(around(&tz, 1_677_646_800), ((-18_000, "EST"), (-14_400, "EDT")));
+
+        // n counts from 0 and includes it
+        let tz = PosixTz::parse("EST5EDT,59/0,300").unwrap();
+        assert_eq!(around(&tz, 1_709_182_800), ((-18_000, "EST"), (-14_400, "EDT")));
+        assert_eq!(around(&tz, 1_677_646_800), ((-18_000, "EST"), (-14_400, "EDT")));
+    }
+
+    #[test]
+    fn parses_offsets_and_defaults() {
+        let tz = PosixTz::parse("<+0545>-5:45").unwrap();
+        assert_eq!(tz.offset_at(0), (20_700, "+0545", false));
+        let tz = PosixTz::parse("NST3:30NDT,M3.2.0,M11.1.0").unwrap();
+        assert_eq!(tz.offset_at(1_720_000_000), (-9_000, "NDT", true));
+        // without rules the US ones apply, one hour ahead
+        assert_eq!(PosixTz::parse("EST5EDT").unwrap(), PosixTz::parse("EST5EDT,M3.2.0/2,M11.1.0/2").unwrap());
+        assert_eq!(PosixTz::parse("UTC0").unwrap().offset_at(1_720_000_000), (0, "UTC", false));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        for bad in [
+            "EST5EDT,J0,J300",
+            "EST5EDT,J60,J366",
+            "EST5EDT,366,0",
+            "EST5EDT,M13.1.0,M11.1.0",
+            "EST5EDT,M3.6.0,M11.1.0",
+            "EST5EDT,M3.2.7,M11.1.0",
+            "EST5EDT,M3.2.0",
+            "EST5EDT,M3.2.0/168,M11.1.0",
+            "ES5",
+            "<EST5",
+            "EST25",
+            "EST5x",
+        ] {
+            assert!(PosixTz::parse(bad).is_err(), "{:?} should not parse", bad);
+        }
+        assert!(PosixTz::parse("EST5EDT,J1,J365").is_ok());
+    }
+
+    #[test]
+    fn resolve_accepts_names_and_tz_strings() {
+        assert_eq!(resolve("Europe/Berlin").unwrap(), "CET-1CEST,M3.5.0,M10.5.0/3");
+        // the `/time` parts are not taken for an Area/City name
+        assert_eq!(resolve("GMT0BST,M3.5.0/1,M10.5.0").unwrap(), "GMT0BST,M3.5.0/1,M10.5.0");
+        assert_eq!(resolve("<+03>-3").unwrap(), "<+03>-3");
+
+        for unknown in ["Mars/Olympus_Mons", "Etc/GMT+5", "America/Argentina/Cordoba"] {
+            let error = resolve(unknown).unwrap_err().to_string();
+            assert!(error.starts_with("unknown zone"), "{}: {}", unknown, error);
+        }
+        let error = resolve("EST5EDT,M3.2.0/x,M11.1.0").unwrap_err().to_string();
+        assert!(!error.starts_with("unknown zone"), "{}", error);
+    }
+
+    #[test]
+    fn every_known_zone_parses() {
+        for (name, posix) in ZONES {
+            assert!(PosixTz::parse(posix).is_ok(), "{} ({})", name, posix);
+        }
+    }
+
+    #[test]
+    fn to_local_applies_the_offset() {
+        let berlin = zone("Europe/Berlin");
+        let utc = DateTime::from_timestamp(1_720_000_000, 0).unwrap();
+        assert_eq!(berlin.to_local(utc).to_rfc3339(), "2024-07-03T11:46:40+02:00");
+    }
+}
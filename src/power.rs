@@ -0,0 +1,160 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{anyhow, Result};
+use esp_idf_sys::{
+    esp_wifi_get_config, esp_wifi_set_config, esp_wifi_set_max_tx_power, esp_wifi_set_ps,
+    wifi_config_t, wifi_interface_t_WIFI_IF_STA, wifi_ps_type_t, wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    wifi_ps_type_t_WIFI_PS_MIN_MODEM, wifi_ps_type_t_WIFI_PS_NONE,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+
+pub const POWER_SETTINGS: Key<PowerSettings> = Key::new("power", PowerSettings::default);
+
+// Number of streams/uploads in flight, the auto policy stays awake while non-zero.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSaveMode {
+    None,
+    Min,
+    Max,
+    /// `None` while streaming or uploading, `idle_mode` otherwise.
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerSettings {
+    pub mode: PowerSaveMode,
+    /// What `Auto` drops back to when nothing is active, `Min` or `Max`.
+    pub idle_mode: PowerSaveMode,
+    /// Limit in dBm, 2 to 20; `None` keeps the driver default.
+    pub max_tx_power_dbm: Option<f32>,
+    /// DTIM period of the access point, the station can't learn it before associating.
+    pub dtim_period: u8,
+    /// How many DTIM periods to sleep through in `Max` mode.
+    pub listen_dtims: u8,
+}
+
+impl Default for PowerSettings {
+    fn default() -> Self {
+        Self {
+            mode: PowerSaveMode::Auto,
+            idle_mode: PowerSaveMode::Min,
+            max_tx_power_dbm: None,
+            dtim_period: 1,
+            listen_dtims: 3,
+        }
+    }
+}
+
+impl PowerSettings {
+    pub fn validate(&self) -> Result<()> {
+        if matches!(self.idle_mode, PowerSaveMode::Auto) {
+            return Err(anyhow!("idle_mode can't be auto"));
+        }
+        if let Some(dbm) = self.max_tx_power_dbm {
+            if !(2.0..=20.0).contains(&dbm) {
+                return Err(anyhow!("max_tx_power_dbm must be between 2 and 20"));
+            }
+        }
+        if self.dtim_period == 0 || self.listen_dtims == 0 {
+            return Err(anyhow!("dtim_period and listen_dtims must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Listen interval in beacon intervals, always a whole number of DTIM periods.
+    pub fn listen_interval(&self) -> u16 {
+        u16::from(self.dtim_period) * u16::from(self.listen_dtims)
+    }
+
+    /// The mode to use right now, given how many streams/uploads are active.
+    pub fn effective_mode(&self, active: usize) -> PowerSaveMode {
+        match self.mode {
+            PowerSaveMode::Auto if active > 0 => PowerSaveMode::None,
+            PowerSaveMode::Auto => self.idle_mode,
+            mode => mode,
+        }
+    }
+}
+
+fn ps_type(mode: PowerSaveMode) -> wifi_ps_type_t {
+    match mode {
+        PowerSaveMode::None => wifi_ps_type_t_WIFI_PS_NONE,
+        PowerSaveMode::Min | PowerSaveMode::Auto => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+        PowerSaveMode::Max => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    }
+}
+
+pub fn load() -> Result<PowerSettings> {
+    SETTINGS.lock().get(&POWER_SETTINGS)
+}
+
+pub fn store(settings: &PowerSettings) -> Result<()> {
+    settings.validate()?;
+    SETTINGS.lock().set(&POWER_SETTINGS, settings)
+}
+
+fn apply_mode(settings: &PowerSettings) -> Result<()> {
+    let mode = settings.effective_mode(ACTIVE.load(Ordering::SeqCst));
+    debug!("wifi power save: {:?}", mode);
+    esp!(unsafe { esp_wifi_set_ps(ps_type(mode)) })?;
+    Ok(())
+}
+
+/// Applies power save and TX power, the driver has to be started.
+pub fn apply() -> Result<()> {
+    let settings = load()?;
+    apply_mode(&settings)?;
+    if let Some(dbm) = settings.max_tx_power_dbm {
+        // the driver takes quarter dBm
+        esp!(unsafe { esp_wifi_set_max_tx_power((dbm * 4.0) as i8) })?;
+    }
+    Ok(())
+}
+
+/// Writes the listen interval into the station config, call between `set_configuration` and `connect`.
+pub fn apply_listen_interval() -> Result<()> {
+    let settings = load()?;
+    let mut config = wifi_config_t::default();
+    esp!(unsafe { esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut config) })?;
+    unsafe { config.sta.listen_interval = settings.listen_interval() };
+    esp!(unsafe { esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut config) })?;
+    Ok(())
+}
+
+/// Keeps the radio fully awake under the auto policy for as long as it is held.
+pub struct ActivityGuard(());
+
+pub fn busy() -> ActivityGuard {
+    if ACTIVE.fetch_add(1, Ordering::SeqCst) == 0 {
+        reapply_mode();
+    }
+    ActivityGuard(())
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        if ACTIVE.fetch_sub(1, Ordering::SeqCst) == 1 {
+            reapply_mode();
+        }
+    }
+}
+
+fn reapply_mode() {
+    match load() {
+        Ok(settings) if settings.mode == PowerSaveMode::Auto => {
+            if let Err(e) = apply_mode(&settings) {
+                // fails harmlessly while the driver is stopped
+                debug!("power save: {}", e);
+            }
+        },
+        Ok(_) => {},
+        Err(e) => error!("power settings: {}", e),
+    }
+}
@@ -0,0 +1,278 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+use embassy_futures::select::{select, Either};
+use embedded_svc::wifi::{AccessPointConfiguration, AccessPointInfo, AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_svc::{
+    http::{server::EspHttpServer, Method},
+    io::Write,
+    wifi::{AsyncWifi, EspWifi},
+};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::api::{read_json, reply_error, reply_json};
+use crate::networks::{upsert_network, KnownNetwork};
+use crate::peripherals::ESP_TASK_TIMER_SVR;
+use crate::preludes::*;
+use crate::small_display::InfoUpdate;
+use crate::wifi::wifi_scan;
+
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+const DNS_PORT: u16 = 53;
+const DNS_TTL: u32 = 60;
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+    // button gestures ask the Wi-Fi task to enter provisioning through this
+    static ref REQUESTS: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+}
+
+struct Session {
+    networks: Vec<ScannedNetwork>,
+    saved: flume::Sender<KnownNetwork>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ScannedNetwork {
+    ssid: String,
+    rssi: i8,
+    secure: bool,
+}
+
+impl From<&AccessPointInfo> for ScannedNetwork {
+    fn from(ap: &AccessPointInfo) -> Self {
+        Self {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            secure: !matches!(ap.auth_method, None | Some(AuthMethod::None)),
+        }
+    }
+}
+
+pub fn request_provisioning() {
+    let _ = REQUESTS.0.try_send(());
+}
+
+pub fn provisioning_requests() -> flume::Receiver<()> {
+    REQUESTS.1.clone()
+}
+
+pub fn is_active() -> bool {
+    SESSION.lock().is_some()
+}
+
+/// Runs the access point and captive portal until credentials are saved or the timeout expires.
+pub async fn run_provisioning(wifi: &mut AsyncWifi<EspWifi<'static>>, tx: InfoSender) -> Result<()> {
+    let mac = wifi.wifi().ap_netif().get_mac()?;
+    let ap_ssid = format!("ttgo-camera-{:02x}{:02x}", mac[4], mac[5]);
+    warn!("starting provisioning access point {}", ap_ssid);
+    tx.send(InfoUpdate::Msg(format!("setup: {}", ap_ssid)))?;
+
+    let mut ssid: heapless::String<32> = heapless::String::new();
+    ssid.push_str(&ap_ssid).map_err(|_| anyhow!("ap ssid too long"))?;
+    let _ = wifi.stop().await;
+    wifi.set_configuration(&Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid,
+            auth_method: AuthMethod::None,
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+    // not waiting for the netifs: the station side stays down until credentials exist
+    wifi.start().await?;
+
+    let networks: Vec<ScannedNetwork> = match wifi_scan(wifi).await {
+        Ok(scan) => scan.iter().map(ScannedNetwork::from).collect(),
+        Err(e) => {
+            warn!("provisioning scan: {}", e);
+            Vec::new()
+        },
+    };
+
+    let ap_ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    tx.send(InfoUpdate::Addr(ap_ip))?;
+    let dns_running = Arc::new(AtomicBool::new(true));
+    let dns = {
+        let running = dns_running.clone();
+        thread::Builder::new()
+            .name("captive-dns".to_owned())
+            .stack_size(4096)
+            .spawn(move || {
+                if let Err(e) = captive_dns(ap_ip, &running) {
+                    error!("captive dns: {}", e);
+                }
+            })?
+    };
+
+    let (saved_tx, saved_rx) = flume::bounded(1);
+    *SESSION.lock() = Some(Session { networks, saved: saved_tx });
+
+    let mut timer = ESP_TASK_TIMER_SVR.timer_async()?;
+    let outcome = select(saved_rx.recv_async(), timer.after(PROVISIONING_TIMEOUT)).await;
+
+    SESSION.lock().take();
+    dns_running.store(false, Ordering::SeqCst);
+    let _ = dns.join();
+
+    match outcome {
+        Either::First(Ok(network)) => info!("provisioned network {}", network.ssid),
+        _ => warn!("provisioning timed out"),
+    }
+
+    wifi.stop().await?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    Ok(())
+}
+
+/// Answers every A query with the access point address so clients land on the setup page.
+fn captive_dns(ap_ip: Ipv4Addr, running: &AtomicBool) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let mut buf = [0u8; 512];
+    while running.load(Ordering::SeqCst) {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(reply) = dns_reply(&buf[..len], ap_ip) {
+            let _ = socket.send_to(&reply, peer);
+        }
+    }
+    Ok(())
+}
+
+/// Builds the response to a single-question DNS query, `None` if the packet isn't one.
+pub fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    if query.len() < 12 || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if qdcount != 1 {
+        return None;
+    }
+
+    // walk the labels of the question name
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        pos += len;
+    }
+    let question_end = pos + 4;
+    let question = query.get(12..question_end)?;
+    let qtype = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let answer = qtype == 1;
+
+    let mut reply = Vec::with_capacity(question_end + 16);
+    reply.extend_from_slice(&query[0..2]);
+    // response, opcode copied, authoritative, recursion desired copied and available
+    reply.push(0x84 | (query[2] & 0x79));
+    reply.push(0x80);
+    reply.extend_from_slice(&1u16.to_be_bytes());
+    reply.extend_from_slice(&u16::from(answer).to_be_bytes());
+    reply.extend_from_slice(&[0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if answer {
+        reply.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01]);
+        reply.extend_from_slice(&DNS_TTL.to_be_bytes());
+        reply.extend_from_slice(&4u16.to_be_bytes());
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+static SETUP_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width,initial-scale=1">
+<title>Camera setup</title></head>
+<body>
+<h2>Camera Wi-Fi setup</h2>
+<form id="f">
+<p><select id="ssid"></select> or <input id="other" placeholder="hidden network"></p>
+<p><input id="psk" type="password" placeholder="password"></p>
+<p><button>Save</button></p>
+</form>
+<p id="msg"></p>
+<script>
+fetch('/setup/networks').then(r => r.json()).then(list => {
+  const s = document.getElementById('ssid');
+  list.forEach(n => s.add(new Option(n.ssid + ' (' + n.rssi + ' dBm' + (n.secure ? ', secured' : '') + ')', n.ssid)));
+});
+document.getElementById('f').onsubmit = e => {
+  e.preventDefault();
+  const other = document.getElementById('other').value;
+  const body = {ssid: other || document.getElementById('ssid').value, psk: document.getElementById('psk').value, priority: 10};
+  fetch('/setup/save', {method: 'POST', body: JSON.stringify(body)})
+    .then(r => r.json())
+    .then(r => document.getElementById('msg').textContent = r.ok ? 'Saved, the camera is joining the network.' : r.error);
+};
+</script>
+</body></html>
+"#;
+
+// URLs phones and laptops probe to detect a captive portal
+static PROBE_URLS: [&str; 5] = [
+    "/generate_204",
+    "/gen_204",
+    "/hotspot-detect.html",
+    "/ncsi.txt",
+    "/connecttest.txt",
+];
+
+pub fn register_handlers(server: &mut EspHttpServer) -> Result<()> {
+    server.fn_handler("/setup", Method::Get, |request| {
+        let mut response = request.into_response(200, None, &[("Content-Type", "text/html")])?;
+        response.write_all(SETUP_PAGE.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/setup/networks", Method::Get, |request| {
+        let networks = SESSION.lock().as_ref().map(|s| s.networks.clone()).unwrap_or_default();
+        reply_json(request, 200, &networks)
+    })?;
+
+    // outside the portal, networks are managed through /api/wifi/networks
+    server.fn_handler("/setup/save", Method::Post, |mut request| {
+        if !is_active() {
+            request.into_status_response(404)?;
+            return Ok(());
+        }
+        let network: KnownNetwork = match read_json(&mut request) {
+            Ok(n) => n,
+            Err(e) => return reply_error(request, 400, &e.to_string()),
+        };
+        if let Err(e) = upsert_network(network.clone()) {
+            return reply_error(request, 400, &e.to_string());
+        }
+        if let Some(session) = SESSION.lock().as_ref() {
+            let _ = session.saved.try_send(network);
+        }
+        reply_json(request, 200, &serde_json::json!({ "ok": true }))
+    })?;
+
+    for url in PROBE_URLS {
+        server.fn_handler(url, Method::Get, |request| {
+            if !is_active() {
+                request.into_status_response(404)?;
+                return Ok(());
+            }
+            request.into_response(302, None, &[("Location", "/setup")])?;
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
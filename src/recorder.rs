@@ -0,0 +1,161 @@
+// Keeps captured frames on the storage partition.
+//
+// The upload thread hands every capture it takes to `save`, which stores the
+// ones the settings ask for in the capture store. Motion frames taken close
+// together share an event, so they can be fetched as one.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::captures::{CaptureStore, NewCapture, Quota, Record, StoreStats};
+use crate::clock::TimeStatus;
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+use crate::storage;
+use crate::upload::{Capture, Trigger};
+
+pub const RECORDER_SETTINGS: Key<RecorderSettings> = Key::new("recorder", RecorderSettings::default);
+
+const CAPTURES_DIR: &str = "captures";
+/// The recorder's share of the storage partition, see `storage::CLUSTER_BYTES`.
+const MAX_QUOTA_BYTES: u64 = 160 * 1024;
+/// Each long file name takes 128 bytes of directory as well.
+const MAX_QUOTA_FILES: usize = 200;
+
+lazy_static! {
+    static ref STORE: Mutex<Option<CaptureStore>> = Mutex::new(None);
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecorderSettings {
+    pub enabled: bool,
+    pub on_motion: bool,
+    pub on_schedule: bool,
+    pub on_manual: bool,
+    pub max_bytes: u64,
+    pub max_files: usize,
+    /// Motion frames less than this apart belong to the same event.
+    pub event_gap_secs: u32,
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            on_motion: true,
+            on_schedule: false,
+            on_manual: true,
+            max_bytes: 128 * 1024,
+            max_files: 100,
+            event_gap_secs: 30,
+        }
+    }
+}
+
+impl RecorderSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=MAX_QUOTA_BYTES).contains(&self.max_bytes) {
+            return Err(anyhow!("max_bytes must be between 1 and {}", MAX_QUOTA_BYTES));
+        }
+        if !(1..=MAX_QUOTA_FILES).contains(&self.max_files) {
+            return Err(anyhow!("max_files must be between 1 and {}", MAX_QUOTA_FILES));
+        }
+        Ok(())
+    }
+
+    /// Settings stored before the limits were lowered are held to them as well.
+    fn quota(&self) -> Quota {
+        Quota {
+            max_bytes: self.max_bytes.min(MAX_QUOTA_BYTES),
+            max_files: self.max_files.min(MAX_QUOTA_FILES),
+            cluster_bytes: storage::CLUSTER_BYTES,
+        }
+    }
+
+    fn wants(&self, trigger: Trigger) -> bool {
+        self.enabled
+            && match trigger {
+                Trigger::Motion => self.on_motion,
+                Trigger::Schedule => self.on_schedule,
+                Trigger::Manual => self.on_manual,
+            }
+    }
+}
+
+pub fn load() -> Result<RecorderSettings> {
+    SETTINGS.lock().get(&RECORDER_SETTINGS)
+}
+
+/// Stores new settings and applies the quota right away, which may delete old frames.
+pub fn store(settings: RecorderSettings) -> Result<()> {
+    settings.validate()?;
+    SETTINGS.lock().set(&RECORDER_SETTINGS, &settings)?;
+    if let Some(store) = STORE.lock().as_mut() {
+        store.set_quota(settings.quota())?;
+    }
+    Ok(())
+}
+
+/// Runs `f` on the capture store, failing if it is not open.
+pub fn with_store<R>(f: impl FnOnce(&mut CaptureStore) -> Result<R>) -> Result<R> {
+    let mut store = STORE.lock();
+    f(store.as_mut().ok_or_else(|| anyhow!("capture storage is not available"))?)
+}
+
+pub fn stats() -> Option<StoreStats> {
+    STORE.lock().as_ref().map(CaptureStore::stats)
+}
+
+/// Whether a capture for `trigger` would be kept.
+pub fn wants(trigger: Trigger) -> bool {
+    STORE.lock().is_some() && load().is_ok_and(|s| s.wants(trigger))
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Keeps `capture` if the settings ask for it.
+pub fn save(capture: &Capture) -> Result<Option<Record>> {
+    let settings = load()?;
+    if !settings.wants(capture.trigger) {
+        return Ok(None);
+    }
+    with_store(|store| {
+        let taken_unix_ms = unix_ms(capture.taken);
+        let gap_ms = u64::from(settings.event_gap_secs) * 1000;
+        let event_id = store
+            .latest()
+            .filter(|last| capture.trigger == Trigger::Motion && last.trigger == Trigger::Motion.as_str())
+            .filter(|last| taken_unix_ms.checked_sub(last.taken_unix_ms).is_some_and(|gap| gap <= gap_ms))
+            .map(|last| last.event_id);
+        let record = store.add(
+            NewCapture {
+                taken_unix_ms,
+                time_synced: capture.time_status != TimeStatus::Unsynced,
+                trigger: capture.trigger.as_str().to_owned(),
+                event_id,
+            },
+            &capture.jpeg,
+        )?;
+        info!("recorder: stored {} ({} bytes, event {})", record.file_name(), record.size, record.event_id);
+        Ok(Some(record))
+    })
+}
+
+/// Opens the capture store on the storage partition.
+pub fn start() -> Result<()> {
+    if !storage::is_mounted() {
+        return Err(anyhow!("storage is not mounted"));
+    }
+    let store = CaptureStore::open(storage::path(CAPTURES_DIR), load()?.quota())?;
+    let stats = store.stats();
+    info!("recorder: {} frames in {} events, {} bytes", stats.files, stats.events, stats.bytes);
+    *STORE.lock() = Some(store);
+    Ok(())
+}
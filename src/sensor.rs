@@ -0,0 +1,133 @@
+// Image sensor settings that survive a reboot.
+//
+// Changes come in as a `SensorPatch` with only the fields to change; the
+// merged settings are stored and pushed to the OV2640 right away.
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_camera_rs::Camera;
+use serde::{Deserialize, Serialize};
+
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+
+pub const SENSOR_SETTINGS: Key<SensorSettings> = Key::new("sensor", SensorSettings::default);
+
+/// Compression added per attempt when a frame has to get smaller.
+const QUALITY_STEP: usize = 8;
+const WORST_QUALITY: i32 = 63;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SensorSettings {
+    /// JPEG quality, 4 (best) to `WORST_QUALITY`.
+    pub quality: i32,
+    pub brightness: i32,
+    pub contrast: i32,
+    pub saturation: i32,
+    pub hmirror: bool,
+    pub vflip: bool,
+}
+
+impl Default for SensorSettings {
+    fn default() -> Self {
+        Self { quality: 12, brightness: 0, contrast: 0, saturation: 0, hmirror: false, vflip: false }
+    }
+}
+
+impl SensorSettings {
+    pub fn validate(&self) -> Result<()> {
+        if !(4..=WORST_QUALITY).contains(&self.quality) {
+            return Err(anyhow!("quality must be between 4 and {}", WORST_QUALITY));
+        }
+        for (name, value) in [("brightness", self.brightness), ("contrast", self.contrast), ("saturation", self.saturation)] {
+            if !(-2..=2).contains(&value) {
+                return Err(anyhow!("{} must be between -2 and 2", name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn patched(mut self, patch: &SensorPatch) -> Self {
+        self.quality = patch.quality.unwrap_or(self.quality);
+        self.brightness = patch.brightness.unwrap_or(self.brightness);
+        self.contrast = patch.contrast.unwrap_or(self.contrast);
+        self.saturation = patch.saturation.unwrap_or(self.saturation);
+        self.hmirror = patch.hmirror.unwrap_or(self.hmirror);
+        self.vflip = patch.vflip.unwrap_or(self.vflip);
+        self
+    }
+}
+
+/// The fields of `SensorSettings` to change, the rest stay as they are.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorPatch {
+    pub quality: Option<i32>,
+    pub brightness: Option<i32>,
+    pub contrast: Option<i32>,
+    pub saturation: Option<i32>,
+    pub hmirror: Option<bool>,
+    pub vflip: Option<bool>,
+}
+
+pub fn load() -> Result<SensorSettings> {
+    SETTINGS.lock().get(&SENSOR_SETTINGS)
+}
+
+fn apply_to(camera: &Camera, settings: &SensorSettings) -> Result<()> {
+    let sensor = camera.sensor();
+    sensor.set_quality(settings.quality)?;
+    sensor.set_brightness(settings.brightness)?;
+    sensor.set_contrast(settings.contrast)?;
+    sensor.set_saturation(settings.saturation)?;
+    sensor.set_hmirror(settings.hmirror)?;
+    sensor.set_vflip(settings.vflip)?;
+    Ok(())
+}
+
+/// Pushes the stored settings to the sensor, at boot.
+pub fn apply(camera: &Mutex<Camera>) -> Result<()> {
+    let settings = load()?;
+    let camera = camera.lock().map_err(|_| anyhow!("camera lock poisoned"))?;
+    apply_to(&camera, &settings)
+}
+
+/// Merges `patch` into the stored settings, applies and stores them.
+pub fn update(camera: &Mutex<Camera>, patch: &SensorPatch) -> Result<SensorSettings> {
+    let settings = load()?.patched(patch);
+    settings.validate()?;
+    {
+        let camera = camera.lock().map_err(|_| anyhow!("camera lock poisoned"))?;
+        apply_to(&camera, &settings)?;
+    }
+    SETTINGS.lock().set(&SENSOR_SETTINGS, &settings)?;
+    info!("sensor settings: {:?}", settings);
+    Ok(settings)
+}
+
+/// Captures a frame of at most `max_bytes`, compressing harder until it fits.
+pub fn capture_within(camera: &Mutex<Camera>, max_bytes: usize) -> Result<Vec<u8>> {
+    let stored = load()?.quality;
+    let camera = camera.lock().map_err(|_| anyhow!("camera lock poisoned"))?;
+    let sensor = camera.sensor();
+    let mut result = Err(anyhow!("no frame under {} bytes even at quality {}", max_bytes, WORST_QUALITY));
+    for quality in (stored..=WORST_QUALITY).step_by(QUALITY_STEP).skip(1).chain([WORST_QUALITY]) {
+        if let Err(e) = sensor.set_quality(quality) {
+            result = Err(e.into());
+            break;
+        }
+        // the frame already waiting was taken with the previous setting
+        drop(camera.get_framebuffer());
+        let Some(fb) = camera.get_framebuffer() else {
+            continue;
+        };
+        if fb.data().len() <= max_bytes {
+            debug!("recompressed to {} bytes at quality {}", fb.data().len(), quality);
+            result = Ok(fb.data().to_vec());
+            break;
+        }
+    }
+    sensor.set_quality(stored)?;
+    result
+}
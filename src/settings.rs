@@ -0,0 +1,126 @@
+use std::marker::PhantomData;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::nvs::{EspNvs, NvsDefault};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::networks::KnownNetwork;
+use crate::peripherals::NVS_DEFAULT_PARTITION;
+use crate::preludes::*;
+
+// Compile-time values from cfg.toml. They only seed the NVS settings on first
+// boot, afterwards the device is configured at runtime.
+#[toml_cfg::toml_config]
+pub struct Config {
+    #[default("")]
+    wifi_ssid: &'static str,
+    #[default("")]
+    wifi_psk: &'static str,
+}
+
+const NVS_NAMESPACE: &str = "settings";
+// limit imposed by the NVS API
+const MAX_KEY_LEN: usize = 15;
+
+lazy_static! {
+    pub static ref SETTINGS: Mutex<SettingsStore> =
+        Mutex::new(SettingsStore::open().unwrap());
+}
+
+/// A typed NVS entry together with the value it starts out with.
+pub struct Key<T> {
+    name: &'static str,
+    default: fn() -> T,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Key<T> {
+    pub const fn new(name: &'static str, default: fn() -> T) -> Self {
+        assert!(name.len() <= MAX_KEY_LEN);
+        Self { name, default, _phantom: PhantomData }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+pub const WIFI_NETWORKS: Key<Vec<KnownNetwork>> = Key::new("wifi_networks", default_networks);
+
+fn default_networks() -> Vec<KnownNetwork> {
+    if CONFIG.wifi_ssid.is_empty() {
+        return Vec::new();
+    }
+    vec![KnownNetwork {
+        ssid: CONFIG.wifi_ssid.to_owned(),
+        psk: CONFIG.wifi_psk.to_owned(),
+        priority: 0,
+    }]
+}
+
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl SettingsStore {
+    pub fn open() -> Result<Self> {
+        let nvs = EspNvs::new(NVS_DEFAULT_PARTITION.clone(), NVS_NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+
+    /// Returns the stored value, storing and returning the default if there is
+    /// none yet or what is stored no longer deserializes.
+    pub fn get<T: Serialize + DeserializeOwned>(&mut self, key: &Key<T>) -> Result<T> {
+        match self.read(key)? {
+            Some(Ok(value)) => return Ok(value),
+            // left alone, the key would fail every read and update from now on
+            Some(Err(e)) => {
+                error!("setting {} is corrupt, replacing it with its default: {}", key.name, e);
+                self.reset(key)?;
+            },
+            None => info!("seeding setting {} with its default", key.name),
+        }
+        let value = (key.default)();
+        self.set(key, &value)?;
+        Ok(value)
+    }
+
+    pub fn get_stored<T: DeserializeOwned>(&self, key: &Key<T>) -> Result<Option<T>> {
+        self.read(key)?
+            .transpose()
+            .map_err(|e| anyhow!("setting {} is corrupt: {}", key.name, e))
+    }
+
+    /// NVS errors fail the outer result, a blob that doesn't deserialize the inner one.
+    fn read<T: DeserializeOwned>(&self, key: &Key<T>) -> Result<Option<serde_json::Result<T>>> {
+        let Some(len) = self.nvs.blob_len(key.name)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0u8; len];
+        Ok(self.nvs.get_raw(key.name, &mut buf)?.map(serde_json::from_slice))
+    }
+
+    pub fn set<T: Serialize>(&mut self, key: &Key<T>, value: &T) -> Result<()> {
+        let raw = serde_json::to_vec(value)?;
+        self.nvs.set_raw(key.name, &raw)?;
+        Ok(())
+    }
+
+    /// Forgets the stored value, the next `get` falls back to the default.
+    pub fn reset<T>(&mut self, key: &Key<T>) -> Result<bool> {
+        Ok(self.nvs.remove(key.name)?)
+    }
+
+    pub fn update<T, F>(&mut self, key: &Key<T>, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce(&mut T) -> Result<()>,
+    {
+        let mut value = self.get(key)?;
+        f(&mut value)?;
+        self.set(key, &value)?;
+        Ok(value)
+    }
+}
@@ -1,4 +1,5 @@
 
+use std::fmt::Debug;
 use std::net::Ipv4Addr;
 use display_interface::DisplayError;
 // use display_interface::DisplayError;
@@ -8,7 +9,6 @@ use embedded_layout::{
     prelude::*,
     ViewGroup,
 };
-use esp_idf_hal::i2c::I2cDriver;
 use esp_idf_sys::EspError;
 use log::{info, warn, error};
 use once_cell::sync::Lazy;
@@ -16,16 +16,15 @@ use parking_lot::Mutex;
 use thiserror::Error;
 use embedded_graphics::prelude::*;
 use embedded_graphics::mono_font::MonoTextStyle;
-use ssd1306::mode::BufferedGraphicsMode;
-use ssd1306::{prelude::*, Ssd1306, I2CDisplayInterface};
 use embedded_graphics::{
     Drawable,
+    primitives::Rectangle,
     mono_font::{ascii::FONT_6X12, MonoTextStyleBuilder},
     pixelcolor::BinaryColor,
     text::{Baseline, Text},
 };
 
-use crate::{preludes::InfoReceiver, window::{LabeledText, InputStatsRow, LabeledTextBuilder}};
+use crate::{panel::Panel, preludes::InfoReceiver, window::{LabeledText, InputStatsRow, LabeledTextBuilder, StatusBar, IconStyle, ClockState}};
 
 #[derive(Error, Debug)]
 pub enum SmallDisplayError {
@@ -51,44 +50,26 @@ impl From<DisplayError> for SmallDisplayError {
     }
 }
 
-type Sdi<'d> = I2CInterface<I2cDriver<'d>>;
-type SmDisplay<'a> = Ssd1306<
-    I2CInterface<esp_idf_hal::i2c::I2cDriver<'a>>,
-    DisplaySize128x64,
-    BufferedGraphicsMode<DisplaySize128x64>
->;
-
-
-pub fn bld_interface(i2c: I2cDriver<'_>) -> Result<Sdi<'_>, SmallDisplayError> {
-    Ok(I2CDisplayInterface::new(i2c))
-}
-
-
 #[derive(Copy, Clone, Debug)]
-pub struct SmallDisplay<'d, DI, SIZE, MODE, COLOR> {
-    display: Ssd1306<DI, SIZE, MODE>,
-    text_style: MonoTextStyle<'d, COLOR>,
+pub struct SmallDisplay<'d, P> {
+    display: P,
+    text_style: MonoTextStyle<'d, BinaryColor>,
 }
 
-impl<'d, DI, SIZE> SmallDisplay<'d, DI, SIZE, BufferedGraphicsMode<SIZE>, BinaryColor>
+impl<'d, P> SmallDisplay<'d, P>
 where
-    DI: WriteOnlyDataCommand,
-    SIZE: DisplaySize,
+    P: Panel,
+    P::Error: Debug,
 {
-    pub fn new(
-        interface: DI,
-        size: SIZE,
-        rotation: DisplayRotation,
-    ) -> Self where SIZE: DisplaySize {
-        let display = Ssd1306::new(interface, size, rotation).into_buffered_graphics_mode();
+    pub fn new(display: P) -> Self {
         let text_style = *DEFAULT_TEXT_STYLE.lock();
         Self { display , text_style}
     }
     pub fn init(&mut self) {
-        let _ = self.display.init();//?;//.map_err(|e| SmallDisplayError::Display(e))
+        let _ = self.display.init_panel();
     }
     pub fn flush(&mut self) {
-        let _ = self.display.flush();//.map_err(SmallDisplayError::Display)
+        let _ = self.display.flush_panel();
     }
 
     pub fn write_text(&mut self, text: &str, position: Point, baseline: Baseline) -> Result<Point, SmallDisplayError> {
@@ -101,15 +82,6 @@ where
 }
 
 
-pub fn init_display(
-    interface: Sdi<'_>,
-    size: impl DisplaySize,
-    rotation: DisplayRotation,
-) -> anyhow::Result<Ssd1306<I2CInterface<I2cDriver<'_>>, impl DisplaySize, ssd1306::mode::BasicMode>> {
-    let disp = Ssd1306::new(interface, size, rotation);
-    Ok(disp)
-}
-
 // type DefaultTextStyle<'a> = MonoTextStyle<'a, BinaryColor>;
 // type MsgBoxText<'a> = Text<'a, DefaultTextStyle<'a>>;
 
@@ -126,75 +98,164 @@ static DEFAULT_TEXT_STYLE: Lazy<Mutex<MonoTextStyle<'static, BinaryColor>>> = La
 #[derive(Clone, Debug)]
 pub enum InfoUpdate {
     Addr(Ipv4Addr),
+    Network { gateway: Ipv4Addr, dns: Option<Ipv4Addr>, fixed: bool },
     Button(digital::PinState),
     Motion(digital::PinState),
     Msg(String),
+    Rssi(Option<i8>),
+    Battery { level: Option<u8>, charging: bool },
+    Armed(bool),
+    Recording(bool),
+    TimeSync(ClockState),
+    Gesture(Gesture),
+    /// Text for the message row, sent by an operator.
+    Notice(String),
+    /// Something failed that an operator should hear about.
+    Error(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Press,
+    LongPress,
+}
+
+impl InfoUpdate {
+    /// Applies the update to the icon row, returns false if it has no icon.
+    pub fn apply_to_status_bar<C: PixelColor>(&self, bar: &mut StatusBar<C>) -> bool {
+        match self {
+            InfoUpdate::Rssi(rssi) => bar.set_rssi(*rssi),
+            InfoUpdate::Battery { level, charging } => bar.set_battery(*level, *charging),
+            InfoUpdate::Armed(armed) => bar.set_armed(*armed),
+            InfoUpdate::Recording(recording) => bar.set_recording(*recording),
+            InfoUpdate::TimeSync(state) => bar.set_clock(*state),
+            InfoUpdate::Motion(level) => bar.set_motion(*level == digital::PinState::High),
+            InfoUpdate::Addr(_)
+            | InfoUpdate::Network { .. }
+            | InfoUpdate::Button(_)
+            | InfoUpdate::Msg(_)
+            | InfoUpdate::Gesture(_)
+            | InfoUpdate::Notice(_)
+            | InfoUpdate::Error(_) => return false,
+        }
+        true
+    }
 }
 
 
 // screen width 128
 // screen height 64
-// top line is the ip address
+// top line is the icon status bar
+// next line is the ip address
 // next line has PIR and Button
-// use the rest for messages
+// then the gateway and dns server, when the panel is tall enough
 
 const LONGEST_IPV4_ADDR: &str = "255.255.255.255";
 type StatusInfoGpio = Option<digital::PinState>;
 #[derive(Clone, Debug)]
-pub struct StatusInfo<'txt, C: PixelColor> {
+pub struct StatusInfo {
     address: Ipv4Addr,
     button_state: StatusInfoGpio,
     motion_state: StatusInfoGpio,
-    pub win: StatusWindow<'txt, C>,
     ip_string: String,
+    gateway_string: String,
+    dns_string: String,
+    message_string: String,
 }
 
-impl<'txt> Default for StatusInfo<'txt, BinaryColor> {
+impl Default for StatusInfo {
     fn default() -> Self {
         Self {
             address: Ipv4Addr::UNSPECIFIED,
             button_state: None,
             motion_state: None,
-            win: StatusWindow::new(*DEFAULT_TEXT_STYLE.lock()),
             ip_string: String::new(),
+            gateway_string: String::new(),
+            dns_string: String::new(),
+            message_string: String::new(),
         }
     }
 }
 
-
-impl<'txt> StatusInfo<'txt, BinaryColor> {
+impl StatusInfo {
     pub fn new(address: Ipv4Addr, button_state: StatusInfoGpio, motion_state: StatusInfoGpio) -> Self {
         Self {
             address,
             button_state,
             motion_state,
-            win: StatusWindow::new(*DEFAULT_TEXT_STYLE.lock()),
             ip_string: address.to_string(),
+            ..Default::default()
         }
     }
 
-    pub fn update<D: DrawTarget<Color=BinaryColor>>(&'txt mut self, info_update: &InfoUpdate, target: &mut D) -> Result<(), SmallDisplayError> {
-        let mut win = self.win;
-        warn!("display update");
+    /// Records the update, returns false if it doesn't change any text.
+    pub fn apply(&mut self, info_update: &InfoUpdate) -> bool {
         match info_update {
             InfoUpdate::Addr(address) => {
                 println!("ip: {}", address);
                 self.set_address(address.to_owned());
-                win.set_ip_text(&self.ip_string);
+            },
+            InfoUpdate::Network { gateway, dns, fixed } => {
+                self.gateway_string = if *fixed { format!("{} (s)", gateway) } else { gateway.to_string() };
+                self.dns_string = dns.map(|d| d.to_string()).unwrap_or_default();
             },
             InfoUpdate::Button(l) => {
                 println!("button");
                 self.button_state = Some(l.to_owned());
-                win.set_button_text(self.button_state_as_str());
             },
             InfoUpdate::Motion(l) => {
                 println!("motion");
                 self.motion_state = Some(l.to_owned());
-                win.set_motion_text(self.motion_state_as_str());
             },
-            InfoUpdate::Msg(ref m) => info!("update: {}", m),
+            InfoUpdate::Msg(ref m) => {
+                info!("update: {}", m);
+                return false;
+            },
+            InfoUpdate::Notice(text) => {
+                self.message_string = text.clone();
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// The notice row, only while there is a notice to show.
+    pub fn message_row(&self, area: &Rectangle) -> Option<LabeledText<'_, BinaryColor>> {
+        if self.message_string.is_empty() {
+            return None;
+        }
+        let row = LabeledTextBuilder::new("Msg:", *DEFAULT_TEXT_STYLE.lock()).with_text(&self.message_string).build();
+        Some(row.align_to(area, horizontal::Left, vertical::Top))
+    }
+
+    pub fn window(&self, area: &Rectangle) -> StatusWindow<'_, BinaryColor> {
+        let mut win = StatusWindow::new(*DEFAULT_TEXT_STYLE.lock());
+        win.set_ip_text(&self.ip_string);
+        win.set_button_text(self.button_state_as_str());
+        win.set_motion_text(self.motion_state_as_str());
+        win.set_gateway_text(&self.gateway_string);
+        win.set_dns_text(&self.dns_string);
+        win.align_to(area, horizontal::Left, vertical::Top)
+    }
+
+    pub fn update<D: DrawTarget<Color=BinaryColor>>(&mut self, info_update: &InfoUpdate, target: &mut D, area: &Rectangle) -> Result<(), SmallDisplayError> {
+        warn!("display update");
+        if !self.apply(info_update) {
+            return Ok(());
+        }
+        self.draw(target, area)
+    }
+
+    pub fn draw<D: DrawTarget<Color=BinaryColor>>(&self, target: &mut D, area: &Rectangle) -> Result<(), SmallDisplayError> {
+        // shorter strings would leave the tail of the previous ones behind
+        target.fill_solid(area, BinaryColor::Off).map_err(|_e| SmallDisplayError::Other("DisplayError".to_string()))?;
+        let mut window = self.window(area);
+        if let Some(message) = self.message_row(area) {
+            message.draw(target).map_err(|_e| SmallDisplayError::Other("DisplayError".to_string()))?;
+            // the other rows move down to make room, same margin as between them
+            window = window.align_to(&message, horizontal::Left, vertical::TopToBottom).translate(Point::new(0, 2));
         }
-        self.win.draw(target).map_err(|_e| SmallDisplayError::Other("DisplayError".to_string()))?;
+        window.draw_within(target, area).map_err(|_e| SmallDisplayError::Other("DisplayError".to_string()))?;
         // target.flush().map_err(|_e| SmallDisplayError::Other(format!("DisplayError")))?;
         Ok(())
     }
@@ -239,6 +300,8 @@ impl<'txt> StatusInfo<'txt, BinaryColor> {
 pub struct StatusWindow<'txt, C: PixelColor> {
     ip: LabeledText<'txt, C>,
     inputs: InputStatsRow<'txt, C>,
+    gateway: LabeledText<'txt, C>,
+    dns: LabeledText<'txt, C>,
 }
 
 impl<'txt, C: PixelColor> StatusWindow<'txt, C> {
@@ -246,12 +309,19 @@ impl<'txt, C: PixelColor> StatusWindow<'txt, C> {
         let ip_row = LabeledTextBuilder::new("IP:", style)
             .with_text(LONGEST_IPV4_ADDR)
             .build();
-        let mut input_row = InputStatsRow::new(style);
+        let input_row = InputStatsRow::new(style);
+        let gateway_row = LabeledTextBuilder::new("GW:", style)
+            .with_text(LONGEST_IPV4_ADDR)
+            .build();
+        let dns_row = LabeledTextBuilder::new("DNS:", style)
+            .with_text(LONGEST_IPV4_ADDR)
+            .build();
 
-        input_row.align_to_mut(&ip_row, horizontal::Left, vertical::TopToBottom);
         let s = Self {
             ip: LinearLayout::horizontal(ip_row).arrange().into_inner(),
             inputs: input_row,
+            gateway: gateway_row,
+            dns: dns_row,
         };
         LinearLayout::vertical(s).with_spacing(spacing::FixedMargin(2)).arrange().into_inner()
     }
@@ -265,32 +335,63 @@ impl<'txt, C: PixelColor> StatusWindow<'txt, C> {
     pub fn set_motion_text(&mut self, text: &'txt str) {
         self.inputs.set_motion_text(text)
     }
+    pub fn set_gateway_text(&mut self, text: &'txt str) {
+        self.gateway.set_text(text)
+    }
+    pub fn set_dns_text(&mut self, text: &'txt str) {
+        self.dns.set_text(text)
+    }
 
+    /// Draws only the rows that fit entirely inside `area`, so short panels drop the lower rows.
+    pub fn draw_within<D: DrawTarget<Color = C>>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error> {
+        let fits = |bounds: Rectangle| bounds.bottom_right().map_or(true, |p| area.contains(p));
+        if fits(self.ip.bounds()) {
+            self.ip.draw(target)?;
+        }
+        if fits(self.inputs.bounds()) {
+            self.inputs.draw(target)?;
+        }
+        if fits(self.gateway.bounds()) {
+            self.gateway.draw(target)?;
+        }
+        if fits(self.dns.bounds()) {
+            self.dns.draw(target)?;
+        }
+        Ok(())
+    }
 }
 
 
-pub async fn display_runner(interface: I2CInterface<I2cDriver<'_>>, rx: InfoReceiver) -> Result<(), SmallDisplayError> {
+pub async fn display_runner<P>(mut display: P, rx: InfoReceiver) -> Result<(), SmallDisplayError>
+where
+    P: Panel,
+    P::Error: Debug,
+{
     info!("started display_runner!!!!!!!");
     // let character_style: MonoTextStyle<'_, BinaryColor> = *DEFAULT_TEXT_STYLE.lock();
-    let mut display = init_display(interface, DisplaySize128x64, DisplayRotation::Rotate0).unwrap()
-        .into_buffered_graphics_mode();
-    let _ = display.init();
+    if let Err(e) = display.init_panel() {
+        error!("display init: {}", e);
+    }
 
 
     let display_bounds = display.bounding_box();
 
-    display.clear_buffer();
-    let _ = display.flush();
-
-    // let mut status_info = StatusInfo::default();
-    // status_info.win.align_to_mut(&display_bounds, horizontal::Left, vertical::Top);
-    // status_info.win.draw(&mut display)?;
-    // display.flush()?;
+    display.clear_panel();
+    let _ = display.flush_panel();
+
+    let mut status_bar = StatusBar::new(IconStyle::new(BinaryColor::On, BinaryColor::Off));
+    status_bar.align_to_mut(&display_bounds, horizontal::Left, vertical::Top);
+    let _ = status_bar.draw(&mut display);
+    let window_top = status_bar.bounds().bottom_right().map_or(0, |p| p.y) + 2;
+    let window_bounds = Rectangle::new(
+        Point::new(0, window_top),
+        Size::new(display_bounds.size.width, display_bounds.size.height.saturating_sub(window_top as u32)),
+    );
+
+    let mut status_info = StatusInfo::default();
+    status_info.draw(&mut display, &window_bounds)?;
+    let _ = display.flush_panel();
     loop {
-        let mut status_info = StatusInfo::default();
-        status_info.win.align_to_mut(&display_bounds, horizontal::Left, vertical::Top);
-        let _draw = status_info.win.draw(&mut display);
-            // let mut status_info = status_info.clone();
         let info_update = match rx.recv() {
             Ok(x) => x,
             Err(e) => {
@@ -313,8 +414,14 @@ pub async fn display_runner(interface: I2CInterface<I2cDriver<'_>>, rx: InfoRece
             InfoUpdate::Msg(ref text) => {
                 info!("disp: {}", text);
             },
+            ref other => {
+                info!("icon: {:?}", other);
+            },
+        }
+        if info_update.apply_to_status_bar(&mut status_bar) {
+            let _ = status_bar.draw(&mut display);
         }
-        status_info.update(&info_update, &mut display)?;
-        let _ = display.flush();
+        status_info.update(&info_update, &mut display, &window_bounds)?;
+        let _ = display.flush_panel();
     }
 }
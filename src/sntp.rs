@@ -0,0 +1,401 @@
+// SNTPv4 client (RFC 4330): the packet format and a single exchange.
+//
+// Only needs a std `UdpSocket`, which is how the tests talk to a stand-in
+// server on the loopback interface. Setting the clock from a sample and
+// choosing servers is up to ntp.rs.
+
+use std::net::UdpSocket;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+use thiserror::Error;
+
+const PACKET_LEN: usize = 48;
+const NTP_PORT: u16 = 123;
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+/// Seconds from 1900-01-01 (NTP era 0) to 1970-01-01.
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Error)]
+pub enum NtpError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("short packet: {0} bytes")]
+    ShortPacket(usize),
+    #[error("unexpected mode {0}")]
+    BadMode(u8),
+    #[error("unsupported version {0}")]
+    BadVersion(u8),
+    #[error("server clock is not synchronized")]
+    Unsynchronized,
+    #[error("kiss-o'-death: {0}")]
+    KissOfDeath(String),
+    #[error("invalid stratum {0}")]
+    BadStratum(u8),
+    #[error("reply does not match our request")]
+    OriginMismatch,
+    #[error("reply has no transmit timestamp")]
+    ZeroTransmit,
+    #[error("system clock is before 1970")]
+    ClockBeforeEpoch,
+}
+
+/// NTP 32.32 fixed point timestamp, seconds since 1900.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NtpTimestamp(pub u64);
+
+impl NtpTimestamp {
+    pub fn from_system_time(time: SystemTime) -> Result<Self, NtpError> {
+        let since_unix = time.duration_since(UNIX_EPOCH).map_err(|_| NtpError::ClockBeforeEpoch)?;
+        // wraps into era 1 in 2036, as the wire format does
+        let secs = since_unix.as_secs().wrapping_add(UNIX_OFFSET) & 0xffff_ffff;
+        let frac = (u64::from(since_unix.subsec_nanos()) << 32) / 1_000_000_000;
+        Ok(Self((secs << 32) | frac))
+    }
+
+    pub fn to_system_time(self) -> SystemTime {
+        let mut secs = self.0 >> 32;
+        // RFC 4330 section 3: with the MSB clear the timestamp is in era 1 (after 2036)
+        if secs & 0x8000_0000 == 0 {
+            secs += 1 << 32;
+        }
+        let nanos = ((self.0 & 0xffff_ffff) * 1_000_000_000) >> 32;
+        UNIX_EPOCH + Duration::new(secs - UNIX_OFFSET, nanos as u32)
+    }
+
+    /// `self - other` in microseconds, rounded to the nearest, correct across an era boundary.
+    fn micros_since(self, other: NtpTimestamp) -> i64 {
+        let diff = self.0.wrapping_sub(other.0) as i64;
+        ((i128::from(diff) * 1_000_000 + (1 << 31)) >> 32) as i64
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Packet {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_id: [u8; 4],
+    pub reference: NtpTimestamp,
+    pub originate: NtpTimestamp,
+    pub receive: NtpTimestamp,
+    pub transmit: NtpTimestamp,
+}
+
+impl Packet {
+    pub fn client_request(transmit: NtpTimestamp) -> Self {
+        Self {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit,
+            ..Default::default()
+        }
+    }
+
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let mut buf = [0; PACKET_LEN];
+        buf[0] = (self.leap << 6) | ((self.version & 0x7) << 3) | (self.mode & 0x7);
+        buf[1] = self.stratum;
+        buf[2] = self.poll as u8;
+        buf[3] = self.precision as u8;
+        BigEndian::write_u32(&mut buf[4..8], self.root_delay);
+        BigEndian::write_u32(&mut buf[8..12], self.root_dispersion);
+        buf[12..16].copy_from_slice(&self.reference_id);
+        BigEndian::write_u64(&mut buf[16..24], self.reference.0);
+        BigEndian::write_u64(&mut buf[24..32], self.originate.0);
+        BigEndian::write_u64(&mut buf[32..40], self.receive.0);
+        BigEndian::write_u64(&mut buf[40..48], self.transmit.0);
+        buf
+    }
+
+    /// Parses a packet, extension fields and MAC after the header are ignored.
+    pub fn decode(buf: &[u8]) -> Result<Self, NtpError> {
+        if buf.len() < PACKET_LEN {
+            return Err(NtpError::ShortPacket(buf.len()));
+        }
+        Ok(Self {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x7,
+            mode: buf[0] & 0x7,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: BigEndian::read_u32(&buf[4..8]),
+            root_dispersion: BigEndian::read_u32(&buf[8..12]),
+            reference_id: [buf[12], buf[13], buf[14], buf[15]],
+            reference: NtpTimestamp(BigEndian::read_u64(&buf[16..24])),
+            originate: NtpTimestamp(BigEndian::read_u64(&buf[24..32])),
+            receive: NtpTimestamp(BigEndian::read_u64(&buf[32..40])),
+            transmit: NtpTimestamp(BigEndian::read_u64(&buf[40..48])),
+        })
+    }
+
+    /// Checks a server reply against the request we sent.
+    pub fn validate_reply(&self, sent: NtpTimestamp) -> Result<(), NtpError> {
+        if self.mode != MODE_SERVER {
+            return Err(NtpError::BadMode(self.mode));
+        }
+        if !(1..=VERSION).contains(&self.version) {
+            return Err(NtpError::BadVersion(self.version));
+        }
+        if self.stratum == 0 {
+            let code = String::from_utf8_lossy(&self.reference_id).trim_end_matches('\0').to_owned();
+            return Err(NtpError::KissOfDeath(code));
+        }
+        if self.stratum > 15 {
+            return Err(NtpError::BadStratum(self.stratum));
+        }
+        if self.leap == LEAP_UNSYNCHRONIZED {
+            return Err(NtpError::Unsynchronized);
+        }
+        if self.originate != sent {
+            return Err(NtpError::OriginMismatch);
+        }
+        if self.transmit.0 == 0 {
+            return Err(NtpError::ZeroTransmit);
+        }
+        Ok(())
+    }
+}
+
+/// Result of one exchange.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// How far the local clock is behind the server, in microseconds.
+    pub offset_micros: i64,
+    /// Round trip time minus the server's processing time, in microseconds.
+    pub delay_micros: i64,
+    pub stratum: u8,
+}
+
+impl Sample {
+    /// Offset and delay from the four timestamps: client send (t1), server
+    /// receive (t2), server send (t3) and client receive (t4).
+    pub fn from_timestamps(t1: NtpTimestamp, t2: NtpTimestamp, t3: NtpTimestamp, t4: NtpTimestamp, stratum: u8) -> Self {
+        let offset_micros = (t2.micros_since(t1) + t3.micros_since(t4)) / 2;
+        let delay_micros = (t4.micros_since(t1) - t3.micros_since(t2)).max(0);
+        Self { offset_micros, delay_micros, stratum }
+    }
+}
+
+/// One request/reply exchange with `server`, which is a host name or address without the port.
+pub fn query(client: &UdpSocket, server: &str) -> Result<Sample, NtpError> {
+    client.connect((server, NTP_PORT))?;
+    query_connected(client)
+}
+
+/// Same as `query` for a socket already connected to the server, lets tests use any port.
+pub fn query_connected(client: &UdpSocket) -> Result<Sample, NtpError> {
+    let t1 = NtpTimestamp::from_system_time(SystemTime::now())?;
+    client.send(&Packet::client_request(t1).encode())?;
+
+    let mut buf = [0; 128];
+    let len = client.recv(&mut buf)?;
+    let t4 = NtpTimestamp::from_system_time(SystemTime::now())?;
+
+    let reply = Packet::decode(&buf[..len])?;
+    reply.validate_reply(t1)?;
+    Ok(Sample::from_timestamps(t1, reply.receive, reply.transmit, t4, reply.stratum))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const SECOND: u64 = 1 << 32;
+
+    fn ts(secs: u64, micros: u64) -> NtpTimestamp {
+        NtpTimestamp((secs << 32) | ((micros << 32) / 1_000_000))
+    }
+
+    #[test]
+    fn timestamps_round_trip_through_system_time() {
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 250_000_000);
+        let ntp = NtpTimestamp::from_system_time(time).unwrap();
+        assert_eq!(ntp.0 >> 32, 1_700_000_000 + UNIX_OFFSET);
+        assert_eq!(ntp.0 & 0xffff_ffff, SECOND / 4);
+        assert_eq!(ntp.to_system_time(), time);
+    }
+
+    #[test]
+    fn timestamps_after_2036_land_in_era_1() {
+        // 2040-01-01, past the 32 bit seconds rollover on 2036-02-07
+        let time = UNIX_EPOCH + Duration::from_secs(2_208_988_800);
+        let ntp = NtpTimestamp::from_system_time(time).unwrap();
+        assert!(ntp.0 >> 32 < 0x8000_0000);
+        assert_eq!(ntp.to_system_time(), time);
+
+        let before = NtpTimestamp((0xffff_ffff << 32) | (SECOND / 2));
+        assert_eq!(ts(0, 0).micros_since(before), 500_000);
+        assert_eq!(before.micros_since(ts(0, 0)), -500_000);
+    }
+
+    #[test]
+    fn sample_from_symmetric_paths() {
+        // server 2s ahead, 100ms each way, 10ms spent in the server
+        let t1 = ts(1000, 0);
+        let t2 = ts(1002, 100_000);
+        let t3 = ts(1002, 110_000);
+        let t4 = ts(1000, 210_000);
+        let sample = Sample::from_timestamps(t1, t2, t3, t4, 2);
+        assert_eq!(sample, Sample { offset_micros: 2_000_000, delay_micros: 200_000, stratum: 2 });
+    }
+
+    #[test]
+    fn sample_with_local_clock_ahead() {
+        let t1 = ts(1000, 0);
+        let t2 = ts(995, 20_000);
+        let t3 = ts(995, 20_000);
+        let t4 = ts(1000, 40_000);
+        let sample = Sample::from_timestamps(t1, t2, t3, t4, 1);
+        assert_eq!(sample.offset_micros, -5_000_000);
+        assert_eq!(sample.delay_micros, 40_000);
+    }
+
+    #[test]
+    fn negative_delay_is_clamped() {
+        // a server that claims to have taken longer than the whole round trip
+        let sample = Sample::from_timestamps(ts(10, 0), ts(10, 0), ts(10, 500_000), ts(10, 100_000), 3);
+        assert_eq!(sample.delay_micros, 0);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packet = Packet {
+            leap: 1,
+            version: 4,
+            mode: MODE_SERVER,
+            stratum: 2,
+            poll: 6,
+            precision: -20,
+            root_delay: 0x0001_8000,
+            root_dispersion: 42,
+            reference_id: *b"GPS\0",
+            reference: ts(1, 2),
+            originate: ts(3, 4),
+            receive: ts(5, 6),
+            transmit: ts(7, 8),
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded[0], 0b01_100_100);
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+        assert!(matches!(Packet::decode(&encoded[..47]), Err(NtpError::ShortPacket(47))));
+    }
+
+    fn reply_to(sent: NtpTimestamp) -> Packet {
+        Packet {
+            version: VERSION,
+            mode: MODE_SERVER,
+            stratum: 1,
+            reference_id: *b"PPS\0",
+            originate: sent,
+            receive: ts(2000, 0),
+            transmit: ts(2000, 1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reply_validation() {
+        let sent = ts(1000, 0);
+        assert!(reply_to(sent).validate_reply(sent).is_ok());
+
+        let check = |change: fn(&mut Packet)| {
+            let mut reply = reply_to(sent);
+            change(&mut reply);
+            reply.validate_reply(sent).unwrap_err()
+        };
+        assert!(matches!(check(|p| p.mode = MODE_CLIENT), NtpError::BadMode(3)));
+        assert!(matches!(check(|p| p.version = 5), NtpError::BadVersion(5)));
+        assert!(matches!(check(|p| p.version = 0), NtpError::BadVersion(0)));
+        assert!(matches!(check(|p| p.stratum = 16), NtpError::BadStratum(16)));
+        assert!(matches!(check(|p| p.leap = LEAP_UNSYNCHRONIZED), NtpError::Unsynchronized));
+        assert!(matches!(check(|p| p.originate = ts(999, 0)), NtpError::OriginMismatch));
+        assert!(matches!(check(|p| p.transmit = NtpTimestamp(0)), NtpError::ZeroTransmit));
+        match check(|p| {
+            p.stratum = 0;
+            p.reference_id = *b"RATE";
+        }) {
+            NtpError::KissOfDeath(code) => assert_eq!(code, "RATE"),
+            other => panic!("expected a kiss-o'-death, got {:?}", other),
+        }
+    }
+
+    /// Answers one request the way `respond` says, with `None` staying silent.
+    fn stand_in(respond: impl FnOnce(Packet) -> Option<Vec<u8>> + Send + 'static) -> UdpSocket {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 128];
+            let (len, peer) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::decode(&buf[..len]).unwrap();
+            if let Some(reply) = respond(request) {
+                server.send_to(&reply, peer).unwrap();
+            }
+        });
+        client
+    }
+
+    /// A server whose clock runs `offset` ahead of ours.
+    fn server_time(offset: Duration) -> NtpTimestamp {
+        NtpTimestamp::from_system_time(SystemTime::now() + offset).unwrap()
+    }
+
+    #[test]
+    fn query_measures_the_offset_of_a_local_server() {
+        let client = stand_in(|request| {
+            assert_eq!((request.version, request.mode), (VERSION, MODE_CLIENT));
+            let mut reply = reply_to(request.transmit);
+            reply.receive = server_time(Duration::from_secs(30));
+            reply.transmit = server_time(Duration::from_secs(30));
+            Some(reply.encode().to_vec())
+        });
+        let sample = query_connected(&client).unwrap();
+        assert_eq!(sample.stratum, 1);
+        assert!((sample.offset_micros - 30_000_000).abs() < 200_000, "offset {}", sample.offset_micros);
+        assert!((0..200_000).contains(&sample.delay_micros), "delay {}", sample.delay_micros);
+    }
+
+    #[test]
+    fn query_rejects_a_reply_to_another_request() {
+        let client = stand_in(|request| {
+            let mut reply = reply_to(NtpTimestamp(request.transmit.0 + 1));
+            reply.transmit = server_time(Duration::ZERO);
+            Some(reply.encode().to_vec())
+        });
+        assert!(matches!(query_connected(&client), Err(NtpError::OriginMismatch)));
+    }
+
+    #[test]
+    fn query_rejects_kiss_o_death_and_short_replies() {
+        let client = stand_in(|request| {
+            let mut reply = reply_to(request.transmit);
+            reply.stratum = 0;
+            reply.reference_id = *b"DENY";
+            Some(reply.encode().to_vec())
+        });
+        assert!(matches!(query_connected(&client), Err(NtpError::KissOfDeath(code)) if code == "DENY"));
+
+        let client = stand_in(|request| Some(reply_to(request.transmit).encode()[..40].to_vec()));
+        assert!(matches!(query_connected(&client), Err(NtpError::ShortPacket(40))));
+    }
+
+    #[test]
+    fn query_times_out_on_a_silent_server() {
+        let client = stand_in(|_| None);
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(matches!(query_connected(&client), Err(NtpError::Io(_))));
+    }
+}
@@ -0,0 +1,52 @@
+// Flash storage mounted as a FAT filesystem, used through `std::fs`.
+
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use esp_idf_sys::{esp_vfs_fat_mount_config_t, esp_vfs_fat_spiflash_mount_rw_wl, wl_handle_t, WL_INVALID_HANDLE};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::preludes::*;
+
+pub const MOUNT_POINT: &str = "/storage";
+const PARTITION_LABEL: &str = "storage";
+const MAX_OPEN_FILES: i32 = 8;
+/// Allocation unit of the filesystem; every file takes whole clusters.
+///
+/// Of the 512K partition, wear levelling keeps about 16K and FAT its tables
+/// and root directory, which leaves roughly 115 clusters. The recorder and the
+/// outbox get 40 each; the rest holds their directories, the capture journal
+/// while it is compacted, and whatever is being written.
+pub const CLUSTER_BYTES: u64 = 4096;
+
+lazy_static! {
+    static ref WL_HANDLE: Mutex<Option<wl_handle_t>> = Mutex::new(None);
+}
+
+/// Mounts the `storage` partition, formatting it if it has no filesystem yet.
+pub fn mount() -> Result<()> {
+    let mut handle = WL_INVALID_HANDLE as wl_handle_t;
+    let base_path = CString::new(MOUNT_POINT)?;
+    let label = CString::new(PARTITION_LABEL)?;
+    let config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: true,
+        max_files: MAX_OPEN_FILES,
+        allocation_unit_size: CLUSTER_BYTES as usize,
+        ..Default::default()
+    };
+    esp!(unsafe { esp_vfs_fat_spiflash_mount_rw_wl(base_path.as_ptr(), label.as_ptr(), &config, &mut handle) })?;
+    info!("storage mounted at {}", MOUNT_POINT);
+    *WL_HANDLE.lock() = Some(handle);
+    Ok(())
+}
+
+pub fn is_mounted() -> bool {
+    WL_HANDLE.lock().is_some()
+}
+
+/// A directory below the mount point.
+pub fn path(relative: impl AsRef<Path>) -> PathBuf {
+    Path::new(MOUNT_POINT).join(relative)
+}
@@ -0,0 +1,70 @@
+// `{{name}}` placeholders in the JSON bodies webhooks post.
+//
+// The template is read once from start to end. Each placeholder is swapped
+// for its value as it is found and the value is never looked at again, so an
+// event detail that itself says `{{hostname}}` arrives as written.
+
+/// Replaces `{{name}}` with the JSON-escaped value, without quotes. Unknown
+/// placeholders and unmatched braces are left as they are.
+pub fn render(template: &str, fields: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find("}}") else {
+            out.push_str(&rest[start..]);
+            return out;
+        };
+        match fields.iter().find(|(name, _)| *name == &after[..end]) {
+            Some((_, value)) => {
+                let escaped = serde_json::to_string(value).unwrap_or_default();
+                out.push_str(&escaped[1..escaped.len().saturating_sub(1)]);
+            },
+            None => out.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(detail: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("event", "motion_start".to_owned()),
+            ("hostname", "ttgo-camera".to_owned()),
+            ("detail", detail.to_owned()),
+        ]
+    }
+
+    #[test]
+    fn fills_in_every_placeholder() {
+        let body = render(r#"{"event":"{{event}}","host":"{{hostname}}","again":"{{event}}"}"#, &fields("x"));
+        assert_eq!(body, r#"{"event":"motion_start","host":"ttgo-camera","again":"motion_start"}"#);
+    }
+
+    #[test]
+    fn leaves_placeholders_inside_values_alone() {
+        let body = render(r#"{"detail":"{{detail}}","host":"{{hostname}}"}"#, &fields("on {{hostname}}"));
+        assert_eq!(body, r#"{"detail":"on {{hostname}}","host":"ttgo-camera"}"#);
+        let body = render("{{detail}}{{event}}", &fields("{{"));
+        assert_eq!(body, "{{motion_start");
+    }
+
+    #[test]
+    fn escapes_values_for_json() {
+        let body = render(r#"{"detail":"{{detail}}"}"#, &fields("say \"hi\"\n"));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap()["detail"], "say \"hi\"\n");
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders_and_stray_braces() {
+        assert_eq!(render("{{nope}} {{event}}", &fields("")), "{{nope}} motion_start");
+        assert_eq!(render("{{event}} {{event", &fields("")), "motion_start {{event");
+        assert_eq!(render("}} {", &fields("")), "}} {");
+    }
+}
@@ -0,0 +1,72 @@
+// Local time.
+//
+// The zone is stored as an IANA name from `ZONES` or a POSIX TZ string. It is
+// handed to newlib through `TZ`/`tzset` for `localtime`, and parsed by
+// posix_tz.rs as well so chrono can format local times without a zoneinfo
+// database.
+
+use std::env;
+
+use anyhow::Result;
+use chrono::{DateTime, FixedOffset, Utc};
+use esp_idf_sys::tzset;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+
+use crate::posix_tz::{resolve, PosixTz};
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+
+pub const TIMEZONE: Key<String> = Key::new("timezone", || "UTC".to_owned());
+
+lazy_static! {
+    static ref CURRENT: Mutex<PosixTz> = Mutex::new(PosixTz::utc());
+}
+
+fn apply_zone(zone: &str) -> Result<()> {
+    let posix = resolve(zone)?;
+    let parsed = PosixTz::parse(&posix)?;
+    env::set_var("TZ", &posix);
+    unsafe { tzset() };
+    info!("timezone: {} ({})", zone, posix);
+    *CURRENT.lock() = parsed;
+    Ok(())
+}
+
+/// Applies the stored zone, falls back to UTC if it doesn't parse.
+pub fn apply() -> Result<()> {
+    let zone = zone();
+    if let Err(e) = apply_zone(&zone) {
+        error!("timezone {:?}: {}", zone, e);
+        apply_zone("UTC")?;
+    }
+    Ok(())
+}
+
+pub fn zone() -> String {
+    SETTINGS.lock().get(&TIMEZONE).unwrap_or_else(|e| {
+        error!("timezone setting: {}", e);
+        "UTC".to_owned()
+    })
+}
+
+pub fn set_zone(zone: &str) -> Result<()> {
+    resolve(zone)?;
+    SETTINGS.lock().set(&TIMEZONE, &zone.to_owned())?;
+    apply_zone(zone)
+}
+
+pub fn to_local(utc: DateTime<Utc>) -> DateTime<FixedOffset> {
+    CURRENT.lock().to_local(utc)
+}
+
+pub fn now_local() -> DateTime<FixedOffset> {
+    to_local(Utc::now())
+}
+
+/// Abbreviation of the zone in effect right now, e.g. "CEST".
+pub fn abbreviation() -> String {
+    let tz = CURRENT.lock();
+    let (_, name, _) = tz.offset_at(Utc::now().timestamp());
+    name.to_owned()
+}
@@ -0,0 +1,478 @@
+// Pieces for sending stored files over HTTP.
+//
+// Conditional and range requests for single files, and tar or zip streams
+// of several. The archives are written entry by entry without compression,
+// JPEGs do not shrink anyway, so their length is known before the first byte
+// goes out. The tests take the archives apart again the way tar and unzip
+// would.
+
+const TAR_BLOCK: u64 = 512;
+const ZIP_LOCAL_HEADER: u64 = 30;
+const ZIP_DATA_DESCRIPTOR: u64 = 16;
+const ZIP_CENTRAL_HEADER: u64 = 46;
+const ZIP_END: u64 = 22;
+/// Sizes and the CRC follow the data, so entries can be streamed.
+const ZIP_FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+const ZIP_VERSION: u16 = 20;
+
+/// Strong validator for a stored file, which never changes once written.
+pub fn etag(id: u64, size: u64, modified_ms: u64) -> String {
+    format!("\"{:x}-{:x}-{:x}\"", id, size, modified_ms)
+}
+
+/// Whether an `If-None-Match` value names `etag`; weak tags match too.
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Whether an `If-Range` value names `etag`. Only the same strong tag counts,
+/// a weak one or a date gets the whole body.
+pub fn if_range_matches(header: &str, etag: &str) -> bool {
+    header.trim() == etag
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    Full,
+    /// Inclusive byte positions.
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
+
+impl RangeRequest {
+    /// Reads a `Range` header for a body of `len` bytes.
+    ///
+    /// Only single ranges are served, anything else gets the whole body as
+    /// the RFC allows.
+    pub fn parse(header: Option<&str>, len: u64) -> Self {
+        let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+            return RangeRequest::Full;
+        };
+        if spec.contains(',') {
+            return RangeRequest::Full;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 {
+                    return RangeRequest::Unsatisfiable;
+                }
+                (len.saturating_sub(suffix), len.saturating_sub(1))
+            },
+            _ => return RangeRequest::Full,
+        };
+        if start >= len {
+            return RangeRequest::Unsatisfiable;
+        }
+        RangeRequest::Partial { start, end }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "tar" => Some(ArchiveFormat::Tar),
+            "zip" => Some(ArchiveFormat::Zip),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified_unix: u64,
+}
+
+struct Written {
+    name: String,
+    size: u64,
+    crc: u32,
+    modified_unix: u64,
+    offset: u64,
+}
+
+/// Produces the bytes around each file of an archive; the file data itself
+/// goes through `data` on its way to the client.
+pub struct ArchiveWriter {
+    format: ArchiveFormat,
+    offset: u64,
+    current: Option<Written>,
+    written: Vec<Written>,
+}
+
+impl ArchiveWriter {
+    pub fn new(format: ArchiveFormat) -> Self {
+        Self { format, offset: 0, current: None, written: Vec::new() }
+    }
+
+    /// Length of the whole archive holding `entries`.
+    pub fn content_length(format: ArchiveFormat, entries: &[ArchiveEntry]) -> u64 {
+        match format {
+            ArchiveFormat::Tar => entries.iter().map(|e| TAR_BLOCK + e.size.div_ceil(TAR_BLOCK) * TAR_BLOCK).sum::<u64>() + 2 * TAR_BLOCK,
+            ArchiveFormat::Zip => {
+                entries
+                    .iter()
+                    .map(|e| ZIP_LOCAL_HEADER + ZIP_DATA_DESCRIPTOR + ZIP_CENTRAL_HEADER + 2 * e.name.len() as u64 + e.size)
+                    .sum::<u64>()
+                    + ZIP_END
+            },
+        }
+    }
+
+    /// What comes before the data of `entry`.
+    pub fn begin(&mut self, entry: &ArchiveEntry) -> Vec<u8> {
+        let header = match self.format {
+            ArchiveFormat::Tar => tar_header(entry),
+            ArchiveFormat::Zip => {
+                let (time, date) = dos_time(entry.modified_unix);
+                let mut header = Vec::with_capacity(ZIP_LOCAL_HEADER as usize + entry.name.len());
+                header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+                header.extend_from_slice(&ZIP_VERSION.to_le_bytes());
+                header.extend_from_slice(&(ZIP_FLAG_DATA_DESCRIPTOR | ZIP_FLAG_UTF8).to_le_bytes());
+                header.extend_from_slice(&0u16.to_le_bytes()); // stored
+                header.extend_from_slice(&time.to_le_bytes());
+                header.extend_from_slice(&date.to_le_bytes());
+                header.extend_from_slice(&[0u8; 12]); // CRC and sizes, in the descriptor
+                header.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+                header.extend_from_slice(&0u16.to_le_bytes());
+                header.extend_from_slice(entry.name.as_bytes());
+                header
+            },
+        };
+        self.current = Some(Written {
+            name: entry.name.clone(),
+            size: 0,
+            crc: !0,
+            modified_unix: entry.modified_unix,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u64;
+        header
+    }
+
+    pub fn data(&mut self, chunk: &[u8]) {
+        if let Some(current) = self.current.as_mut() {
+            current.size += chunk.len() as u64;
+            current.crc = crc32_update(current.crc, chunk);
+        }
+        self.offset += chunk.len() as u64;
+    }
+
+    /// What comes after the data of the current entry.
+    pub fn end(&mut self) -> Vec<u8> {
+        let Some(mut current) = self.current.take() else {
+            return Vec::new();
+        };
+        current.crc = !current.crc;
+        let trailer = match self.format {
+            ArchiveFormat::Tar => vec![0u8; (current.size.div_ceil(TAR_BLOCK) * TAR_BLOCK - current.size) as usize],
+            ArchiveFormat::Zip => {
+                let mut descriptor = Vec::with_capacity(ZIP_DATA_DESCRIPTOR as usize);
+                descriptor.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+                descriptor.extend_from_slice(&current.crc.to_le_bytes());
+                descriptor.extend_from_slice(&(current.size as u32).to_le_bytes());
+                descriptor.extend_from_slice(&(current.size as u32).to_le_bytes());
+                descriptor
+            },
+        };
+        self.offset += trailer.len() as u64;
+        self.written.push(current);
+        trailer
+    }
+
+    /// The end of the archive.
+    pub fn finish(self) -> Vec<u8> {
+        match self.format {
+            ArchiveFormat::Tar => vec![0u8; 2 * TAR_BLOCK as usize],
+            ArchiveFormat::Zip => {
+                let mut central = Vec::new();
+                for file in &self.written {
+                    let (time, date) = dos_time(file.modified_unix);
+                    central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+                    central.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // made by
+                    central.extend_from_slice(&ZIP_VERSION.to_le_bytes()); // needed
+                    central.extend_from_slice(&(ZIP_FLAG_DATA_DESCRIPTOR | ZIP_FLAG_UTF8).to_le_bytes());
+                    central.extend_from_slice(&0u16.to_le_bytes());
+                    central.extend_from_slice(&time.to_le_bytes());
+                    central.extend_from_slice(&date.to_le_bytes());
+                    central.extend_from_slice(&file.crc.to_le_bytes());
+                    central.extend_from_slice(&(file.size as u32).to_le_bytes());
+                    central.extend_from_slice(&(file.size as u32).to_le_bytes());
+                    central.extend_from_slice(&(file.name.len() as u16).to_le_bytes());
+                    central.extend_from_slice(&[0u8; 8]); // extra, comment, disk, internal attributes
+                    central.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+                    central.extend_from_slice(&(file.offset as u32).to_le_bytes());
+                    central.extend_from_slice(file.name.as_bytes());
+                }
+                let count = self.written.len() as u16;
+                let mut end = central;
+                let central_len = end.len() as u32;
+                end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+                end.extend_from_slice(&[0u8; 4]); // disk numbers
+                end.extend_from_slice(&count.to_le_bytes());
+                end.extend_from_slice(&count.to_le_bytes());
+                end.extend_from_slice(&central_len.to_le_bytes());
+                end.extend_from_slice(&(self.offset as u32).to_le_bytes());
+                end.extend_from_slice(&0u16.to_le_bytes());
+                end
+            },
+        }
+    }
+}
+
+fn tar_header(entry: &ArchiveEntry) -> Vec<u8> {
+    fn octal(field: &mut [u8], value: u64) {
+        let digits = format!("{:0width$o}", value, width = field.len() - 1);
+        field[..digits.len()].copy_from_slice(digits.as_bytes());
+    }
+
+    let mut header = vec![0u8; TAR_BLOCK as usize];
+    let name = entry.name.as_bytes();
+    header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], entry.size);
+    octal(&mut header[136..148], entry.modified_unix);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is taken with its own field as spaces
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    let digits = format!("{:06o}\0 ", sum);
+    header[148..156].copy_from_slice(digits.as_bytes());
+    header
+}
+
+/// MS-DOS time and date, which start in 1980 and count seconds in twos.
+fn dos_time(unix: u64) -> (u16, u16) {
+    const DOS_EPOCH: u64 = 315_532_800;
+    if unix < DOS_EPOCH {
+        return (0, (1 << 5) | 1);
+    }
+    let days = (unix / 86_400) as i64;
+    let rem = unix % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let time = (((rem / 3600) << 11) | ((rem % 3600 / 60) << 5) | ((rem % 60) / 2)) as u16;
+    let date = (((year - 1980).min(127) as u16) << 9) | ((month as u16) << 5) | day as u16;
+    (time, date)
+}
+
+/// Proleptic Gregorian date of a day count since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILES: [(&str, &[u8]); 3] = [
+        ("20261019-120301_motion_000001.jpg", b"\xff\xd8first frame\xff\xd9"),
+        ("empty.jpg", b""),
+        ("20261019-120302_motion_000002.jpg", &[0x5a; 1024]),
+    ];
+    /// 2026-10-19 12:03:01 UTC
+    const MODIFIED: u64 = 1_792_411_381;
+
+    fn entries() -> Vec<ArchiveEntry> {
+        FILES
+            .iter()
+            .map(|(name, data)| ArchiveEntry { name: (*name).to_owned(), size: data.len() as u64, modified_unix: MODIFIED })
+            .collect()
+    }
+
+    /// Streams `FILES` through the writer the way the handler does, in small chunks.
+    fn archive(format: ArchiveFormat) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(format);
+        let mut out = Vec::new();
+        for (entry, (_, data)) in entries().iter().zip(FILES) {
+            out.extend(writer.begin(entry));
+            for chunk in data.chunks(100) {
+                writer.data(chunk);
+                out.extend_from_slice(chunk);
+            }
+            out.extend(writer.end());
+        }
+        out.extend(writer.finish());
+        out
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        !crc32_update(!0, bytes)
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn octal(field: &[u8]) -> u64 {
+        let digits = std::str::from_utf8(field).unwrap().trim_matches(|c| c == '\0' || c == ' ');
+        u64::from_str_radix(digits, 8).unwrap()
+    }
+
+    #[test]
+    fn ranges() {
+        let parse = |header| RangeRequest::parse(Some(header), 1000);
+        assert_eq!(RangeRequest::parse(None, 1000), RangeRequest::Full);
+        assert_eq!(parse("bytes=0-99"), RangeRequest::Partial { start: 0, end: 99 });
+        assert_eq!(parse("bytes=500-"), RangeRequest::Partial { start: 500, end: 999 });
+        assert_eq!(parse("bytes=990-5000"), RangeRequest::Partial { start: 990, end: 999 });
+        assert_eq!(parse("bytes=-100"), RangeRequest::Partial { start: 900, end: 999 });
+        assert_eq!(parse("bytes=-5000"), RangeRequest::Partial { start: 0, end: 999 });
+        assert_eq!(parse(" bytes=7-7 "), RangeRequest::Partial { start: 7, end: 7 });
+
+        assert_eq!(parse("bytes=1000-"), RangeRequest::Unsatisfiable);
+        assert_eq!(parse("bytes=-0"), RangeRequest::Unsatisfiable);
+        assert_eq!(RangeRequest::parse(Some("bytes=0-"), 0), RangeRequest::Unsatisfiable);
+
+        // what is not a single valid range gets the whole body
+        for header in ["bytes=0-1,5-6", "bytes=9-2", "bytes=x-1", "bytes=5", "items=0-1", "bytes=-"] {
+            assert_eq!(parse(header), RangeRequest::Full, "{}", header);
+        }
+    }
+
+    #[test]
+    fn if_none_match_is_weak_and_if_range_strong() {
+        let tag = etag(1, 2048, 1_792_411_381_000);
+        assert_eq!(tag, "\"1-800-1a1540b2d08\"");
+        for header in [tag.clone(), format!("W/{}", tag), format!("\"other\", {}", tag), "*".to_owned()] {
+            assert!(etag_matches(&header, &tag), "{}", header);
+        }
+        assert!(!etag_matches("\"other\"", &tag));
+
+        assert!(if_range_matches(&tag, &tag));
+        assert!(if_range_matches(&format!(" {} ", tag), &tag));
+        for header in [format!("W/{}", tag), "*".to_owned(), "\"other\"".to_owned(), "Mon, 19 Oct 2026 12:03:01 GMT".to_owned()] {
+            assert!(!if_range_matches(&header, &tag), "{}", header);
+        }
+    }
+
+    #[test]
+    fn crc32_matches_zlib() {
+        assert_eq!(crc32(b"hello"), 0x3610_a686);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        // streamed in pieces, as the archive entries are
+        assert_eq!(!crc32_update(crc32_update(!0, b"hel"), b"lo"), 0x3610_a686);
+    }
+
+    #[test]
+    fn dos_times() {
+        assert_eq!(dos_time(MODIFIED), ((12 << 11) | (3 << 5), (46 << 9) | (10 << 5) | 19));
+        assert_eq!(dos_time(0), (0, (1 << 5) | 1));
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    }
+
+    #[test]
+    fn tar_layout() {
+        let tar = archive(ArchiveFormat::Tar);
+        assert_eq!(tar.len() as u64, ArchiveWriter::content_length(ArchiveFormat::Tar, &entries()));
+        assert_eq!(tar.len() % TAR_BLOCK as usize, 0);
+
+        let mut at = 0;
+        for (name, data) in FILES {
+            let header = &tar[at..at + 512];
+            let stored_name = &header[..100];
+            assert_eq!(&stored_name[..name.len()], name.as_bytes());
+            assert_eq!(&header[257..263], b"ustar\0");
+            assert_eq!(header[156], b'0');
+            assert_eq!(octal(&header[124..136]), data.len() as u64);
+            assert_eq!(octal(&header[136..148]), MODIFIED);
+            let unsigned: u64 = header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { 32 } else { u64::from(b) }).sum();
+            assert_eq!(octal(&header[148..156]), unsigned);
+
+            at += 512;
+            assert_eq!(&tar[at..at + data.len()], data);
+            let padded = data.len().div_ceil(512) * 512;
+            assert!(tar[at + data.len()..at + padded].iter().all(|&b| b == 0));
+            at += padded;
+        }
+        // two zero blocks close the archive
+        assert_eq!(tar.len() - at, 1024);
+        assert!(tar[at..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn zip_layout() {
+        let zip = archive(ArchiveFormat::Zip);
+        assert_eq!(zip.len() as u64, ArchiveWriter::content_length(ArchiveFormat::Zip, &entries()));
+
+        // unzip starts from the end of central directory record
+        let end = zip.len() - ZIP_END as usize;
+        assert_eq!(u32_at(&zip, end), 0x0605_4b50);
+        assert_eq!((u16_at(&zip, end + 8), u16_at(&zip, end + 10)), (3, 3));
+        let central_len = u32_at(&zip, end + 12) as usize;
+        let central_start = u32_at(&zip, end + 16) as usize;
+        assert_eq!(central_start + central_len, end);
+
+        let mut at = central_start;
+        for (name, data) in FILES {
+            assert_eq!(u32_at(&zip, at), 0x0201_4b50);
+            let crc = u32_at(&zip, at + 16);
+            assert_eq!(crc, crc32(data));
+            assert_eq!((u32_at(&zip, at + 20), u32_at(&zip, at + 24)), (data.len() as u32, data.len() as u32));
+            let name_len = u16_at(&zip, at + 28) as usize;
+            assert_eq!(&zip[at + 46..at + 46 + name_len], name.as_bytes());
+
+            let local = u32_at(&zip, at + 42) as usize;
+            assert_eq!(u32_at(&zip, local), 0x0403_4b50);
+            assert_eq!(u16_at(&zip, local + 6), ZIP_FLAG_DATA_DESCRIPTOR | ZIP_FLAG_UTF8);
+            assert_eq!(u16_at(&zip, local + 8), 0, "stored");
+            assert_eq!(&zip[local + 30..local + 30 + name_len], name.as_bytes());
+            let data_start = local + 30 + name_len;
+            assert_eq!(&zip[data_start..data_start + data.len()], data);
+            let descriptor = data_start + data.len();
+            assert_eq!(u32_at(&zip, descriptor), 0x0807_4b50);
+            assert_eq!((u32_at(&zip, descriptor + 4), u32_at(&zip, descriptor + 8)), (crc, data.len() as u32));
+
+            at += 46 + name_len;
+        }
+        assert_eq!(at, end);
+    }
+}
@@ -0,0 +1,333 @@
+// Pushes captured frames to an HTTP endpoint.
+//
+// Captures are requested from anywhere with `request_capture` (motion, the
+// API) or by the snapshot schedule, and handled one at a time on the upload
+// thread so a slow server never holds up the camera lock for long. The same
+// captures feed the MQTT camera topic when it asks for them.
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use embedded_svc::http::Method;
+use esp_camera_rs::Camera;
+use esp_idf_sys::esp_random;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::{self, TimeStatus};
+use crate::delivery;
+use crate::http::{self, Body, Http, HttpError};
+use crate::link;
+use crate::mqtt;
+use crate::multipart::{FormFile, UploadRequest};
+use crate::outbox::Kind;
+use crate::peripherals::device_id;
+use crate::power;
+use crate::preludes::*;
+use crate::recorder;
+use crate::settings::{Key, SETTINGS};
+
+pub const UPLOAD_SETTINGS: Key<UploadSettings> = Key::new("upload", UploadSettings::default);
+
+const MAX_HEADERS: usize = 8;
+const SETTINGS_CHECK: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    // a capture already waiting covers any trigger that comes in meanwhile
+    static ref CAPTURES: (flume::Sender<Trigger>, flume::Receiver<Trigger>) = flume::bounded(1);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadFormat {
+    /// `multipart/form-data` with the image and the metadata as fields.
+    Multipart,
+    /// The bare JPEG, metadata only in headers.
+    Raw,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadSettings {
+    pub enabled: bool,
+    pub url: String,
+    pub format: UploadFormat,
+    /// Form field holding the image in multipart uploads.
+    pub field_name: String,
+    /// Extra headers, usually `Authorization`.
+    pub headers: Vec<(String, String)>,
+    pub on_motion: bool,
+    /// Take a snapshot this often, 0 turns the schedule off.
+    pub snapshot_interval_secs: u32,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            format: UploadFormat::Multipart,
+            field_name: "image".to_owned(),
+            headers: Vec::new(),
+            on_motion: true,
+            snapshot_interval_secs: 0,
+        }
+    }
+}
+
+impl UploadSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(anyhow!("url must be an http:// or https:// URL"));
+        }
+        if self.field_name.is_empty() || self.field_name.contains(['"', '\r', '\n']) {
+            return Err(anyhow!("invalid field_name"));
+        }
+        if self.headers.len() > MAX_HEADERS {
+            return Err(anyhow!("at most {} headers", MAX_HEADERS));
+        }
+        for (name, value) in &self.headers {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+                return Err(anyhow!("invalid header name {:?}", name));
+            }
+            if value.contains(['\r', '\n']) {
+                return Err(anyhow!("header {} has a line break", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy that is safe to show, header values may be credentials.
+    pub fn redacted(&self) -> Self {
+        Self {
+            headers: self.headers.iter().map(|(name, _)| (name.clone(), "***".to_owned())).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+pub fn load() -> Result<UploadSettings> {
+    SETTINGS.lock().get(&UPLOAD_SETTINGS)
+}
+
+/// Stores new settings; header values sent as "***" keep what was stored before.
+pub fn store(mut settings: UploadSettings) -> Result<()> {
+    let current = load()?;
+    for (name, value) in settings.headers.iter_mut() {
+        if value == "***" {
+            if let Some((_, old)) = current.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                *value = old.clone();
+            }
+        }
+    }
+    settings.validate()?;
+    SETTINGS.lock().set(&UPLOAD_SETTINGS, &settings)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    Motion,
+    Schedule,
+    Manual,
+}
+
+impl Trigger {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trigger::Motion => "motion",
+            Trigger::Schedule => "schedule",
+            Trigger::Manual => "manual",
+        }
+    }
+}
+
+/// A frame and what is known about it.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub jpeg: Vec<u8>,
+    pub taken: SystemTime,
+    pub trigger: Trigger,
+    /// Clock status when the frame was taken, it may have changed by the time it is sent.
+    pub time_status: TimeStatus,
+}
+
+/// What the outbox keeps next to a queued frame.
+#[derive(Serialize, Deserialize)]
+struct CaptureMeta {
+    trigger: Trigger,
+    taken_unix_ms: u64,
+    time_status: TimeStatus,
+}
+
+impl Capture {
+    /// RFC 3339 in UTC, or nothing if the clock was never set.
+    pub fn timestamp(&self) -> Option<String> {
+        if self.time_status == TimeStatus::Unsynced {
+            return None;
+        }
+        Some(DateTime::<Utc>::from(self.taken).to_rfc3339_opts(SecondsFormat::Millis, true))
+    }
+
+    pub fn metadata(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("device_id", device_id()),
+            ("trigger", self.trigger.as_str().to_owned()),
+            ("time_status", format!("{:?}", self.time_status).to_lowercase()),
+        ];
+        if let Some(timestamp) = self.timestamp() {
+            fields.push(("timestamp", timestamp));
+        }
+        fields
+    }
+}
+
+/// Sends one capture as configured.
+pub fn upload(client: &mut Http, settings: &UploadSettings, capture: &Capture) -> Result<(), HttpError> {
+    let _busy = power::busy();
+    let boundary = format!("ttgo-camera-{:08x}{:08x}", unsafe { esp_random() }, unsafe { esp_random() });
+    let filename = format!("{}-{}.jpg", device_id(), capture.trigger.as_str());
+    let form = (settings.format == UploadFormat::Multipart).then_some(FormFile {
+        boundary: &boundary,
+        field: &settings.field_name,
+        filename: &filename,
+    });
+    let request = UploadRequest::new(&capture.metadata(), &settings.headers, form);
+
+    let len = request.content_length(&capture.jpeg);
+    let mut reader = request.body(&capture.jpeg);
+    client.request(Method::Post, &settings.url, &request.header_refs(), Body::Reader { len, reader: &mut reader }, |_| Ok(()))
+}
+
+/// Asks the upload thread for a capture.
+pub fn request_capture(trigger: Trigger) {
+    let _ = CAPTURES.0.try_send(trigger);
+}
+
+fn capture(camera: &Mutex<Camera>, trigger: Trigger) -> Result<Capture> {
+    let camera = camera.lock().map_err(|_| anyhow!("camera lock poisoned"))?;
+    let fb = camera.get_framebuffer().ok_or_else(|| anyhow!("unable to get framebuffer"))?;
+    Ok(Capture {
+        jpeg: fb.data().to_vec(),
+        taken: SystemTime::now(),
+        trigger,
+        time_status: clock::status(),
+    })
+}
+
+fn enqueue(capture: &Capture) -> Result<()> {
+    let meta = CaptureMeta {
+        trigger: capture.trigger,
+        taken_unix_ms: capture.taken.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64),
+        time_status: capture.time_status,
+    };
+    delivery::enqueue(Kind::Upload, &meta, &capture.jpeg)
+}
+
+/// Sends a capture the outbox held on to, with the current settings.
+pub fn send_queued(meta: &serde_json::Value, jpeg: Vec<u8>) -> Result<()> {
+    let meta: CaptureMeta = serde_json::from_value(meta.clone())?;
+    let settings = load()?;
+    if settings.url.is_empty() {
+        return Err(anyhow!("no upload url configured"));
+    }
+    let capture = Capture {
+        jpeg,
+        taken: UNIX_EPOCH + Duration::from_millis(meta.taken_unix_ms),
+        trigger: meta.trigger,
+        time_status: meta.time_status,
+    };
+    let mut client = http::create_default_client()?;
+    upload(&mut client, &settings, &capture)?;
+    Ok(())
+}
+
+fn handle(camera: &Mutex<Camera>, trigger: Trigger, tx: &InfoSender) -> Result<()> {
+    let settings = load()?;
+    let wanted = match trigger {
+        Trigger::Motion => settings.enabled && settings.on_motion,
+        Trigger::Schedule => settings.enabled && settings.snapshot_interval_secs > 0,
+        Trigger::Manual => !settings.url.is_empty(),
+    };
+    let to_mqtt = mqtt::wants_snapshot(trigger);
+    let to_storage = recorder::wants(trigger);
+    if !wanted && !to_mqtt && !to_storage {
+        return Ok(());
+    }
+
+    let capture = capture(camera, trigger)?;
+    if to_storage {
+        if let Err(e) = recorder::save(&capture) {
+            error!("recorder: {}", e);
+        }
+    }
+    if to_mqtt {
+        if let Err(e) = mqtt::publish_snapshot(camera, &capture) {
+            warn!("mqtt snapshot: {}", e);
+        }
+    }
+    if !wanted {
+        return Ok(());
+    }
+    // anything already queued goes first, keep the order
+    if !link::is_connected() || !delivery::is_empty() {
+        return enqueue(&capture);
+    }
+
+    tx.send(InfoUpdate::Recording(true))?;
+    let started = Instant::now();
+    let result = http::create_default_client().and_then(|mut client| upload(&mut client, &settings, &capture));
+    tx.send(InfoUpdate::Recording(false))?;
+    match result {
+        Ok(()) => {
+            info!("uploaded {} bytes ({}) in {}ms", capture.jpeg.len(), trigger.as_str(), started.elapsed().as_millis());
+            Ok(())
+        },
+        Err(e) if e.is_transient() => {
+            warn!("upload failed, queueing it: {}", e);
+            enqueue(&capture)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn run(camera: Arc<Mutex<Camera>>, tx: InfoSender) {
+    let mut next_snapshot: Option<Instant> = None;
+    loop {
+        let interval = load().map(|s| s.snapshot_interval_secs).unwrap_or(0);
+        if interval == 0 {
+            next_snapshot = None;
+        } else if next_snapshot.is_none() {
+            next_snapshot = Some(Instant::now() + Duration::from_secs(interval.into()));
+        }
+
+        // wake up now and then to pick up a changed schedule
+        let check = Instant::now() + SETTINGS_CHECK;
+        let deadline = next_snapshot.map_or(check, |at| at.min(check));
+        let trigger = match CAPTURES.1.recv_deadline(deadline) {
+            Ok(trigger) => trigger,
+            Err(flume::RecvTimeoutError::Timeout) if next_snapshot.is_some_and(|at| Instant::now() >= at) => {
+                next_snapshot = None;
+                Trigger::Schedule
+            },
+            Err(flume::RecvTimeoutError::Timeout) => continue,
+            Err(flume::RecvTimeoutError::Disconnected) => return,
+        };
+
+        if let Err(e) = handle(&camera, trigger, &tx) {
+            error!("upload ({}): {}", trigger.as_str(), e);
+            let _ = tx.send(InfoUpdate::Error(format!("upload failed: {}", e)));
+        }
+    }
+}
+
+pub fn start(camera: Arc<Mutex<Camera>>, tx: InfoSender) -> Result<()> {
+    thread::Builder::new()
+        .name("upload".to_owned())
+        .stack_size(16 * 1024)
+        .spawn(move || run(camera, tx))?;
+    Ok(())
+}
@@ -0,0 +1,345 @@
+// JSON webhooks for device events.
+//
+// Events are picked out of the `InfoUpdate` stream by `observe` and posted
+// from the webhook thread, so a slow automation server never stalls the
+// display or the Wi-Fi task. Deliveries that fail while offline go through
+// the outbox like uploads do.
+
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use embedded_hal::digital::PinState;
+use embedded_svc::http::Method;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::clock::{self, TimeStatus};
+use crate::commands;
+use crate::delivery;
+use crate::http::{self, Body, HttpError};
+use crate::link;
+use crate::mdns;
+use crate::outbox::Kind;
+use crate::peripherals::device_id;
+use crate::preludes::*;
+use crate::settings::{Key, SETTINGS};
+use crate::small_display::Gesture;
+use crate::template::render;
+
+pub const WEBHOOK_SETTINGS: Key<WebhookSettings> = Key::new("webhook", WebhookSettings::default);
+
+const MAX_HEADERS: usize = 8;
+const MAX_TEMPLATE_LEN: usize = 2048;
+const PENDING_EVENTS: usize = 16;
+
+pub const DEFAULT_TEMPLATE: &str = r#"{"event":"{{event}}","device_id":"{{device_id}}","hostname":"{{hostname}}","timestamp":"{{timestamp}}","time_status":"{{time_status}}","detail":"{{detail}}","thumbnail_url":"{{thumbnail_url}}"}"#;
+
+lazy_static! {
+    static ref EVENTS: (flume::Sender<Event>, flume::Receiver<Event>) = flume::bounded(PENDING_EVENTS);
+    static ref OBSERVED: Mutex<Observed> = Mutex::new(Observed::default());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Boot,
+    MotionStart,
+    MotionEnd,
+    ButtonPress,
+    ButtonLongPress,
+    WifiConnected,
+    WifiReconnected,
+    Error,
+    /// Sent from the API to check the setup, ignores the event filter.
+    Test,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Boot => "boot",
+            EventKind::MotionStart => "motion_start",
+            EventKind::MotionEnd => "motion_end",
+            EventKind::ButtonPress => "button_press",
+            EventKind::ButtonLongPress => "button_long_press",
+            EventKind::WifiConnected => "wifi_connected",
+            EventKind::WifiReconnected => "wifi_reconnected",
+            EventKind::Error => "error",
+            EventKind::Test => "test",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    pub url: String,
+    /// Events to send, all of them when empty.
+    pub events: Vec<EventKind>,
+    /// JSON with `{{placeholders}}`, see `DEFAULT_TEMPLATE`.
+    pub template: Option<String>,
+    /// Key for an HMAC-SHA256 of the body, sent hex encoded in `signature_header`.
+    pub secret: Option<String>,
+    pub signature_header: String,
+    pub headers: Vec<(String, String)>,
+    /// Adds a link to a fresh snapshot from this camera.
+    pub thumbnail: bool,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            events: Vec::new(),
+            template: None,
+            secret: None,
+            signature_header: "X-Signature-256".to_owned(),
+            headers: Vec::new(),
+            thumbnail: false,
+        }
+    }
+}
+
+impl WebhookSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.enabled && !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(anyhow!("url must be an http:// or https:// URL"));
+        }
+        if let Some(template) = &self.template {
+            if template.len() > MAX_TEMPLATE_LEN {
+                return Err(anyhow!("template is longer than {} bytes", MAX_TEMPLATE_LEN));
+            }
+            // placeholders render as plain strings, so the sample must still parse
+            serde_json::from_str::<serde_json::Value>(&render(template, &Event::sample().fields(false)))
+                .map_err(|e| anyhow!("template does not render to JSON: {}", e))?;
+        }
+        if self.headers.len() > MAX_HEADERS {
+            return Err(anyhow!("at most {} headers", MAX_HEADERS));
+        }
+        validate_header_name(&self.signature_header)?;
+        for (name, value) in &self.headers {
+            validate_header_name(name)?;
+            if value.contains(['\r', '\n']) {
+                return Err(anyhow!("header {} has a line break", name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.enabled && (kind == EventKind::Test || self.events.is_empty() || self.events.contains(&kind))
+    }
+
+    /// Copy that is safe to show, the secret and header values stay on the device.
+    pub fn redacted(&self) -> Self {
+        Self {
+            secret: self.secret.as_ref().map(|_| "***".to_owned()),
+            headers: self.headers.iter().map(|(name, _)| (name.clone(), "***".to_owned())).collect(),
+            ..self.clone()
+        }
+    }
+}
+
+fn validate_header_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+        return Err(anyhow!("invalid header name {:?}", name));
+    }
+    Ok(())
+}
+
+pub fn load() -> Result<WebhookSettings> {
+    SETTINGS.lock().get(&WEBHOOK_SETTINGS)
+}
+
+/// Stores new settings; "***" for the secret or a header value keeps the stored one.
+pub fn store(mut settings: WebhookSettings) -> Result<()> {
+    let current = load()?;
+    if settings.secret.as_deref() == Some("***") {
+        settings.secret = current.secret.clone();
+    }
+    for (name, value) in settings.headers.iter_mut() {
+        if value == "***" {
+            if let Some((_, old)) = current.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+                *value = old.clone();
+            }
+        }
+    }
+    settings.validate()?;
+    SETTINGS.lock().set(&WEBHOOK_SETTINGS, &settings)
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub kind: EventKind,
+    pub detail: String,
+    pub at: SystemTime,
+    pub time_status: TimeStatus,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, detail: impl Into<String>) -> Self {
+        Self { kind, detail: detail.into(), at: SystemTime::now(), time_status: clock::status() }
+    }
+
+    fn sample() -> Self {
+        Self::new(EventKind::MotionStart, "sample \"detail\"")
+    }
+
+    /// Placeholder values for the template.
+    pub fn fields(&self, thumbnail: bool) -> Vec<(&'static str, String)> {
+        let timestamp = match self.time_status {
+            TimeStatus::Unsynced => String::new(),
+            _ => DateTime::<Utc>::from(self.at).to_rfc3339_opts(SecondsFormat::Millis, true),
+        };
+        let thumbnail_url = match link::ip_config() {
+            Some(ip) if thumbnail => format!("http://{}/", ip.ip),
+            _ => String::new(),
+        };
+        vec![
+            ("event", self.kind.as_str().to_owned()),
+            ("device_id", device_id()),
+            ("hostname", mdns::hostname()),
+            ("timestamp", timestamp),
+            ("time_status", format!("{:?}", self.time_status).to_lowercase()),
+            ("detail", self.detail.clone()),
+            ("thumbnail_url", thumbnail_url),
+            ("uptime_secs", link::uptime_secs().to_string()),
+        ]
+    }
+}
+
+/// Hex HMAC-SHA256 of `body`.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// What the outbox keeps next to a queued body.
+#[derive(Serialize, Deserialize)]
+struct WebhookMeta {
+    event: EventKind,
+}
+
+fn post(settings: &WebhookSettings, body: &[u8]) -> Result<(), HttpError> {
+    let signature = settings.secret.as_deref().map(|secret| format!("sha256={}", sign(secret, body)));
+    let mut headers: Vec<(&str, &str)> = vec![("Content-Type", "application/json")];
+    headers.extend(settings.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    if let Some(signature) = &signature {
+        headers.push((settings.signature_header.as_str(), signature.as_str()));
+    }
+    let mut client = http::create_default_client()?;
+    client.request(Method::Post, &settings.url, &headers, Body::Bytes(body), |_| Ok(()))
+}
+
+/// Sends a body the outbox held on to, signed with the current secret.
+pub fn send_queued(meta: &serde_json::Value, body: Vec<u8>) -> Result<()> {
+    let meta: WebhookMeta = serde_json::from_value(meta.clone())?;
+    let settings = load()?;
+    if settings.url.is_empty() {
+        return Err(anyhow!("no webhook url configured"));
+    }
+    post(&settings, &body)?;
+    debug!("webhook: delivered queued {}", meta.event.as_str());
+    Ok(())
+}
+
+fn deliver(event: &Event) -> Result<()> {
+    let settings = load()?;
+    if !settings.wants(event.kind) {
+        return Ok(());
+    }
+    let template = settings.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+    let body = render(template, &event.fields(settings.thumbnail)).into_bytes();
+    let meta = WebhookMeta { event: event.kind };
+
+    if !link::is_connected() || !delivery::is_empty() {
+        return delivery::enqueue(Kind::Webhook, &meta, &body);
+    }
+    match post(&settings, &body) {
+        Ok(()) => {
+            debug!("webhook: sent {}", event.kind.as_str());
+            Ok(())
+        },
+        Err(e) if e.is_transient() => {
+            warn!("webhook failed, queueing it: {}", e);
+            delivery::enqueue(Kind::Webhook, &meta, &body)
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Queues an event for the webhook thread, dropped if it is far behind.
+pub fn notify(event: Event) {
+    if EVENTS.0.try_send(event).is_err() {
+        warn!("webhook: too many pending events, dropping one");
+    }
+}
+
+#[derive(Default)]
+struct Observed {
+    motion: Option<PinState>,
+    connected_before: bool,
+    address: Option<Ipv4Addr>,
+}
+
+/// Turns display updates into webhook events.
+pub fn observe(update: &InfoUpdate) {
+    let mut observed = OBSERVED.lock();
+    let event = match update {
+        InfoUpdate::Motion(level) => {
+            let previous = observed.motion.replace(*level);
+            match (previous, level) {
+                _ if !commands::is_armed() => None,
+                (Some(PinState::Low), PinState::High) => Some(Event::new(EventKind::MotionStart, "")),
+                (Some(PinState::High), PinState::Low) => Some(Event::new(EventKind::MotionEnd, "")),
+                _ => None,
+            }
+        },
+        InfoUpdate::Gesture(Gesture::Press) => Some(Event::new(EventKind::ButtonPress, "")),
+        InfoUpdate::Gesture(Gesture::LongPress) => Some(Event::new(EventKind::ButtonLongPress, "provisioning")),
+        InfoUpdate::Addr(ip) if observed.address != Some(*ip) || !observed.connected_before => {
+            let kind = if observed.connected_before { EventKind::WifiReconnected } else { EventKind::WifiConnected };
+            observed.connected_before = true;
+            observed.address = Some(*ip);
+            Some(Event::new(kind, ip.to_string()))
+        },
+        InfoUpdate::Rssi(None) => {
+            // link is down, the next address is a reconnect even if it is the same
+            observed.address = None;
+            None
+        },
+        InfoUpdate::Error(message) => Some(Event::new(EventKind::Error, message.clone())),
+        _ => None,
+    };
+    drop(observed);
+    if let Some(event) = event {
+        notify(event);
+    }
+}
+
+fn run() {
+    while let Ok(event) = EVENTS.1.recv() {
+        // failures are logged but never reported as error events, that could loop
+        if let Err(e) = deliver(&event) {
+            error!("webhook {}: {}", event.kind.as_str(), e);
+        }
+    }
+}
+
+pub fn start() -> Result<()> {
+    notify(Event::new(EventKind::Boot, format!("fw {}", mdns::FIRMWARE_VERSION)));
+    thread::Builder::new()
+        .name("webhook".to_owned())
+        .stack_size(16 * 1024)
+        .spawn(run)?;
+    Ok(())
+}
@@ -2,117 +2,266 @@ use anyhow::Result;
 use anyhow::anyhow;
 use embedded_svc::wifi::AccessPointInfo;
 use esp_idf_svc::wifi::{ClientConfiguration, Configuration};
-use crate::{ntp::ntp_sync, small_display::InfoUpdate};
+use crate::{clock, small_display::InfoUpdate, networks::{load_networks, Candidate, rank_candidates}};
+use crate::provisioning::{provisioning_requests, run_provisioning};
+use crate::connection::{BackoffConfig, ConnAction, ConnEvent, ConnectionManager};
+use crate::ipconfig::{self, IpSettings};
+use crate::link;
+use crate::power;
+use crate::peripherals::{ESP_TASK_TIMER_SVR, SYS_LOOP};
 
+use embassy_futures::select::{select3, select4, Either3, Either4};
+use lazy_static::lazy_static;
 use log::{info, warn};
 use crate::preludes::*;
-use esp_idf_svc::wifi::{AsyncWifi, EspWifi};
-use esp_idf_sys::esp_wifi_clear_ap_list;
-use std::thread;
-
-
-pub async fn initial_wifi_connect(wifi: &mut AsyncWifi<EspWifi<'static>>, tx: InfoSender) -> Result<AccessPointInfo> {
-    tx.send(InfoUpdate::Msg("initial_wifi".to_owned()))?;
-    wifi.start().await?;
-
-    // let scan_result = wifi_scan(wifi).await?;
-    match wifi_scan(wifi).await {
-        Ok(ap) => {
-            info!("Found configured access point {} on channel {}", ap.ssid, ap.channel);
-            let mut ssid: heapless::String<32> = heapless::String::new();
-            ssid.push_str(CONFIG.wifi_ssid).unwrap();
-            let mut psk: heapless::String<64> = heapless::String::new();
-            psk.push_str(CONFIG.wifi_psk).unwrap();
-            wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-                ssid,
-                password: psk,
-                channel: Some(ap.channel),
-                auth_method: match ap.auth_method {
-                    Some(a) => a,
-                    None => embedded_svc::wifi::AuthMethod::default(),
-                },
-                ..Default::default()
-            }))?;
-            wifi.connect().await?;
-            wifi.wait_netif_up().await?;
-            let ip = wifi.wifi().sta_netif().get_ip_info()?;
-            warn!("ip: {:?}", ip);
-            tx.send(InfoUpdate::Addr(ip.ip))?;
-            info!("Connected to Wi-fi, now trying setting time from ntp.");
-            ntp_sync()?;
-
-            Ok(ap)
+use std::time::Instant;
+use esp_idf_svc::netif::IpEvent;
+use esp_idf_svc::wifi::{AsyncWifi, EspWifi, WifiEvent};
+use esp_idf_sys::{esp_random, esp_wifi_clear_ap_list};
 
-        },
-        Err(e) => Err(e),
+/// Where the Wi-Fi task sends the result of a scan someone asked for.
+type ScanReply = flume::Sender<Result<Vec<AccessPointInfo>>>;
+
+lazy_static! {
+    static ref RECONNECT_REQUESTS: (flume::Sender<()>, flume::Receiver<()>) = flume::bounded(1);
+    static ref SCAN_REQUESTS: (flume::Sender<ScanReply>, flume::Receiver<ScanReply>) = flume::bounded(1);
+}
+
+/// Scans and pairs the visible access points with the known networks, best first.
+pub async fn scan_candidates(wifi: &mut AsyncWifi<EspWifi<'static>>) -> Result<Vec<Candidate>> {
+    if !wifi.is_started()? {
+        wifi.start().await?;
     }
+    let scan = wifi_scan(wifi).await?;
+    let known = load_networks()?;
+    let candidates = rank_candidates(&scan, &known);
+    info!("{} of {} known networks in range", candidates.len(), known.len());
+    Ok(candidates)
 }
 
-pub async fn app_wifi_loop(mut wifi: AsyncWifi<EspWifi<'static>>, tx: InfoSender) -> Result<()> {
-    let mut count = 0u8;
-    let mut fail_count = 0u8;
+/// Tries the candidates in order and returns the access point that was joined.
+pub async fn join_best(wifi: &mut AsyncWifi<EspWifi<'static>>, candidates: &[Candidate]) -> Result<AccessPointInfo> {
+    for candidate in candidates {
+        let ap = &candidate.ap;
+        info!("Trying access point {} ({:02x?}) on channel {}, priority {}, rssi {}",
+            ap.ssid, ap.bssid, ap.channel, candidate.network.priority, ap.signal_strength);
+        match connect_candidate(wifi, candidate).await {
+            Ok(()) => return Ok(ap.clone()),
+            Err(e) => {
+                warn!("failed to join {}: {}", ap.ssid, e);
+                let _ = wifi.disconnect().await;
+            },
+        }
+    }
+    Err(anyhow!("couldn't associate with any known network"))
+}
 
-    warn!("wifi_loop");
-    tx.send(InfoUpdate::Msg("msg".to_owned()))?;
-    initial_wifi_connect(&mut wifi, tx.clone()).await?;
+fn report_link(wifi: &AsyncWifi<EspWifi<'static>>, ap: &AccessPointInfo, tx: &InfoSender, fixed: bool) -> Result<()> {
+    let ip = wifi.wifi().sta_netif().get_ip_info()?;
+    warn!("ip: {:?}", ip);
+    link::set_connected(&ap.ssid, &ip);
+    tx.send(InfoUpdate::Addr(ip.ip))?;
+    tx.send(InfoUpdate::Network { gateway: ip.subnet.gateway, dns: ip.dns, fixed })?;
+    tx.send(InfoUpdate::Rssi(Some(ap.signal_strength)))?;
+    Ok(())
+}
 
-    loop {
-        // sleep(Duration::from_secs(10)).await;
-        thread::sleep(Duration::from_secs(10));
-        count += 1;
-
-        if count == 8 {
-            count = 0;
-            // Wi-Fi driver will fault when trying scanning while connected to AP
-            // if let Err(e) = wifi_scan(&mut wifi).await {
-            //     error!("wifi_scan: {}", e);
-            // }
-
-            if fail_count > 0 {
-                tx.send(InfoUpdate::Msg("msg".to_owned()))?;
-
-                info!("Network failure detected, try re-connecting...");
-                wifi.disconnect().await?;
-                wifi.stop().await?;
-                initial_wifi_connect(&mut wifi, tx.clone()).await?;
-                info!("Connected to Wi-fi, now trying setting time from ntp.");
-                ntp_sync()?;
-            }
-        }
+async fn connect_candidate(wifi: &mut AsyncWifi<EspWifi<'static>>, candidate: &Candidate) -> Result<()> {
+    let mut ssid: heapless::String<32> = heapless::String::new();
+    ssid.push_str(&candidate.network.ssid).map_err(|_| anyhow!("ssid too long"))?;
+    let mut psk: heapless::String<64> = heapless::String::new();
+    psk.push_str(&candidate.network.psk).map_err(|_| anyhow!("psk too long"))?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid,
+        bssid: Some(candidate.ap.bssid),
+        password: psk,
+        channel: Some(candidate.ap.channel),
+        auth_method: match candidate.ap.auth_method {
+            Some(a) => a,
+            None => embedded_svc::wifi::AuthMethod::default(),
+        },
+        ..Default::default()
+    }))?;
+    if let Err(e) = power::apply_listen_interval() {
+        warn!("listen interval: {}", e);
+    }
+    wifi.connect().await?;
+    wifi.wait_netif_up().await?;
+    Ok(())
+}
 
-        if count >= 6 && fail_count > 0 {
-            tx.send(InfoUpdate::Msg("msg".to_owned()))?;
-            if let Err(e) = ntp_sync() {
-                error!("ntp_sync: {}", e);
-                fail_count += 1;
-            } else {
-                fail_count = 0;
-            };
-        }
+/// Swaps in a new station netif when the stored IP settings or the DHCP hostname
+/// derived from the mDNS one changed since the last connect.
+async fn apply_ip_settings(wifi: &mut AsyncWifi<EspWifi<'static>>, applied: &mut Option<(IpSettings, Option<String>)>) -> Result<()> {
+    let settings = ipconfig::load()?;
+    let dhcp_hostname = settings.dhcp_hostname();
+    if applied.as_ref().is_some_and(|(s, name)| *s == settings && *name == dhcp_hostname) {
+        return Ok(());
+    }
+    info!("station ip settings: {:?}, dhcp hostname {:?}", settings, dhcp_hostname);
+    let netif = settings.sta_netif()?;
+    let started = wifi.is_started()?;
+    if started {
+        wifi.stop().await?;
+    }
+    wifi.wifi_mut().swap_netif_sta(netif)?;
+    if started {
+        wifi.start().await?;
     }
+    *applied = Some((settings, dhcp_hostname));
+    Ok(())
 }
 
-#[toml_cfg::toml_config]
-pub struct Config {
-    #[default("")]
-    wifi_ssid: &'static str,
-    #[default("")]
-    wifi_psk: &'static str,
+pub fn request_reconnect() {
+    let _ = RECONNECT_REQUESTS.0.try_send(());
 }
 
+/// Scans on behalf of the HTTP API and waits up to `timeout` for the result.
+///
+/// The scan runs on the Wi-Fi task while it is idle: a second scan started
+/// next to its own would fail, and the two would take each other's results.
+pub fn request_scan(timeout: Duration) -> Result<Vec<AccessPointInfo>> {
+    let (reply, result) = flume::bounded(1);
+    let busy = || anyhow!("the Wi-Fi task is busy scanning or connecting, try again");
+    SCAN_REQUESTS.0.try_send(reply).map_err(|_| busy())?;
+    result.recv_timeout(timeout).map_err(|_| busy())?
+}
 
-pub async fn wifi_scan<'a>(wifi: &'a mut AsyncWifi<EspWifi<'static>>) -> Result<AccessPointInfo> {
-    esp!(unsafe { esp_wifi_clear_ap_list() })?;
-    let ssid = CONFIG.wifi_ssid;
+async fn answer_scan(wifi: &mut AsyncWifi<EspWifi<'static>>, reply: ScanReply) {
+    // whoever asked may have given up by now
+    if reply.is_disconnected() {
+        return;
+    }
+    let _ = reply.send(wifi_scan(wifi).await.map(|scan| scan.to_vec()));
+}
+
+async fn next_event(
+    wifi: &mut AsyncWifi<EspWifi<'static>>,
+    events: &flume::Receiver<ConnEvent>,
+    provisioning: &flume::Receiver<()>,
+) -> Result<ConnEvent> {
+    loop {
+        match select4(events.recv_async(), provisioning.recv_async(), RECONNECT_REQUESTS.1.recv_async(), SCAN_REQUESTS.1.recv_async()).await {
+            Either4::First(event) => return Ok(event?),
+            Either4::Second(_) => return Ok(ConnEvent::ProvisioningRequested),
+            Either4::Third(_) => return Ok(ConnEvent::ReconnectRequested),
+            Either4::Fourth(reply) => answer_scan(wifi, reply?).await,
+        }
+    }
+}
 
-    if let Ok((scan, _)) = wifi.scan_n::<32>().await {
-        for ap in scan.into_iter() {
-            if ap.ssid == ssid {
-                return Ok(ap);
+/// Runs the connection manager, driven by the system event loop and its own scan/connect results.
+pub async fn app_wifi_loop(mut wifi: AsyncWifi<EspWifi<'static>>, tx: InfoSender) -> Result<()> {
+    warn!("wifi_loop");
+    link::register_disconnect_history()?;
+
+    let (events_tx, events) = flume::unbounded::<ConnEvent>();
+    let _wifi_events = {
+        let events_tx = events_tx.clone();
+        SYS_LOOP.subscribe::<WifiEvent, _>(move |event: &WifiEvent| {
+            if matches!(event, WifiEvent::StaDisconnected) {
+                let _ = events_tx.send(ConnEvent::Disconnected);
             }
+        })?
+    };
+    let _ip_events = SYS_LOOP.subscribe::<IpEvent, _>(move |event: &IpEvent| {
+        if matches!(event, IpEvent::DhcpIpDeassigned(_)) {
+            let _ = events_tx.send(ConnEvent::LostIp);
         }
+    })?;
+
+    let provisioning = provisioning_requests();
+    let mut timer = ESP_TASK_TIMER_SVR.timer_async()?;
+    let mut manager = ConnectionManager::new(BackoffConfig::default(), unsafe { esp_random() });
+    let mut candidates = Vec::new();
+    let mut ip_settings: Option<(IpSettings, Option<String>)> = None;
+    let mut next = manager.handle(ConnEvent::Start);
+
+    loop {
+        let event = match next.take() {
+            Some(ConnAction::Scan) => {
+                tx.send(InfoUpdate::Msg("scanning".to_owned()))?;
+                match scan_candidates(&mut wifi).await {
+                    Ok(found) => {
+                        candidates = found;
+                        ConnEvent::ScanDone { candidates: candidates.len() }
+                    },
+                    Err(e) => {
+                        error!("wifi_scan: {}", e);
+                        ConnEvent::ScanFailed
+                    },
+                }
+            },
+            Some(ConnAction::Connect) => {
+                if let Err(e) = apply_ip_settings(&mut wifi, &mut ip_settings).await {
+                    error!("ip settings: {}", e);
+                }
+                let fixed = ip_settings.as_ref().is_some_and(|(s, _)| s.is_static());
+                match join_best(&mut wifi, &candidates).await {
+                    Ok(ap) => {
+                        report_link(&wifi, &ap, &tx, fixed)?;
+                        ConnEvent::GotIp
+                    },
+                    Err(e) => {
+                        warn!("connect: {}", e);
+                        ConnEvent::ConnectFailed
+                    },
+                }
+            },
+            Some(ConnAction::Disconnect) => {
+                info!("reconnecting to apply new settings");
+                let _ = wifi.disconnect().await;
+                ConnEvent::Disconnected
+            },
+            Some(ConnAction::Online) => {
+                info!("Connected to Wi-fi");
+                if let Err(e) = power::apply() {
+                    error!("power settings: {}", e);
+                }
+                clock::request_sync();
+                // drop disconnects left over from failed attempts, then make sure the link is still up
+                let _ = events.drain();
+                if wifi.is_connected()? {
+                    next_event(&mut wifi, &events, &provisioning).await?
+                } else {
+                    ConnEvent::Disconnected
+                }
+            },
+            Some(ConnAction::Wait(delay)) => {
+                tx.send(InfoUpdate::Rssi(None))?;
+                info!("retrying Wi-Fi in {:?}", delay);
+                let until = Instant::now() + delay;
+                loop {
+                    let left = until.saturating_duration_since(Instant::now());
+                    match select3(timer.after(left), provisioning.recv_async(), SCAN_REQUESTS.1.recv_async()).await {
+                        Either3::First(_) => break ConnEvent::BackoffElapsed,
+                        Either3::Second(_) => break ConnEvent::ProvisioningRequested,
+                        Either3::Third(reply) => answer_scan(&mut wifi, reply?).await,
+                    }
+                }
+            },
+            Some(ConnAction::Provision) => {
+                tx.send(InfoUpdate::Rssi(None))?;
+                let _ = wifi.disconnect().await;
+                if let Err(e) = run_provisioning(&mut wifi, tx.clone()).await {
+                    error!("provisioning: {}", e);
+                }
+                ConnEvent::ProvisioningDone
+            },
+            None => next_event(&mut wifi, &events, &provisioning).await?,
+        };
+        info!("wifi {:?}: {:?}", manager.state(), event);
+        next = manager.handle(event);
+        link::set_state(manager.state(), manager.reconnects());
     }
-    Err(anyhow!("couldn't find ssid"))
+}
+
+
+pub async fn wifi_scan<'a>(wifi: &'a mut AsyncWifi<EspWifi<'static>>) -> Result<heapless::Vec<AccessPointInfo, 32>> {
+    esp!(unsafe { esp_wifi_clear_ap_list() })?;
+    let (scan, total) = wifi.scan_n::<32>().await?;
+    info!("scan found {} access points", total);
+    Ok(scan)
 }
 
 // pub type MacList = HeaplessVec<[u8; 6], 32>;
@@ -5,7 +5,9 @@ use std::marker::PhantomData;
 use embedded_graphics::{
     mono_font::MonoTextStyle,
     pixelcolor::PixelColor,
-    text::Text, geometry::Point,
+    prelude::{DrawTarget, Drawable, Primitive},
+    primitives::{Circle, Line, PrimitiveStyle, Rectangle},
+    text::Text, geometry::{Point, Size},
 };
 
 use embedded_layout::{
@@ -120,3 +122,447 @@ impl<'label, C: PixelColor> InputStatsRow<'label, C> {
     }
 
 }
+
+
+// Status bar icons
+//
+// Every icon occupies an ICON_SIZE cell and clears that cell with the
+// background color before drawing, so an icon can be redrawn in place
+// whenever its state changes.
+
+pub const ICON_SIZE: Size = Size::new(12, 8);
+
+#[derive(Clone, Copy, Debug)]
+pub struct IconStyle<C: PixelColor> {
+    pub foreground: C,
+    pub background: C,
+}
+
+impl<C: PixelColor> IconStyle<C> {
+    pub fn new(foreground: C, background: C) -> Self {
+        Self { foreground, background }
+    }
+
+    #[inline]
+    fn fill(&self) -> PrimitiveStyle<C> {
+        PrimitiveStyle::with_fill(self.foreground)
+    }
+
+    #[inline]
+    fn stroke(&self) -> PrimitiveStyle<C> {
+        PrimitiveStyle::with_stroke(self.foreground, 1)
+    }
+
+    #[inline]
+    fn clear(&self) -> PrimitiveStyle<C> {
+        PrimitiveStyle::with_fill(self.background)
+    }
+}
+
+macro_rules! impl_icon_view {
+    ($icon:ident) => {
+        impl<C: PixelColor> View for $icon<C> {
+            #[inline]
+            fn translate_impl(&mut self, by: Point) {
+                self.bounds.top_left += by;
+            }
+
+            #[inline]
+            fn bounds(&self) -> Rectangle {
+                self.bounds
+            }
+        }
+    };
+}
+
+/// Maps an RSSI reading in dBm onto 0..=4 signal bars.
+pub fn rssi_to_bars(rssi: i8) -> u8 {
+    match rssi {
+        r if r >= -55 => 4,
+        r if r >= -67 => 3,
+        r if r >= -75 => 2,
+        r if r >= -85 => 1,
+        _ => 0,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WifiIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    rssi: Option<i8>,
+}
+
+impl<C: PixelColor> WifiIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            rssi: None,
+        }
+    }
+
+    /// `None` means the station is not associated.
+    pub fn set_rssi(&mut self, rssi: Option<i8>) {
+        self.rssi = rssi;
+    }
+}
+
+impl_icon_view!(WifiIcon);
+
+impl<C: PixelColor> Drawable for WifiIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        let origin = self.bounds.top_left;
+        let bottom = origin.y + ICON_SIZE.height as i32;
+        match self.rssi {
+            Some(rssi) => {
+                let bars = rssi_to_bars(rssi) as i32;
+                for bar in 0..4 {
+                    let height = 2 * (bar + 1);
+                    let top_left = Point::new(origin.x + 3 * bar, bottom - height);
+                    let rect = Rectangle::new(top_left, Size::new(2, height as u32));
+                    if bar < bars {
+                        rect.into_styled(self.style.fill()).draw(target)?;
+                    } else {
+                        // unlit bars keep a baseline pixel so the icon stays recognisable
+                        Rectangle::new(Point::new(top_left.x, bottom - 1), Size::new(2, 1))
+                            .into_styled(self.style.fill())
+                            .draw(target)?;
+                    }
+                }
+            },
+            None => {
+                Line::new(origin, origin + Point::new(7, 7))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+                Line::new(origin + Point::new(0, 7), origin + Point::new(7, 0))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BatteryIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    level: Option<u8>,
+    charging: bool,
+}
+
+impl<C: PixelColor> BatteryIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            level: None,
+            charging: false,
+        }
+    }
+
+    /// `level` is a percentage, `None` when no battery is present.
+    pub fn set_level(&mut self, level: Option<u8>, charging: bool) {
+        self.level = level.map(|l| l.min(100));
+        self.charging = charging;
+    }
+}
+
+impl_icon_view!(BatteryIcon);
+
+impl<C: PixelColor> Drawable for BatteryIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        let origin = self.bounds.top_left;
+        let Some(level) = self.level else {
+            return Ok(());
+        };
+
+        Rectangle::new(origin, Size::new(10, 8))
+            .into_styled(self.style.stroke())
+            .draw(target)?;
+        Rectangle::new(origin + Point::new(10, 2), Size::new(2, 4))
+            .into_styled(self.style.fill())
+            .draw(target)?;
+
+        let width = (u32::from(level) * 8 + 50) / 100;
+        if width > 0 {
+            Rectangle::new(origin + Point::new(1, 1), Size::new(width, 6))
+                .into_styled(self.style.fill())
+                .draw(target)?;
+        }
+        if self.charging {
+            // a bolt cut out of the fill so it shows at any charge level
+            let bolt = PrimitiveStyle::with_stroke(self.style.background, 1);
+            Line::new(origin + Point::new(6, 1), origin + Point::new(4, 4))
+                .into_styled(bolt)
+                .draw(target)?;
+            Line::new(origin + Point::new(4, 4), origin + Point::new(6, 4))
+                .into_styled(bolt)
+                .draw(target)?;
+            Line::new(origin + Point::new(6, 4), origin + Point::new(4, 6))
+                .into_styled(bolt)
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ArmedIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    armed: bool,
+}
+
+impl<C: PixelColor> ArmedIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            armed: false,
+        }
+    }
+
+    pub fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+    }
+}
+
+impl_icon_view!(ArmedIcon);
+
+impl<C: PixelColor> Drawable for ArmedIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        let origin = self.bounds.top_left;
+
+        // padlock: the shackle is closed when armed and lifted open otherwise
+        Rectangle::new(origin + Point::new(1, 4), Size::new(7, 4))
+            .into_styled(self.style.fill())
+            .draw(target)?;
+        let lift = if self.armed { 0 } else { -1 };
+        Line::new(origin + Point::new(2, 4 + lift), origin + Point::new(2, 1 + lift))
+            .into_styled(self.style.stroke())
+            .draw(target)?;
+        Line::new(origin + Point::new(2, 1 + lift), origin + Point::new(6, 1 + lift))
+            .into_styled(self.style.stroke())
+            .draw(target)?;
+        if self.armed {
+            Line::new(origin + Point::new(6, 1), origin + Point::new(6, 3))
+                .into_styled(self.style.stroke())
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MotionIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    active: bool,
+}
+
+impl<C: PixelColor> MotionIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            active: false,
+        }
+    }
+
+    pub fn set_active(&mut self, active: bool) {
+        self.active = active;
+    }
+}
+
+impl_icon_view!(MotionIcon);
+
+impl<C: PixelColor> Drawable for MotionIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        let origin = self.bounds.top_left;
+        let dot = Circle::new(origin + Point::new(6, 2), 4);
+        if !self.active {
+            return dot.into_styled(self.style.stroke()).draw(target);
+        }
+
+        // a filled dot trailed by speed lines
+        dot.into_styled(self.style.fill()).draw(target)?;
+        for (y, len) in [(2, 3), (4, 5), (6, 3)] {
+            Line::new(origin + Point::new(5 - len, y), origin + Point::new(4, y))
+                .into_styled(self.style.stroke())
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClockState {
+    #[default]
+    Unsynced,
+    Synced,
+    Stale,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClockIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    state: ClockState,
+}
+
+impl<C: PixelColor> ClockIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            state: ClockState::Unsynced,
+        }
+    }
+
+    pub fn set_state(&mut self, state: ClockState) {
+        self.state = state;
+    }
+}
+
+impl_icon_view!(ClockIcon);
+
+impl<C: PixelColor> Drawable for ClockIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        let origin = self.bounds.top_left;
+        let center = origin + Point::new(4, 4);
+        Circle::new(origin, 8)
+            .into_styled(self.style.stroke())
+            .draw(target)?;
+        match self.state {
+            ClockState::Unsynced => {
+                Line::new(origin + Point::new(2, 6), origin + Point::new(6, 2))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+            },
+            ClockState::Synced => {
+                Line::new(center, center + Point::new(0, -3))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+                Line::new(center, center + Point::new(2, 0))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+            },
+            ClockState::Stale => {
+                // minute hand only, the hour can no longer be trusted
+                Line::new(center, center + Point::new(0, -3))
+                    .into_styled(self.style.stroke())
+                    .draw(target)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecordingIcon<C: PixelColor> {
+    bounds: Rectangle,
+    style: IconStyle<C>,
+    recording: bool,
+}
+
+impl<C: PixelColor> RecordingIcon<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        Self {
+            bounds: Rectangle::new(Point::zero(), ICON_SIZE),
+            style,
+            recording: false,
+        }
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+}
+
+impl_icon_view!(RecordingIcon);
+
+impl<C: PixelColor> Drawable for RecordingIcon<C> {
+    type Color = C;
+    type Output = ();
+
+    fn draw<D: DrawTarget<Color = C>>(&self, target: &mut D) -> Result<(), D::Error> {
+        self.bounds.into_styled(self.style.clear()).draw(target)?;
+        if self.recording {
+            Circle::new(self.bounds.top_left + Point::new(2, 0), 8)
+                .into_styled(self.style.fill())
+                .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, ViewGroup)]
+pub struct StatusBar<C: PixelColor> {
+    wifi: WifiIcon<C>,
+    battery: BatteryIcon<C>,
+    armed: ArmedIcon<C>,
+    motion: MotionIcon<C>,
+    clock: ClockIcon<C>,
+    recording: RecordingIcon<C>,
+}
+
+impl<C: PixelColor> StatusBar<C> {
+    pub fn new(style: IconStyle<C>) -> Self {
+        let bar = Self {
+            wifi: WifiIcon::new(style),
+            battery: BatteryIcon::new(style),
+            armed: ArmedIcon::new(style),
+            motion: MotionIcon::new(style),
+            clock: ClockIcon::new(style),
+            recording: RecordingIcon::new(style),
+        };
+        LinearLayout::horizontal(bar).with_spacing(spacing::FixedMargin(2)).arrange().into_inner()
+    }
+
+    pub fn set_rssi(&mut self, rssi: Option<i8>) {
+        self.wifi.set_rssi(rssi);
+    }
+
+    pub fn set_battery(&mut self, level: Option<u8>, charging: bool) {
+        self.battery.set_level(level, charging);
+    }
+
+    pub fn set_armed(&mut self, armed: bool) {
+        self.armed.set_armed(armed);
+    }
+
+    pub fn set_motion(&mut self, active: bool) {
+        self.motion.set_active(active);
+    }
+
+    pub fn set_clock(&mut self, state: ClockState) {
+        self.clock.set_state(state);
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording.set_recording(recording);
+    }
+}